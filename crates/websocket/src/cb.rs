@@ -0,0 +1,3216 @@
+//! Callback-style `WebSocket` API.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use gloo_events::EventListener;
+use gloo_timers::callback::{Interval, Timeout};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{CloseEvent, ErrorEvent, MessageEvent};
+
+use crate::common::{BackoffDecision, Clock, ReconnectState, SystemClock, Transport};
+
+#[cfg(feature = "futures")]
+use futures::{future, Future};
+#[cfg(feature = "futures")]
+use wasm_bindgen_futures::future_to_promise;
+
+#[cfg(feature = "deflate")]
+use std::io::Read;
+#[cfg(feature = "deflate")]
+use flate2::read::GzDecoder;
+
+/// The current state of a [`WebSocket`](struct.WebSocket.html) connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReadyState {
+    /// Built with [`WebSocketBuilder::build_disconnected`](struct.WebSocketBuilder.html#method.build_disconnected)
+    /// and not yet told to [`connect`](struct.WebSocket.html#method.connect). No
+    /// connection has ever been attempted.
+    Idle,
+    /// Attempting to connect, either for the first time or as part of a reconnect.
+    Connecting,
+    /// Connected and able to send and receive messages.
+    Open,
+    /// In the process of closing.
+    Closing,
+    /// Closed, and not currently attempting to reconnect.
+    Closed,
+}
+
+impl fmt::Display for ReadyState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ReadyState::Idle => "idle",
+            ReadyState::Connecting => "connecting",
+            ReadyState::Open => "open",
+            ReadyState::Closing => "closing",
+            ReadyState::Closed => "closed",
+        })
+    }
+}
+
+/// The value returned by [`std::convert::TryFrom<u16>`](#impl-TryFrom%3Cu16%3E) for
+/// [`ReadyState`](enum.ReadyState.html) was not one of the four
+/// [`WebSocket.readyState`](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/readyState)
+/// values `0..=3`.
+///
+/// `ReadyState::Idle` has no corresponding `readyState` number (it only exists before a
+/// connection is ever attempted), so there is no value that converts to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidReadyState(pub u16);
+
+impl fmt::Display for InvalidReadyState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is not a valid WebSocket.readyState value", self.0)
+    }
+}
+
+impl std::error::Error for InvalidReadyState {}
+
+impl std::convert::TryFrom<u16> for ReadyState {
+    type Error = InvalidReadyState;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ReadyState::Connecting),
+            1 => Ok(ReadyState::Open),
+            2 => Ok(ReadyState::Closing),
+            3 => Ok(ReadyState::Closed),
+            other => Err(InvalidReadyState(other)),
+        }
+    }
+}
+
+/// The storage used for [`WsMessage::Bytes`](enum.WsMessage.html#variant.Bytes): a
+/// plain `Vec<u8>`. Enable the `bytes` feature to switch this to
+/// [`bytes::Bytes`](https://docs.rs/bytes/*/bytes/struct.Bytes.html) instead, so
+/// cloning a message to fan it out to multiple subscribers or re-send it is cheap
+/// reference counting instead of a full copy.
+#[cfg(not(feature = "bytes"))]
+pub type BinaryPayload = Vec<u8>;
+
+/// The storage used for [`WsMessage::Bytes`](enum.WsMessage.html#variant.Bytes):
+/// [`bytes::Bytes`](https://docs.rs/bytes/*/bytes/struct.Bytes.html), so cloning a
+/// message to fan it out to multiple subscribers or re-send it is cheap reference
+/// counting instead of a full copy. Disable the `bytes` feature to use a plain
+/// `Vec<u8>` instead.
+#[cfg(feature = "bytes")]
+pub type BinaryPayload = bytes::Bytes;
+
+/// A message to send, borrowing its payload when possible.
+///
+/// [`WebSocket::send_text`](struct.WebSocket.html#method.send_text) and
+/// [`send_bytes`](struct.WebSocket.html#method.send_bytes) build one of these from the
+/// `&str`/`&[u8]` they're given, so the common case — the socket is already open, and
+/// the message is handed straight to `WebSocket.send` — never allocates. If the
+/// message has to be queued instead (the socket isn't open yet and queueing is
+/// enabled), it's copied into an owned [`WsMessage`](enum.WsMessage.html) at that
+/// point, since a queued send has to outlive the call that issued it.
+#[derive(Debug, Clone)]
+pub enum OutgoingMessage<'a> {
+    /// Text to send.
+    Text(Cow<'a, str>),
+    /// Bytes to send.
+    Bytes(Cow<'a, [u8]>),
+}
+
+impl<'a> OutgoingMessage<'a> {
+    fn byte_len(&self) -> usize {
+        match self {
+            OutgoingMessage::Text(text) => text.len(),
+            OutgoingMessage::Bytes(bytes) => bytes.len(),
+        }
+    }
+
+    fn into_owned(self) -> WsMessage {
+        match self {
+            OutgoingMessage::Text(text) => WsMessage::Text(text.into_owned()),
+            OutgoingMessage::Bytes(bytes) => WsMessage::Bytes(bytes.into_owned().into()),
+        }
+    }
+}
+
+/// A message sent or received over a [`WebSocket`](struct.WebSocket.html).
+#[derive(Debug, Clone)]
+pub enum WsMessage {
+    /// A UTF-8 text message.
+    Text(String),
+    /// A binary message.
+    Bytes(BinaryPayload),
+    /// A binary message received while
+    /// [`WebSocketBuilder::binary_type`](struct.WebSocketBuilder.html#method.binary_type)
+    /// is set to `Blob`.
+    ///
+    /// Unlike [`Bytes`](#variant.Bytes), the payload is not copied into Wasm memory up
+    /// front, which matters for multi-megabyte frames. Read it with
+    /// [`bytes`](#method.bytes) (requires the `futures` feature), or reach the
+    /// underlying [`web_sys::Blob`](https://docs.rs/web-sys/*/web_sys/struct.Blob.html)
+    /// directly via [`as_blob`](#method.as_blob) to stream it elsewhere.
+    Blob(web_sys::Blob),
+}
+
+impl WsMessage {
+    fn byte_len(&self) -> usize {
+        match self {
+            WsMessage::Text(text) => text.len(),
+            WsMessage::Bytes(bytes) => bytes.len(),
+            WsMessage::Blob(blob) => blob.size() as usize,
+        }
+    }
+
+    // Shrinks the message to at most `max_bytes`, respecting UTF-8 character boundaries
+    // for text messages. Blobs are sliced rather than read, so this stays synchronous.
+    fn truncate_to(&mut self, max_bytes: usize) {
+        match self {
+            WsMessage::Text(text) => {
+                let mut boundary = max_bytes.min(text.len());
+                while boundary > 0 && !text.is_char_boundary(boundary) {
+                    boundary -= 1;
+                }
+                text.truncate(boundary);
+            }
+            WsMessage::Bytes(bytes) => bytes.truncate(max_bytes),
+            WsMessage::Blob(blob) => {
+                let end = (max_bytes as f64).min(blob.size()) as i32;
+                *blob = blob
+                    .slice_with_i32_and_i32(0, end)
+                    .unwrap_or_else(|_| blob.clone());
+            }
+        }
+    }
+
+    /// Returns the underlying [`web_sys::Blob`](https://docs.rs/web-sys/*/web_sys/struct.Blob.html)
+    /// if this is a [`Blob`](#variant.Blob) message, for passing straight through to
+    /// another browser API or reading it some way other than
+    /// [`bytes`](#method.bytes) (e.g. its `stream()` method).
+    pub fn as_blob(&self) -> Option<&web_sys::Blob> {
+        match self {
+            WsMessage::Blob(blob) => Some(blob),
+            WsMessage::Text(_) | WsMessage::Bytes(_) => None,
+        }
+    }
+}
+
+// `WsMessage` can't just `#[derive(Serialize, Deserialize)]` because `Blob` wraps a
+// live browser handle, not data. Text and Bytes messages round-trip exactly for
+// logging and replay; a `Blob` message serializes to its size only, and can't be
+// deserialized back into one, since there's no browser handle to hand it.
+#[cfg(feature = "serde")]
+impl serde::Serialize for WsMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        #[derive(serde::Serialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum Repr<'a> {
+            Text { text: &'a str },
+            Bytes { bytes: &'a [u8] },
+            Blob { size: f64 },
+        }
+        match self {
+            WsMessage::Text(text) => Repr::Text { text }.serialize(serializer),
+            WsMessage::Bytes(bytes) => Repr::Bytes { bytes }.serialize(serializer),
+            WsMessage::Blob(blob) => Repr::Blob { size: blob.size() }.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for WsMessage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(serde::Deserialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum Repr {
+            Text { text: String },
+            Bytes { bytes: Vec<u8> },
+            Blob { size: f64 },
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Text { text } => Ok(WsMessage::Text(text)),
+            Repr::Bytes { bytes } => Ok(WsMessage::Bytes(bytes.into())),
+            Repr::Blob { .. } => Err(serde::de::Error::custom(
+                "cannot deserialize a WsMessage::Blob: browser Blob handles aren't replayable",
+            )),
+        }
+    }
+}
+
+impl From<String> for WsMessage {
+    fn from(text: String) -> Self {
+        WsMessage::Text(text)
+    }
+}
+
+impl From<&'static str> for WsMessage {
+    fn from(text: &'static str) -> Self {
+        WsMessage::Text(text.to_owned())
+    }
+}
+
+impl From<Vec<u8>> for WsMessage {
+    fn from(bytes: Vec<u8>) -> Self {
+        WsMessage::Bytes(bytes.into())
+    }
+}
+
+impl<'a> From<&'a [u8]> for WsMessage {
+    fn from(bytes: &'a [u8]) -> Self {
+        WsMessage::Bytes(bytes.to_owned().into())
+    }
+}
+
+impl std::convert::TryFrom<WsMessage> for String {
+    /// The original message, returned unchanged when it wasn't
+    /// [`WsMessage::Text`](enum.WsMessage.html#variant.Text).
+    type Error = WsMessage;
+
+    fn try_from(message: WsMessage) -> Result<Self, Self::Error> {
+        match message {
+            WsMessage::Text(text) => Ok(text),
+            other => Err(other),
+        }
+    }
+}
+
+impl std::convert::TryFrom<WsMessage> for Vec<u8> {
+    /// The original message, returned unchanged when it wasn't
+    /// [`WsMessage::Bytes`](enum.WsMessage.html#variant.Bytes).
+    type Error = WsMessage;
+
+    fn try_from(message: WsMessage) -> Result<Self, Self::Error> {
+        match message {
+            WsMessage::Bytes(bytes) => Ok(bytes.to_vec()),
+            other => Err(other),
+        }
+    }
+}
+
+/// What to do when a send arrives while the outgoing queue is already at a configured
+/// limit. See [`WebSocket::set_outgoing_queue_overflow_policy`](struct.WebSocket.html#method.set_outgoing_queue_overflow_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueOverflowPolicy {
+    /// Reject the new message; its completion callback is invoked with an error.
+    Reject,
+    /// Drop the oldest queued message (invoking its completion callback with an error)
+    /// to make room for the new one.
+    DropOldest,
+}
+
+impl Default for QueueOverflowPolicy {
+    fn default() -> Self {
+        QueueOverflowPolicy::Reject
+    }
+}
+
+/// Observes traffic and lifecycle events on a [`WebSocket`](struct.WebSocket.html)
+/// without being able to alter or drop them -- unlike
+/// [`WebSocketBuilder::outgoing_middleware`](struct.WebSocketBuilder.html#method.outgoing_middleware)/[`incoming_middleware`](struct.WebSocketBuilder.html#method.incoming_middleware),
+/// which exist to transform or filter messages, an `Interceptor` is purely a side
+/// channel: several can be attached (via
+/// [`WebSocketBuilder::add_interceptor`](struct.WebSocketBuilder.html#method.add_interceptor))
+/// alongside `on_event` and each other without any of them needing to know about the
+/// others, which suits analytics or audit logging that shouldn't be able to affect
+/// how a message is actually handled.
+///
+/// Every method has a no-op default, so an implementation only needs to override the
+/// events it cares about.
+pub trait Interceptor {
+    /// Fires for every outgoing frame, right before it's handed to the browser.
+    fn on_send(&mut self, _message: &WsMessage) {}
+
+    /// Fires for every incoming frame, before `incoming_middleware` or
+    /// `validate_incoming` run.
+    fn on_receive(&mut self, _message: &WsMessage) {}
+
+    /// Fires when the connection opens, including after a reconnect.
+    fn on_open(&mut self) {}
+
+    /// Fires when the connection closes, with the same fields as
+    /// [`WsEvent::Close`](enum.WsEvent.html#variant.Close).
+    fn on_close(&mut self, _code: u16, _reason: &str, _was_clean: bool) {}
+
+    /// Fires right before a reconnect attempt is scheduled, with the attempt number.
+    fn on_reconnect_attempt(&mut self, _attempt: u32) {}
+}
+
+/// What to do with an incoming frame that exceeds the configured
+/// [`max_message_size`](struct.WebSocketBuilder.html#method.max_message_size).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Discard the frame; no [`WsEvent::Message`](enum.WsEvent.html#variant.Message) is
+    /// emitted for it.
+    Drop,
+    /// Truncate the frame to the configured limit and emit it as usual.
+    Truncate,
+}
+
+impl Default for OversizePolicy {
+    fn default() -> Self {
+        OversizePolicy::Drop
+    }
+}
+
+/// Why [`WebSocketBuilder::validate_incoming`](struct.WebSocketBuilder.html#method.validate_incoming)
+/// rejected an incoming frame. Carries whatever reason the validator supplied.
+#[derive(Debug, Clone)]
+pub struct ValidationError(pub String);
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// What to do with a text frame the browser could only deliver by substituting
+/// replacement characters for invalid UTF-16, e.g. an unpaired surrogate a
+/// non-browser peer is free to put in a text frame but that has no lossless UTF-8
+/// representation. See [`WebSocketBuilder::text_decode_policy`](struct.WebSocketBuilder.html#method.text_decode_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextDecodePolicy {
+    /// Deliver the frame anyway, replacement characters and all -- the same lossy
+    /// behavior `TextDecoder`'s non-fatal mode (and this crate, previously) uses by
+    /// default.
+    Lossy,
+    /// Discard the frame instead of delivering mangled text; [`WebSocket::on_text_decode_error`](struct.WebSocket.html#method.on_text_decode_error),
+    /// if set, still fires.
+    Drop,
+}
+
+impl Default for TextDecodePolicy {
+    fn default() -> Self {
+        TextDecodePolicy::Lossy
+    }
+}
+
+/// [WebSocket close codes](https://developer.mozilla.org/en-US/docs/Web/API/CloseEvent/code)
+/// as defined by [RFC 6455 §7.4](https://datatracker.ietf.org/doc/html/rfc6455#section-7.4).
+///
+/// Used both to [`close`](struct.WebSocket.html#method.close_with_code) a connection and
+/// to interpret the code on an incoming [`WsEvent::Close`](enum.WsEvent.html#variant.Close).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// Normal, expected closure. Used by [`WebSocket::close`](struct.WebSocket.html#method.close).
+    Normal,
+    /// The endpoint is going away, e.g. the browser tab is closing. Used by `WebSocket`
+    /// itself when the page unloads.
+    GoingAway,
+    /// The endpoint is terminating the connection due to a protocol error.
+    ProtocolError,
+    /// The endpoint received data it can't accept, e.g. a text-only endpoint receiving binary data.
+    Unsupported,
+    /// Reserved. Never actually sent in a close frame; a `CloseEvent` reports this code
+    /// when the peer closed without sending one.
+    NoStatusReceived,
+    /// Reserved. Never actually sent in a close frame; a `CloseEvent` reports this code
+    /// when the connection was dropped without a close handshake at all, e.g. the
+    /// network went away.
+    AbnormalClosure,
+    /// The endpoint received a message whose payload was inconsistent with its type,
+    /// e.g. text data that isn't valid UTF-8.
+    InvalidPayloadData,
+    /// The endpoint is terminating the connection because of a policy violation. Used by
+    /// `WebSocket` itself when the server doesn't negotiate a required subprotocol.
+    PolicyViolation,
+    /// The endpoint is terminating the connection because a received message was too big to process.
+    MessageTooBig,
+    /// The client is terminating the connection because the server failed to negotiate
+    /// an extension the client required.
+    MandatoryExtension,
+    /// The server is terminating the connection because it encountered an unexpected condition.
+    InternalError,
+    /// The server is terminating the connection because it is restarting.
+    ServiceRestart,
+    /// The server is terminating the connection because it is temporarily overloaded;
+    /// the client should try again later.
+    TryAgainLater,
+    /// The server was acting as a gateway and received an invalid response from the
+    /// upstream it needed to fulfil the request.
+    BadGateway,
+    /// Reserved. Never actually sent in a close frame; a `CloseEvent` reports this code
+    /// when the TLS handshake failed (e.g. a bad certificate).
+    TlsHandshakeFailure,
+    /// A code not covered by one of the named variants above, including codes in the
+    /// IANA-registered (3000-3999) and private-use (4000-4999) ranges.
+    Other(u16),
+}
+
+impl CloseCode {
+    /// Whether this code is reserved by the spec and must never be set explicitly in a
+    /// close frame -- it can only ever be observed on an incoming
+    /// [`WsEvent::Close`](enum.WsEvent.html#variant.Close), synthesized by the browser
+    /// itself to describe how the connection actually ended.
+    pub fn is_reserved(self) -> bool {
+        match self {
+            CloseCode::NoStatusReceived | CloseCode::AbnormalClosure | CloseCode::TlsHandshakeFailure => true,
+            CloseCode::Other(code) => code == 1004,
+            _ => false,
+        }
+    }
+
+    /// Whether a connection that closed with this code is worth reconnecting to.
+    ///
+    /// Codes that describe a problem with this specific message or negotiation (e.g.
+    /// [`PolicyViolation`](#variant.PolicyViolation), the crate's own response to a
+    /// failed subprotocol negotiation) will just happen again on reconnect, so
+    /// `WebSocket`'s automatic reconnect treats `false` here the same as a manual
+    /// [`close`](struct.WebSocket.html#method.close): it gives up instead of retrying.
+    pub fn should_reconnect(self) -> bool {
+        !matches!(
+            self,
+            CloseCode::Normal
+                | CloseCode::ProtocolError
+                | CloseCode::Unsupported
+                | CloseCode::InvalidPayloadData
+                | CloseCode::PolicyViolation
+                | CloseCode::MessageTooBig
+                | CloseCode::MandatoryExtension
+        )
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::Unsupported => 1003,
+            CloseCode::NoStatusReceived => 1005,
+            CloseCode::AbnormalClosure => 1006,
+            CloseCode::InvalidPayloadData => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError => 1011,
+            CloseCode::ServiceRestart => 1012,
+            CloseCode::TryAgainLater => 1013,
+            CloseCode::BadGateway => 1014,
+            CloseCode::TlsHandshakeFailure => 1015,
+            CloseCode::Other(code) => code,
+        }
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::Unsupported,
+            1005 => CloseCode::NoStatusReceived,
+            1006 => CloseCode::AbnormalClosure,
+            1007 => CloseCode::InvalidPayloadData,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            1012 => CloseCode::ServiceRestart,
+            1013 => CloseCode::TryAgainLater,
+            1014 => CloseCode::BadGateway,
+            1015 => CloseCode::TlsHandshakeFailure,
+            other => CloseCode::Other(other),
+        }
+    }
+}
+
+/// Events emitted by a [`WebSocket`](struct.WebSocket.html) over its lifetime.
+///
+/// Every connection, reconnects included, produces its own `Open` and `Close`
+/// events, and the reconnect attempt itself is reported in-band via
+/// `Reconnecting` and `ReconnectFailed` so consumers don't have to poll
+/// [`reconnect_attempt`](struct.WebSocket.html#method.reconnect_attempt) to show
+/// reconnect progress.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WsEvent {
+    /// The connection was established.
+    Open,
+    /// A message was received from the server.
+    Message(WsMessage),
+    /// The connection was closed.
+    Close {
+        /// The [close code](https://developer.mozilla.org/en-US/docs/Web/API/CloseEvent/code) sent by the peer.
+        code: u16,
+        /// The reason the peer gave for closing the connection.
+        reason: String,
+        /// Whether the connection was closed cleanly.
+        was_clean: bool,
+    },
+    /// An error occurred on the connection. The browser does not expose any detail about the error.
+    Error,
+    /// The server opened the connection without negotiating one of the requested
+    /// subprotocols. Only emitted when
+    /// [`WebSocketBuilder::require_negotiated_protocol`](struct.WebSocketBuilder.html#method.require_negotiated_protocol)
+    /// is set; the connection is closed with [`CloseCode::PolicyViolation`](enum.CloseCode.html#variant.PolicyViolation)
+    /// immediately afterward and does not reconnect.
+    ProtocolMismatch {
+        /// The subprotocols that were requested, in preference order.
+        requested: Vec<String>,
+        /// The subprotocol the server actually negotiated (empty if none).
+        negotiated: String,
+    },
+    /// A reconnect attempt has been scheduled after `delay`.
+    ///
+    /// `attempt` is `0` for the first reconnect after a successful connection, matching
+    /// [`BackoffStrategy::delay`](trait.BackoffStrategy.html)'s numbering.
+    Reconnecting {
+        /// Which reconnect attempt this is, counting from `0`.
+        attempt: u32,
+        /// How long the socket will wait before making this attempt.
+        delay: Duration,
+    },
+    /// Reconnecting was given up on, per
+    /// [`ReconnectConfig::max_retries`](struct.ReconnectConfig.html#method.max_retries)
+    /// or [`max_elapsed_time`](struct.ReconnectConfig.html#method.max_elapsed_time). The
+    /// socket is now closed for good, as if
+    /// [`WebSocket::close`](struct.WebSocket.html#method.close) had been called.
+    ReconnectFailed,
+}
+
+/// Computes how long to wait before each reconnect attempt.
+///
+/// Set one on a [`ReconnectConfig`](struct.ReconnectConfig.html) to customize the
+/// schedule; see [`ConstantBackoff`](struct.ConstantBackoff.html),
+/// [`LinearBackoff`](struct.LinearBackoff.html), and
+/// [`FibonacciBackoff`](struct.FibonacciBackoff.html) for ready-made alternatives to
+/// the default [`ExponentialBackoff`](struct.ExponentialBackoff.html).
+pub trait BackoffStrategy {
+    /// Returns the delay to wait before reconnect attempt number `attempt` (`0` for the
+    /// first reconnect after a successful connection).
+    fn delay(&self, attempt: u32) -> Duration;
+
+    /// Returns whether the delay has stopped growing by `attempt`, i.e.
+    /// `delay(attempt) == delay(attempt + 1)`.
+    ///
+    /// Used to report [`Diagnostic::BackoffClamped`](enum.Diagnostic.html#variant.BackoffClamped);
+    /// the default implementation is usually right, but a strategy whose delay is
+    /// constant by design (like [`ConstantBackoff`](struct.ConstantBackoff.html))
+    /// overrides it to always return `false`, since it was never growing in the first
+    /// place.
+    fn is_clamped(&self, attempt: u32) -> bool {
+        self.delay(attempt) == self.delay(attempt + 1)
+    }
+}
+
+/// Waits the same fixed delay before every reconnect attempt.
+#[derive(Debug, Clone, Copy)]
+pub struct ConstantBackoff {
+    /// The delay to wait before every attempt.
+    pub delay: Duration,
+}
+
+impl BackoffStrategy for ConstantBackoff {
+    fn delay(&self, _attempt: u32) -> Duration {
+        self.delay
+    }
+
+    fn is_clamped(&self, _attempt: u32) -> bool {
+        false
+    }
+}
+
+/// Multiplies the delay by `multiplier` after each failed attempt, up to `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExponentialBackoff {
+    /// The delay before the first reconnect attempt.
+    pub base: Duration,
+    /// How much the delay grows by after each attempt. Defaults to `2.0`, i.e.
+    /// doubling; values less than `1.0` are treated as `1.0` (no growth).
+    pub multiplier: f64,
+    /// The delay never grows past this.
+    pub max: Duration,
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        ExponentialBackoff {
+            base: Duration::from_millis(500),
+            multiplier: 2.0,
+            max: Duration::from_millis(16_000),
+        }
+    }
+}
+
+impl BackoffStrategy for ExponentialBackoff {
+    fn delay(&self, attempt: u32) -> Duration {
+        let base_millis = self.base.as_millis() as f64;
+        let growth = self.multiplier.max(1.0).powi(attempt.min(64) as i32);
+        let millis = base_millis * growth;
+        Duration::from_millis(millis.min(self.max.as_millis() as f64) as u64)
+    }
+}
+
+/// Grows the delay linearly with the attempt number, up to `max`: `base * (attempt + 1)`.
+#[derive(Debug, Clone, Copy)]
+pub struct LinearBackoff {
+    /// The delay before the first reconnect attempt, and the amount added for each
+    /// subsequent one.
+    pub base: Duration,
+    /// The delay never grows past this.
+    pub max: Duration,
+}
+
+impl BackoffStrategy for LinearBackoff {
+    fn delay(&self, attempt: u32) -> Duration {
+        let millis = (self.base.as_millis() as u64).saturating_mul(u64::from(attempt) + 1);
+        Duration::from_millis(millis.min(self.max.as_millis() as u64))
+    }
+}
+
+/// Grows the delay along the Fibonacci sequence, scaled by `base`, up to `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct FibonacciBackoff {
+    /// The delay before the first reconnect attempt, and the scale factor for the rest
+    /// of the sequence.
+    pub base: Duration,
+    /// The delay never grows past this.
+    pub max: Duration,
+}
+
+impl BackoffStrategy for FibonacciBackoff {
+    fn delay(&self, attempt: u32) -> Duration {
+        let millis = (self.base.as_millis() as u64).saturating_mul(fibonacci(attempt));
+        Duration::from_millis(millis.min(self.max.as_millis() as u64))
+    }
+}
+
+// The `n`th Fibonacci number (1-indexed, i.e. `fibonacci(0) == fibonacci(1) == 1`),
+// capped at an attempt number far beyond anything `max` would ever let through, to
+// keep the multiplication in `FibonacciBackoff::delay` from overflowing.
+fn fibonacci(n: u32) -> u64 {
+    let (mut a, mut b) = (1u64, 1u64);
+    for _ in 0..n.min(90) {
+        let next = a.saturating_add(b);
+        a = b;
+        b = next;
+    }
+    a
+}
+
+/// Configures how a [`WebSocket`](struct.WebSocket.html) schedules its reconnect
+/// attempts.
+///
+/// Defaults to [`ExponentialBackoff`](struct.ExponentialBackoff.html). Set with
+/// [`WebSocketBuilder::reconnect_config`](struct.WebSocketBuilder.html#method.reconnect_config).
+///
+/// `ReconnectConfig` is [`Clone`](#impl-Clone), like the rest of
+/// [`WebSocketBuilder`](struct.WebSocketBuilder.html)'s configuration.
+#[derive(Clone)]
+pub struct ReconnectConfig {
+    pub(crate) strategy: Rc<dyn BackoffStrategy>,
+    pub(crate) max_retries: Option<u32>,
+    pub(crate) max_elapsed_time: Option<Duration>,
+    pub(crate) jitter: Rc<dyn Fn(u32) -> f64>,
+}
+
+impl ReconnectConfig {
+    /// Starts a `ReconnectConfig` with the default
+    /// [`ExponentialBackoff`](struct.ExponentialBackoff.html) strategy, ready to chain
+    /// further configuration onto:
+    ///
+    /// ```no_run
+    /// # use gloo_websocket::cb::ReconnectConfig;
+    /// # use std::time::Duration;
+    /// let config = ReconnectConfig::new().max_retries(5).max_elapsed_time(Duration::from_secs(60));
+    /// ```
+    ///
+    /// Equivalent to [`ReconnectConfig::default`](#impl-Default); to tune the backoff
+    /// itself (e.g. `ExponentialBackoff`'s `multiplier` or `max`), build the strategy
+    /// separately and hand it to [`with_strategy`](#method.with_strategy).
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses `strategy` to compute reconnect delays instead of the default
+    /// [`ExponentialBackoff`](struct.ExponentialBackoff.html).
+    pub fn with_strategy(strategy: impl BackoffStrategy + 'static) -> Self {
+        ReconnectConfig {
+            strategy: Rc::new(strategy),
+            max_retries: None,
+            max_elapsed_time: None,
+            jitter: Rc::new(|_attempt| 1.0 + (js_sys::Math::random() - 0.5) * 0.2),
+        }
+    }
+
+    /// Gives up reconnecting after `max_retries` consecutive failed attempts, instead
+    /// of retrying forever.
+    ///
+    /// Once exhausted, the socket stops reconnecting as if
+    /// [`WebSocket::close`](struct.WebSocket.html#method.close) had been called, and
+    /// [`WebSocket::on_reconnect_exhausted`](struct.WebSocket.html#method.on_reconnect_exhausted)
+    /// fires.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Gives up reconnecting once `max_elapsed_time` has passed since the connection
+    /// was last open, regardless of how many attempts that took.
+    ///
+    /// Like [`max_retries`](#method.max_retries), exhaustion closes the socket and
+    /// fires [`WebSocket::on_reconnect_exhausted`](struct.WebSocket.html#method.on_reconnect_exhausted)
+    /// instead of retrying forever with no way out.
+    pub fn max_elapsed_time(mut self, max_elapsed_time: Duration) -> Self {
+        self.max_elapsed_time = Some(max_elapsed_time);
+        self
+    }
+
+    /// Replaces the jitter applied on top of [`strategy`](#method.with_strategy)'s
+    /// delays with `jitter`, a function from attempt number to a multiplier applied to
+    /// that attempt's delay.
+    ///
+    /// By default each delay is scaled by a random factor in `0.9..=1.1` (drawn from
+    /// [`js_sys::Math::random`](https://docs.rs/js-sys/*/js_sys/Math/fn.random.html)) so
+    /// that many clients reconnecting after the same outage don't all retry in
+    /// lockstep. Tests that assert on reconnect timing should replace it with something
+    /// deterministic, e.g. `|_| 1.0`, rather than fighting the randomness; see
+    /// [`without_jitter`](#method.without_jitter) for the common case of disabling it
+    /// entirely.
+    pub fn jitter_fn(mut self, jitter: impl Fn(u32) -> f64 + 'static) -> Self {
+        self.jitter = Rc::new(jitter);
+        self
+    }
+
+    /// Disables jitter, so every delay is exactly what
+    /// [`strategy`](#method.with_strategy) computes. Shorthand for
+    /// `jitter_fn(|_| 1.0)`.
+    ///
+    /// Useful in `wasm-bindgen-test` timing assertions, where the default randomized
+    /// jitter would otherwise make the expected delay flaky.
+    pub fn without_jitter(self) -> Self {
+        self.jitter_fn(|_attempt| 1.0)
+    }
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig::with_strategy(ExponentialBackoff::default())
+    }
+}
+
+impl fmt::Debug for ReconnectConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReconnectConfig").finish()
+    }
+}
+
+/// Why a [`WebSocketBuilder`](struct.WebSocketBuilder.html) failed to
+/// [`build`](struct.WebSocketBuilder.html#method.build).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// The configured URL could not be parsed, or does not resolve to the `ws` or
+    /// `wss` scheme.
+    InvalidUrl {
+        /// The URL that failed to validate, after relative resolution was attempted.
+        url: String,
+    },
+    /// Two or more configured options can't be used together.
+    ConflictingOptions {
+        /// Human-readable description of which options conflict and why.
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::InvalidUrl { url } => write!(f, "invalid websocket url: {}", url),
+            BuildError::ConflictingOptions { reason } => {
+                write!(f, "conflicting websocket builder options: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Error returned when a message could not be sent.
+#[derive(Debug)]
+pub enum SendError {
+    /// The socket is not currently open, and automatic queueing is disabled or the
+    /// message wasn't eligible for it.
+    NotConnected,
+    /// The message exceeds the configured
+    /// [`max_message_size`](struct.WebSocketBuilder.html#method.max_message_size).
+    TooLarge {
+        /// Size of the rejected message, in bytes.
+        size: usize,
+        /// The configured limit.
+        max: usize,
+    },
+    /// The browser's `WebSocket.send` call itself failed.
+    Failed(JsValue),
+    /// The outgoing queue was full and
+    /// [`QueueOverflowPolicy::Reject`](enum.QueueOverflowPolicy.html#variant.Reject) is in
+    /// effect.
+    QueueFull,
+    /// The message was dropped from the outgoing queue to make room for a newer one
+    /// under [`QueueOverflowPolicy::DropOldest`](enum.QueueOverflowPolicy.html#variant.DropOldest).
+    DroppedFromQueue,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::NotConnected => write!(f, "the websocket is not connected"),
+            SendError::TooLarge { size, max } => {
+                write!(f, "message of {} bytes exceeds the {} byte limit", size, max)
+            }
+            SendError::Failed(_) => write!(f, "the websocket rejected the send"),
+            SendError::QueueFull => write!(f, "outgoing queue is full"),
+            SendError::DroppedFromQueue => {
+                write!(f, "dropped from outgoing queue to make room for a newer message")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// Error returned when a [`close`](struct.WebSocket.html#method.close) call's underlying
+/// `WebSocket.close()` invocation fails synchronously.
+///
+/// This does not mean the connection failed to close -- `WebSocket` always stops
+/// reconnecting and gives up its outgoing queue regardless -- only that the browser
+/// rejected the request outright, which in practice means an invalid close code or a
+/// socket that was already closed. The same failure is also reported through
+/// [`Diagnostic::CloseFailed`](enum.Diagnostic.html#variant.CloseFailed) for callers
+/// that close without checking this return value, such as `WebSocket`'s `Drop` impl.
+#[derive(Debug)]
+pub struct CloseError(JsValue);
+
+impl fmt::Display for CloseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the websocket rejected the close request")
+    }
+}
+
+impl std::error::Error for CloseError {}
+
+/// A non-fatal internal event, reported through
+/// [`WebSocket::on_diagnostic`](struct.WebSocket.html#method.on_diagnostic) for callers
+/// who want visibility into things the crate would otherwise swallow silently.
+///
+/// None of these indicate the connection itself failed -- if they did, they'd show up
+/// as a [`WsEvent`](enum.WsEvent.html) instead.
+#[derive(Debug)]
+pub enum Diagnostic {
+    /// The backoff delay computed for a reconnect attempt hit the configured maximum
+    /// and was clamped, rather than continuing to grow.
+    BackoffClamped {
+        /// The reconnect attempt this delay is for.
+        attempt: u32,
+        /// The clamped delay that will actually be used.
+        delay: Duration,
+    },
+    /// The local call to close the underlying socket failed. The socket is likely
+    /// already closed or closing; this does not prevent `WebSocket` from finishing the
+    /// close on its end.
+    CloseFailed(JsValue),
+    /// A scheduled heartbeat send failed. The connection is left open and the next
+    /// heartbeat will simply try again.
+    HeartbeatSendFailed(SendError),
+    /// [`expect_pong`](struct.WebSocketBuilder.html#method.expect_pong)'s miss
+    /// threshold was reached; the connection is being closed and reconnected as if the
+    /// server had dropped it.
+    HeartbeatMissed {
+        /// How many consecutive heartbeats went unanswered.
+        misses: u32,
+    },
+    /// [`WebSocketBuilder::auto_gunzip`](struct.WebSocketBuilder.html#method.auto_gunzip)
+    /// is enabled and a binary frame started with the gzip magic bytes, but failed to
+    /// decompress. The frame is still delivered as
+    /// [`WsEvent::Message`](enum.WsEvent.html#variant.Message), unchanged (still
+    /// gzipped), rather than being dropped.
+    #[cfg(feature = "deflate")]
+    GunzipFailed(String),
+}
+
+// Resolves a possibly-relative URL against `window.location`, the way the browser
+// would resolve a relative `fetch()` or `<a href>` URL, then checks that the result
+// uses the `ws`/`wss` scheme. Absolute URLs are parsed as-is; if there is no `window`
+// (e.g. a worker), there is nothing to resolve against, so the URL must already be
+// absolute. `query_params`, if any, are percent-encoded and appended to the URL's
+// existing query string.
+fn resolve_url(url: &str, query_params: &[(String, String)]) -> Result<String, BuildError> {
+    let invalid = || BuildError::InvalidUrl {
+        url: url.to_string(),
+    };
+
+    let resolved = match web_sys::window().and_then(|window| window.location().href().ok()) {
+        Some(base) => web_sys::Url::new_with_base(url, &base).map_err(|_| invalid())?,
+        None => web_sys::Url::new(url).map_err(|_| invalid())?,
+    };
+
+    // A relative URL resolves against an http(s) page, but the WebSocket protocol
+    // upgrades from http -> ws and https -> wss, not from http(s) itself.
+    match resolved.protocol().as_str() {
+        "http:" => resolved.set_protocol("ws:"),
+        "https:" => resolved.set_protocol("wss:"),
+        "ws:" | "wss:" => {}
+        _ => return Err(invalid()),
+    }
+
+    if !query_params.is_empty() {
+        let search_params = resolved.search_params();
+        for (key, value) in query_params {
+            search_params.append(key, value);
+        }
+    }
+
+    Ok(resolved.href())
+}
+
+// How often to poll `bufferedAmount` while waiting for it to drain. The WebSocket API
+// has no native "drained" event, so this is the only portable way to detect it.
+const DRAIN_POLL_MILLIS: u32 = 50;
+
+// How many sends to buffer while connecting/reconnecting before rejecting new ones.
+const DEFAULT_QUEUE_CAPACITY: usize = 32;
+
+struct QueuedSend {
+    message: WsMessage,
+    callback: Box<dyn FnOnce(Result<(), SendError>)>,
+}
+
+// Either a fixed URL, or a callback invoked fresh on every (re)connect attempt so it
+// can embed a rotated token, a different host, or a new session id. The provider is
+// `Rc`-shared, like the other handlers, so `UrlSource` (and therefore
+// `WebSocketBuilder`) can be cheaply cloned.
+#[derive(Clone)]
+enum UrlSource {
+    Static(String),
+    Provider(Rc<RefCell<dyn FnMut() -> Cow<'static, str>>>),
+}
+
+/// What to do about a pending reconnect attempt, returned by a
+/// [`before_reconnect`](struct.WebSocketBuilder.html#method.before_reconnect) hook.
+#[cfg(feature = "futures")]
+#[derive(Debug)]
+pub enum ReconnectDecision {
+    /// Proceed with the reconnect attempt using the currently configured URL.
+    Proceed,
+    /// Proceed with the reconnect attempt, swapping in `url` first — typically a URL
+    /// whose query string carries a token the hook just refreshed.
+    ProceedWithUrl(Cow<'static, str>),
+    /// Abandon this attempt and stop reconnecting, as if
+    /// [`close`](struct.WebSocket.html#method.close) had been called.
+    Cancel,
+}
+
+#[cfg(feature = "futures")]
+type BeforeReconnectHook =
+    Rc<RefCell<dyn FnMut() -> Box<dyn Future<Item = ReconnectDecision, Error = ()>>>>;
+
+struct Inner {
+    // The URL used by the most recent connection attempt, kept around for
+    // introspection (e.g. `Debug`); see `url_source` for where it comes from.
+    url: String,
+    url_source: UrlSource,
+    // Additional endpoints to rotate through after the primary (`url_source`, when
+    // it's `Static`) repeatedly fails. See `endpoint_index`.
+    fallback_urls: Vec<String>,
+    // Index into `[primary] + fallback_urls` of the endpoint used by the most recent
+    // connection attempt. Advanced on every failed attempt, reset to 0 on success.
+    endpoint_index: usize,
+    query_params: Vec<(String, String)>,
+    #[cfg(feature = "futures")]
+    before_reconnect: Option<BeforeReconnectHook>,
+    protocols: Vec<String>,
+    require_negotiated_protocol: bool,
+    // Whether an idle (see `ReadyState::Idle`) socket should connect itself the first
+    // time something is sent, instead of requiring an explicit `WebSocket::connect`.
+    connect_on_send: bool,
+    binary_type: web_sys::BinaryType,
+    connect_timeout: Option<Duration>,
+    reconnect_attempt_timeout: Option<Duration>,
+    reconnect_config: ReconnectConfig,
+    // Interval and payload template for the application-level heartbeat; see
+    // `WebSocketBuilder::heartbeat`.
+    heartbeat: Option<(Duration, WsMessage)>,
+    heartbeat_timer: Option<Interval>,
+    // Reply matcher and miss threshold for `WebSocketBuilder::expect_pong`.
+    heartbeat_pong: Option<(Rc<dyn Fn(&WsMessage) -> bool>, u32)>,
+    // Whether a reply matching `heartbeat_pong` has arrived since the last heartbeat
+    // was sent, and how many consecutive heartbeats have gone unanswered. Both reset
+    // whenever `start_heartbeat` (re)starts the timer.
+    heartbeat_pong_pending: bool,
+    heartbeat_misses: u32,
+    // When the most recent heartbeat was sent, so the matching pong's arrival can be
+    // turned into a round-trip sample; see `WebSocket::latency`.
+    heartbeat_sent_at: Option<f64>,
+    latency: Option<Duration>,
+    on_latency: Option<Rc<RefCell<dyn FnMut(Duration)>>>,
+    // Extractor for `WebSocketBuilder::sync_clock`, and the offset (server time minus
+    // client time, in milliseconds) last estimated from it.
+    clock_sync: Option<Rc<dyn Fn(&WsMessage) -> Option<f64>>>,
+    clock_offset: Option<f64>,
+    // Set by `force_reconnect` just before it closes the socket, so the `onclose`
+    // handler reconnects regardless of what the resulting close code's own
+    // `should_reconnect` says -- this end decided the connection was dead, not the
+    // server, so the close code itself (likely just `Normal`, the only one this crate
+    // can legally request) doesn't reflect that.
+    heartbeat_force_reconnect: bool,
+    // Capacity and filter for `WebSocketBuilder::replay_buffer`/`replay_filter`, and the
+    // buffer of recently-sent messages itself; see `record_for_replay` and
+    // `replay_sent_messages`.
+    replay_buffer_capacity: Option<usize>,
+    replay_filter: Option<Rc<dyn Fn(&WsMessage) -> bool>>,
+    replay_buffer: VecDeque<WsMessage>,
+    // Outgoing transform pipeline; see `WebSocketBuilder::outgoing_middleware`.
+    outgoing_middleware: Vec<Rc<RefCell<dyn FnMut(WsMessage) -> Option<WsMessage>>>>,
+    // Incoming transform pipeline; see `WebSocketBuilder::incoming_middleware`.
+    incoming_middleware: Vec<Rc<RefCell<dyn FnMut(WsMessage) -> Option<WsMessage>>>>,
+    // Observers attached with `WebSocketBuilder::add_interceptor`.
+    interceptors: Vec<Rc<RefCell<dyn Interceptor>>>,
+    // Bumped on every connection attempt so a stale connect-timeout from an earlier
+    // attempt can recognize it's no longer relevant.
+    connection_id: u64,
+    connect_timeout_handle: Option<Timeout>,
+    socket: Option<web_sys::WebSocket>,
+    ready_state: ReadyState,
+    manually_closed: bool,
+    reconnect_state: ReconnectState,
+    next_retry_in: Option<Duration>,
+    // The time source `schedule_reconnect` reads to drive `reconnect_state`. Always
+    // `SystemClock` outside of tests; see `WebSocket::set_clock`.
+    clock: Rc<dyn Clock>,
+    on_event: Rc<RefCell<dyn FnMut(WsEvent)>>,
+    onopen: Option<Closure<FnMut()>>,
+    onmessage: Option<Closure<FnMut(MessageEvent)>>,
+    onclose: Option<Closure<FnMut(CloseEvent)>>,
+    onerror: Option<Closure<FnMut(ErrorEvent)>>,
+    retry_timeout: Option<Timeout>,
+    close_timeout: Option<Timeout>,
+    on_drain: Option<Rc<RefCell<dyn FnMut()>>>,
+    drain_poll: Option<Interval>,
+    queue_enabled: bool,
+    queue_capacity: usize,
+    queue_max_bytes: Option<usize>,
+    queue_bytes: usize,
+    queue_overflow_policy: QueueOverflowPolicy,
+    on_queue_overflow: Option<Rc<RefCell<dyn FnMut()>>>,
+    pending_sends: VecDeque<QueuedSend>,
+    max_message_size: Option<usize>,
+    incoming_oversize_policy: OversizePolicy,
+    on_oversize: Option<Rc<RefCell<dyn FnMut(usize)>>>,
+    text_decode_policy: TextDecodePolicy,
+    on_text_decode_error: Option<Rc<RefCell<dyn FnMut()>>>,
+    validate_incoming: Option<Rc<RefCell<dyn FnMut(&WsMessage) -> Result<(), ValidationError>>>>,
+    close_on_invalid_message: bool,
+    on_invalid_message: Option<Rc<RefCell<dyn FnMut(&WsMessage, &ValidationError)>>>,
+    on_diagnostic: Option<Rc<RefCell<dyn FnMut(Diagnostic)>>>,
+    // Fires once reconnecting gives up after `ReconnectConfig::max_retries`; see
+    // `WebSocket::on_reconnect_exhausted`.
+    on_reconnect_exhausted: Option<Rc<RefCell<dyn FnMut()>>>,
+    // Fires after a successful reconnect, before the outgoing queue flushes; see
+    // `WebSocket::on_reopen`.
+    on_reopen: Option<Rc<RefCell<dyn FnMut(&WebSocket)>>>,
+    // Whether to close with `CloseCode::GoingAway` when the page unloads; see
+    // `WebSocketBuilder::close_on_unload`.
+    close_on_unload: bool,
+    unload_listener: Option<EventListener>,
+    // Whether scheduled reconnects are suspended because the browser reported itself
+    // offline; see `WebSocketBuilder::pause_when_offline`.
+    pause_when_offline: bool,
+    reconnect_paused: bool,
+    offline_listener: Option<EventListener>,
+    online_listener: Option<EventListener>,
+    // Whether scheduled reconnects are suspended because the tab is hidden; see
+    // `WebSocketBuilder::pause_when_hidden`.
+    pause_when_hidden: bool,
+    reconnect_paused_hidden: bool,
+    visibility_listener: Option<EventListener>,
+    // Whether to log connection lifecycle, reconnect scheduling, and message sizes to
+    // the browser console; see `WebSocketBuilder::debug`.
+    #[cfg(feature = "debug")]
+    debug: bool,
+    // Whether to sniff and transparently decompress gzip-magic-prefixed binary
+    // frames; see `WebSocketBuilder::auto_gunzip`.
+    #[cfg(feature = "deflate")]
+    auto_gunzip: bool,
+}
+
+/// A `WebSocket` connection which reconnects itself automatically when the underlying
+/// connection is lost.
+///
+/// Construct one with [`WebSocket::new`](#method.new), passing a callback which is
+/// invoked for every [`WsEvent`](enum.WsEvent.html) over the socket's lifetime.
+#[must_use = "the websocket is closed and stops reconnecting when dropped"]
+pub struct WebSocket {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("WebSocket")
+            .field("url", &inner.url)
+            .field("ready_state", &inner.ready_state)
+            .field("reconnect_attempt", &inner.reconnect_state.attempt())
+            .finish()
+    }
+}
+
+// The builder has no codec option: typed messages come from wrapping a built socket
+// with a `crate::codec::Codec`, as `crate::typed::TypedWebSocket` does.
+/// Configures a [`WebSocket`](struct.WebSocket.html) before opening the connection.
+///
+/// `WebSocketBuilder` is [`Clone`](#impl-Clone), so a configured builder can be stamped
+/// out into several sockets, e.g. to open the same kind of connection against a handful
+/// of different URLs.
+///
+/// # Example
+///
+/// ```no_run
+/// use gloo_websocket::cb::WebSocketBuilder;
+///
+/// let ws = WebSocketBuilder::new("wss://example.com/socket")
+///     .binary_type(web_sys::BinaryType::Blob)
+///     .build(move |_event| {})
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct WebSocketBuilder {
+    url: UrlSource,
+    fallback_urls: Vec<String>,
+    query_params: Vec<(String, String)>,
+    #[cfg(feature = "futures")]
+    before_reconnect: Option<BeforeReconnectHook>,
+    protocols: Vec<String>,
+    require_negotiated_protocol: bool,
+    connect_on_send: bool,
+    binary_type: web_sys::BinaryType,
+    connect_timeout: Option<Duration>,
+    reconnect_attempt_timeout: Option<Duration>,
+    reconnect_config: ReconnectConfig,
+    heartbeat: Option<(Duration, WsMessage)>,
+    heartbeat_pong: Option<(Rc<dyn Fn(&WsMessage) -> bool>, u32)>,
+    clock_sync: Option<Rc<dyn Fn(&WsMessage) -> Option<f64>>>,
+    replay_buffer_capacity: Option<usize>,
+    replay_filter: Option<Rc<dyn Fn(&WsMessage) -> bool>>,
+    outgoing_middleware: Vec<Rc<RefCell<dyn FnMut(WsMessage) -> Option<WsMessage>>>>,
+    incoming_middleware: Vec<Rc<RefCell<dyn FnMut(WsMessage) -> Option<WsMessage>>>>,
+    interceptors: Vec<Rc<RefCell<dyn Interceptor>>>,
+    max_message_size: Option<usize>,
+    incoming_oversize_policy: OversizePolicy,
+    text_decode_policy: TextDecodePolicy,
+    validate_incoming: Option<Rc<RefCell<dyn FnMut(&WsMessage) -> Result<(), ValidationError>>>>,
+    close_on_invalid_message: bool,
+    close_on_unload: bool,
+    pause_when_offline: bool,
+    pause_when_hidden: bool,
+    #[cfg(feature = "debug")]
+    debug: bool,
+    #[cfg(feature = "deflate")]
+    auto_gunzip: bool,
+}
+
+impl WebSocketBuilder {
+    /// Starts configuring a connection to `url`.
+    ///
+    /// `url` may be relative (e.g. `/socket` or `chat`), in which case it is resolved
+    /// against `window.location`, the same way a relative `fetch()` URL would be. It
+    /// isn't parsed or validated until [`build`](#method.build) is called.
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: UrlSource::Static(url.to_string()),
+            fallback_urls: Vec::new(),
+            query_params: Vec::new(),
+            #[cfg(feature = "futures")]
+            before_reconnect: None,
+            protocols: Vec::new(),
+            require_negotiated_protocol: false,
+            connect_on_send: false,
+            binary_type: web_sys::BinaryType::Arraybuffer,
+            connect_timeout: None,
+            reconnect_attempt_timeout: None,
+            reconnect_config: ReconnectConfig::default(),
+            heartbeat: None,
+            heartbeat_pong: None,
+            clock_sync: None,
+            replay_buffer_capacity: None,
+            replay_filter: None,
+            outgoing_middleware: Vec::new(),
+            incoming_middleware: Vec::new(),
+            interceptors: Vec::new(),
+            max_message_size: None,
+            incoming_oversize_policy: OversizePolicy::default(),
+            text_decode_policy: TextDecodePolicy::default(),
+            validate_incoming: None,
+            close_on_invalid_message: false,
+            close_on_unload: false,
+            pause_when_offline: false,
+            pause_when_hidden: false,
+            #[cfg(feature = "debug")]
+            debug: false,
+            #[cfg(feature = "deflate")]
+            auto_gunzip: false,
+        }
+    }
+
+    /// Sets the subprotocols to request during the opening handshake (the
+    /// `Sec-WebSocket-Protocol` header), in preference order.
+    pub fn protocols<I, S>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// When set, the connection is closed immediately after opening if the server
+    /// didn't negotiate one of the requested [`protocols`](#method.protocols),
+    /// instead of silently proceeding on an unexpected protocol.
+    ///
+    /// A mismatch emits [`WsEvent::ProtocolMismatch`](enum.WsEvent.html#variant.ProtocolMismatch)
+    /// and closes with [`CloseCode::PolicyViolation`](enum.CloseCode.html#variant.PolicyViolation)
+    /// without reconnecting, since a server that can't speak the required protocol
+    /// isn't going to start on a retry.
+    pub fn require_negotiated_protocol(mut self, require: bool) -> Self {
+        self.require_negotiated_protocol = require;
+        self
+    }
+
+    /// When set, a socket left [`ReadyState::Idle`](enum.ReadyState.html#variant.Idle) by
+    /// [`build_disconnected`](#method.build_disconnected) connects itself the first time a
+    /// message is sent, instead of requiring an explicit [`WebSocket::connect`](struct.WebSocket.html#method.connect).
+    ///
+    /// The triggering message is queued and delivered once the connection opens, the same
+    /// way messages sent while reconnecting are.
+    pub fn connect_on_send(mut self, enabled: bool) -> Self {
+        self.connect_on_send = enabled;
+        self
+    }
+
+    /// Runs `hook` before every reconnect attempt (not the initial connection),
+    /// letting it refresh an auth token, rotate the URL, or call off the attempt
+    /// entirely before the socket is opened.
+    ///
+    /// `hook` returns a future so it can do asynchronous work, e.g. fetching a new
+    /// JWT, before resolving to a [`ReconnectDecision`](enum.ReconnectDecision.html).
+    /// It's driven with [`wasm-bindgen-futures`](https://crates.io/crates/wasm-bindgen-futures),
+    /// so it doesn't need to be `Send`.
+    #[cfg(feature = "futures")]
+    pub fn before_reconnect<F, Fut>(mut self, mut hook: F) -> Self
+    where
+        F: FnMut() -> Fut + 'static,
+        Fut: Future<Item = ReconnectDecision, Error = ()> + 'static,
+    {
+        self.before_reconnect = Some(Rc::new(RefCell::new(move || {
+            Box::new(hook()) as Box<dyn Future<Item = ReconnectDecision, Error = ()>>
+        })));
+        self
+    }
+
+    /// Uses `provider` to produce the connection URL fresh on every connection
+    /// attempt, instead of the fixed URL passed to [`new`](#method.new).
+    ///
+    /// This makes it possible for each reconnect to embed a freshly-minted auth
+    /// token, rotate to a different host, or attach a new session id. Like a static
+    /// URL, the value returned by `provider` may be relative, and any
+    /// [`query_param`](#method.query_param)s configured on the builder are still
+    /// appended to it.
+    pub fn url_provider<F>(mut self, provider: F) -> Self
+    where
+        F: FnMut() -> Cow<'static, str> + 'static,
+    {
+        self.url = UrlSource::Provider(Rc::new(RefCell::new(provider)));
+        self
+    }
+
+    /// Adds fallback endpoints to rotate through when the primary URL keeps failing
+    /// to connect, so that a single endpoint outage doesn't take down the client.
+    ///
+    /// After each failed attempt the client moves to the next URL in `primary, then
+    /// each fallback in the order given`, wrapping back around to the primary; a
+    /// successful connection resets rotation back to the primary. This only applies
+    /// to a [`new`](#method.new) URL -- a [`url_provider`](#method.url_provider) is
+    /// already re-evaluated on every attempt and is responsible for its own failover,
+    /// so combining the two is rejected by [`build`](#method.build) with
+    /// [`BuildError::ConflictingOptions`](enum.BuildError.html#variant.ConflictingOptions).
+    pub fn fallback_urls<I, S>(mut self, urls: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.fallback_urls.extend(urls.into_iter().map(Into::into));
+        self
+    }
+
+    /// Appends a query parameter to the connection URL, percent-encoding it as needed.
+    ///
+    /// This is the usual way to pass an auth token or session id, since the browser's
+    /// `WebSocket` API doesn't allow setting custom headers on the handshake request.
+    pub fn query_param(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query_params.push((key.into(), value.into()));
+        self
+    }
+
+    /// Appends several query parameters to the connection URL at once. See
+    /// [`query_param`](#method.query_param).
+    pub fn query_params<I, K, V>(mut self, params: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.query_params
+            .extend(params.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    /// Sets how long to wait for the *initial* connection to open before giving up on
+    /// it and starting the reconnect cycle.
+    ///
+    /// By default there is no timeout, and the browser's own (typically very long)
+    /// connection timeout applies. See also
+    /// [`reconnect_attempt_timeout`](#method.reconnect_attempt_timeout), which governs
+    /// subsequent attempts.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long to wait for each reconnect attempt to open before giving up on it
+    /// and scheduling another one.
+    ///
+    /// By default there is no timeout, and the browser's own connection timeout
+    /// applies. See also [`connect_timeout`](#method.connect_timeout), which governs
+    /// only the first connection attempt.
+    pub fn reconnect_attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.reconnect_attempt_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how reconnect delays are computed. Defaults to
+    /// [`ExponentialBackoff`](struct.ExponentialBackoff.html); use
+    /// [`ReconnectConfig::with_strategy`](struct.ReconnectConfig.html#method.with_strategy)
+    /// with [`ConstantBackoff`](struct.ConstantBackoff.html),
+    /// [`LinearBackoff`](struct.LinearBackoff.html), or
+    /// [`FibonacciBackoff`](struct.FibonacciBackoff.html) for a gentler schedule, or a
+    /// custom [`BackoffStrategy`](trait.BackoffStrategy.html) for anything else.
+    pub fn reconnect_config(mut self, config: ReconnectConfig) -> Self {
+        self.reconnect_config = config;
+        self
+    }
+
+    /// Sends `payload` every `interval` while the connection is open, as an
+    /// application-level heartbeat.
+    ///
+    /// The timer starts when the socket opens and is cancelled as soon as it closes, so
+    /// it never fires against a dead or reconnecting socket; a new one starts after each
+    /// successful reconnect.
+    pub fn heartbeat(mut self, interval: Duration, payload: WsMessage) -> Self {
+        self.heartbeat = Some((interval, payload));
+        self
+    }
+
+    /// Closes and reconnects the socket if `miss_threshold` consecutive heartbeats go
+    /// by without an incoming message for which `is_pong` returns `true`.
+    ///
+    /// The browser has no way to notice a "half-open" connection -- one where the
+    /// underlying TCP connection died without a clean close, so no `Close` event ever
+    /// fires -- short of this kind of proactive, application-level check. A miss
+    /// counter resets as soon as a matching reply arrives, so one missed beat isn't
+    /// enough to trigger a reconnect by itself. Has no effect unless
+    /// [`heartbeat`](#method.heartbeat) is also configured.
+    pub fn expect_pong<F>(mut self, is_pong: F, miss_threshold: u32) -> Self
+    where
+        F: Fn(&WsMessage) -> bool + 'static,
+    {
+        self.heartbeat_pong = Some((Rc::new(is_pong), miss_threshold.max(1)));
+        self
+    }
+
+    /// Estimates the clock offset between this client and the server, using
+    /// `server_time` to read a server-reported timestamp (milliseconds since the Unix
+    /// epoch) out of an incoming message, typically a heartbeat reply.
+    ///
+    /// The offset is derived from the same round trip
+    /// [`expect_pong`](#method.expect_pong) already times, assuming the delay is split
+    /// evenly between the two directions -- good enough for loosely ordering events in
+    /// a collaborative app, not for anything that needs NTP-grade precision. Has no
+    /// effect unless `expect_pong` is also configured.
+    pub fn sync_clock<F>(mut self, server_time: F) -> Self
+    where
+        F: Fn(&WsMessage) -> Option<f64> + 'static,
+    {
+        self.clock_sync = Some(Rc::new(server_time));
+        self
+    }
+
+    /// Remembers the last `capacity` messages successfully handed to the browser, and
+    /// resends them after every reconnect.
+    ///
+    /// A message handed to `WebSocket.send` before the underlying connection dies
+    /// silently -- a dropped Wi-Fi signal, a laptop going to sleep -- has no guarantee
+    /// of having reached the server; the browser doesn't expose enough to tell. This
+    /// re-sends it just in case, trading a possible duplicate for a much less likely
+    /// silently-dropped action. Pair it with [`replay_filter`](#method.replay_filter)
+    /// if some of what you send shouldn't be replayed, or if a replayed duplicate
+    /// needs de-duping downstream (e.g. with [`crate::dedup`]).
+    pub fn replay_buffer(mut self, capacity: usize) -> Self {
+        self.replay_buffer_capacity = Some(capacity);
+        self
+    }
+
+    /// Filters which buffered messages [`replay_buffer`](#method.replay_buffer)
+    /// resends after a reconnect: `keep` is called once per buffered message, and only
+    /// those it returns `true` for are resent (and kept in the buffer for the next
+    /// reconnect, instead of being dropped). Has no effect unless `replay_buffer` is
+    /// also configured.
+    pub fn replay_filter<F>(mut self, keep: F) -> Self
+    where
+        F: Fn(&WsMessage) -> bool + 'static,
+    {
+        self.replay_filter = Some(Rc::new(keep));
+        self
+    }
+
+    /// Appends `transform` to the outgoing pipeline every
+    /// [`send_text`](struct.WebSocket.html#method.send_text)/[`send_bytes`](struct.WebSocket.html#method.send_bytes)
+    /// call (and anything built on them, like [`crate::multiplexer`]) runs through, in
+    /// the order registered, before the message reaches the network -- useful for
+    /// logging, enrichment (stamping an auth field onto every outgoing message), or
+    /// compression.
+    ///
+    /// Returning `None` drops the message: nothing is sent, and the callback passed to
+    /// [`send_text_with_callback`](struct.WebSocket.html#method.send_text_with_callback)/[`send_bytes_with_callback`](struct.WebSocket.html#method.send_bytes_with_callback)
+    /// (if any) sees `Ok(())`, since the drop was intentional rather than a failure.
+    ///
+    /// Call this more than once to chain several transforms.
+    pub fn outgoing_middleware<F>(mut self, transform: F) -> Self
+    where
+        F: FnMut(WsMessage) -> Option<WsMessage> + 'static,
+    {
+        self.outgoing_middleware.push(Rc::new(RefCell::new(transform)));
+        self
+    }
+
+    /// Appends `transform` to the incoming pipeline every frame runs through, in the
+    /// order registered, before it's checked against
+    /// [`validate_incoming`](#method.validate_incoming) or emitted as
+    /// [`WsEvent::Message`](enum.WsEvent.html#variant.Message) -- the symmetric
+    /// counterpart to [`outgoing_middleware`](#method.outgoing_middleware).
+    ///
+    /// Returning `None` consumes the frame instead of passing it on, for a control
+    /// frame a lower-level protocol layer (like [`crate::multiplexer`]'s control
+    /// frames) handles itself and that user code should never see.
+    ///
+    /// Call this more than once to chain several transforms.
+    pub fn incoming_middleware<F>(mut self, transform: F) -> Self
+    where
+        F: FnMut(WsMessage) -> Option<WsMessage> + 'static,
+    {
+        self.incoming_middleware.push(Rc::new(RefCell::new(transform)));
+        self
+    }
+
+    /// Attaches `interceptor`, which observes every send, receive, and lifecycle event
+    /// without being able to alter or drop any of them -- unlike
+    /// [`outgoing_middleware`](#method.outgoing_middleware)/[`incoming_middleware`](#method.incoming_middleware),
+    /// which exist for exactly that. See [`Interceptor`] for the hooks available.
+    ///
+    /// Call this more than once to attach several interceptors independently.
+    pub fn add_interceptor<I>(mut self, interceptor: I) -> Self
+    where
+        I: Interceptor + 'static,
+    {
+        self.interceptors.push(Rc::new(RefCell::new(interceptor)));
+        self
+    }
+
+    /// Rejects outgoing sends larger than `bytes` with
+    /// [`SendError::TooLarge`](enum.SendError.html#variant.TooLarge), and applies
+    /// [`incoming_oversize_policy`](#method.incoming_oversize_policy) to incoming frames
+    /// that exceed it.
+    ///
+    /// By default there is no limit, matching the browser's own (very large) one.
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = Some(bytes);
+        self
+    }
+
+    /// Sets what happens to an incoming frame larger than
+    /// [`max_message_size`](#method.max_message_size). Defaults to
+    /// [`OversizePolicy::Drop`](enum.OversizePolicy.html#variant.Drop).
+    ///
+    /// Has no effect unless `max_message_size` is also set. See also
+    /// [`WebSocket::on_oversize`](struct.WebSocket.html#method.on_oversize), which fires
+    /// regardless of which policy is chosen.
+    pub fn incoming_oversize_policy(mut self, policy: OversizePolicy) -> Self {
+        self.incoming_oversize_policy = policy;
+        self
+    }
+
+    /// Sets what happens to a text frame the browser could only decode by
+    /// substituting Unicode replacement characters for invalid UTF-16. Defaults to
+    /// [`TextDecodePolicy::Lossy`](enum.TextDecodePolicy.html#variant.Lossy), matching
+    /// this crate's prior (unconditional) behavior.
+    ///
+    /// See also [`WebSocket::on_text_decode_error`](struct.WebSocket.html#method.on_text_decode_error),
+    /// which fires regardless of which policy is chosen.
+    pub fn text_decode_policy(mut self, policy: TextDecodePolicy) -> Self {
+        self.text_decode_policy = policy;
+        self
+    }
+
+    /// Runs `validate` on every incoming frame before it reaches
+    /// [`WsEvent::Message`](enum.WsEvent.html#variant.Message), centralizing schema or
+    /// sanity checks that would otherwise have to be duplicated in every message
+    /// handler.
+    ///
+    /// A frame `validate` rejects is never emitted as a `WsEvent::Message`; instead it's
+    /// reported through [`WebSocket::on_invalid_message`](struct.WebSocket.html#method.on_invalid_message),
+    /// and the connection is closed if [`close_on_invalid_message`](#method.close_on_invalid_message)
+    /// is set.
+    pub fn validate_incoming<F>(mut self, validate: F) -> Self
+    where
+        F: FnMut(&WsMessage) -> Result<(), ValidationError> + 'static,
+    {
+        self.validate_incoming = Some(Rc::new(RefCell::new(validate)));
+        self
+    }
+
+    /// When set, a frame rejected by [`validate_incoming`](#method.validate_incoming)
+    /// closes the connection with [`CloseCode::InvalidPayloadData`](enum.CloseCode.html#variant.InvalidPayloadData)
+    /// instead of simply being dropped.
+    ///
+    /// Has no effect unless `validate_incoming` is also configured.
+    pub fn close_on_invalid_message(mut self, close: bool) -> Self {
+        self.close_on_invalid_message = close;
+        self
+    }
+
+    /// Closes the connection with [`CloseCode::GoingAway`](enum.CloseCode.html) when the
+    /// page is unloaded (navigated away from, closed, or refreshed), so the server sees a
+    /// clean departure instead of an abrupt TCP reset.
+    ///
+    /// Listens for the `pagehide` event, which fires reliably on unload across browsers,
+    /// including on mobile where `beforeunload` is unreliable. Has no effect outside a
+    /// window (e.g. in a worker). The listener is removed when the socket is dropped.
+    pub fn close_on_unload(mut self, enabled: bool) -> Self {
+        self.close_on_unload = enabled;
+        self
+    }
+
+    /// Suspends the reconnect backoff cycle while
+    /// [`navigator.onLine`](https://developer.mozilla.org/en-US/docs/Web/API/Navigator/onLine)
+    /// reports the browser is offline, instead of burning attempts against a dead
+    /// network.
+    ///
+    /// A reconnect already in flight when the browser goes offline is cancelled without
+    /// counting against [`reconnect_attempt`](struct.WebSocket.html#method.reconnect_attempt);
+    /// it resumes once the browser is back online.
+    ///
+    /// Has no effect outside a window (e.g. in a worker): `navigator.onLine` isn't
+    /// observed there, so the connection is always treated as online and reconnects
+    /// proceed on schedule. The reconnect timers themselves are scheduled through
+    /// `gloo-timers`, which works the same in a worker as in a window.
+    pub fn pause_when_offline(mut self, enabled: bool) -> Self {
+        self.pause_when_offline = enabled;
+        self
+    }
+
+    /// Suspends the reconnect backoff cycle while the tab is hidden (per
+    /// [`document.visibilityState`](https://developer.mozilla.org/en-US/docs/Web/API/Document/visibilityState)),
+    /// and attempts a reconnect right away when it becomes visible again.
+    ///
+    /// Saves battery and server load from backgrounded tabs that would otherwise keep
+    /// retrying a dead connection no one is watching.
+    ///
+    /// Has no effect outside a window (e.g. in a worker), since there is no document to
+    /// query visibility from; the tab is always treated as visible there.
+    pub fn pause_when_hidden(mut self, enabled: bool) -> Self {
+        self.pause_when_hidden = enabled;
+        self
+    }
+
+    /// Logs connection lifecycle events, reconnect scheduling, and outgoing/incoming
+    /// message sizes to the browser console, each prefixed with `[gloo-websocket]`.
+    ///
+    /// Meant for diagnosing connection issues during development; only available when
+    /// the `debug` Cargo feature is enabled, so it costs nothing in a release build
+    /// that doesn't turn it on.
+    #[cfg(feature = "debug")]
+    pub fn debug(mut self, enabled: bool) -> Self {
+        self.debug = enabled;
+        self
+    }
+
+    /// Sniffs every incoming binary frame for the gzip magic bytes (`1f 8b`) and
+    /// transparently decompresses it before it's emitted as
+    /// [`WsEvent::Message`](enum.WsEvent.html#variant.Message), for a server that
+    /// compresses payloads itself rather than relying on the
+    /// [permessage-deflate](https://datatracker.ietf.org/doc/html/rfc7692) extension
+    /// the browser already negotiates transparently.
+    ///
+    /// A frame that starts with the magic bytes but fails to decompress is reported
+    /// through [`Diagnostic::GunzipFailed`](enum.Diagnostic.html#variant.GunzipFailed)
+    /// and delivered as-is, still gzipped, rather than dropped -- the caller's own
+    /// decoding is likely to fail loudly on it anyway, which is more useful than a
+    /// silently missing message. Only available when the `deflate` Cargo feature is
+    /// enabled, since decompression is done with the same `flate2` this crate's
+    /// [`codec::deflate`](codec/deflate/index.html) already depends on.
+    #[cfg(feature = "deflate")]
+    pub fn auto_gunzip(mut self, enabled: bool) -> Self {
+        self.auto_gunzip = enabled;
+        self
+    }
+
+    /// Sets the [binary type](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/binaryType)
+    /// used for incoming binary frames. Defaults to `Arraybuffer`.
+    ///
+    /// Selecting `Blob` is mainly useful when passing large payloads straight through to
+    /// another browser API (e.g. an `<img>` tag) without copying them into Wasm memory.
+    pub fn binary_type(mut self, binary_type: web_sys::BinaryType) -> Self {
+        self.binary_type = binary_type;
+        self
+    }
+
+    /// Opens the connection, calling `on_event` for every [`WsEvent`](enum.WsEvent.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BuildError::InvalidUrl`](enum.BuildError.html#variant.InvalidUrl) if
+    /// the configured URL can't be parsed, or doesn't resolve to the `ws`/`wss` scheme.
+    /// A [`url_provider`](#method.url_provider) isn't evaluated until the first
+    /// connection attempt, so this can't catch a bad URL it produces; that attempt
+    /// simply fails and a reconnect is scheduled like any other failed attempt.
+    ///
+    /// Returns [`BuildError::ConflictingOptions`](enum.BuildError.html#variant.ConflictingOptions)
+    /// if two configured options can't be used together, e.g.
+    /// [`fallback_urls`](#method.fallback_urls) with a [`url_provider`](#method.url_provider) --
+    /// rather than one silently winning over the other.
+    pub fn build<F>(self, on_event: F) -> Result<WebSocket, BuildError>
+    where
+        F: FnMut(WsEvent) + 'static,
+    {
+        let inner = self.build_inner(on_event)?;
+        connect(&inner);
+        Ok(WebSocket { inner })
+    }
+
+    /// Builds the [`WebSocket`](struct.WebSocket.html) without opening a connection,
+    /// leaving it in [`ReadyState::Idle`](enum.ReadyState.html#variant.Idle) until
+    /// [`WebSocket::connect`](struct.WebSocket.html#method.connect) is called.
+    ///
+    /// This is useful for wiring up event handlers and holding onto the instance
+    /// before any network activity should start, e.g. while waiting for a user to
+    /// log in.
+    ///
+    /// # Errors
+    ///
+    /// See [`build`](#method.build).
+    pub fn build_disconnected<F>(self, on_event: F) -> Result<WebSocket, BuildError>
+    where
+        F: FnMut(WsEvent) + 'static,
+    {
+        let inner = self.build_inner(on_event)?;
+        inner.borrow_mut().ready_state = ReadyState::Idle;
+        Ok(WebSocket { inner })
+    }
+
+    fn build_inner<F>(self, on_event: F) -> Result<Rc<RefCell<Inner>>, BuildError>
+    where
+        F: FnMut(WsEvent) + 'static,
+    {
+        if let UrlSource::Provider(_) = self.url {
+            if !self.fallback_urls.is_empty() {
+                return Err(BuildError::ConflictingOptions {
+                    reason: "fallback_urls has no effect with url_provider, \
+                             since the provider is already re-evaluated on every attempt",
+                });
+            }
+        }
+
+        if let UrlSource::Static(ref raw) = self.url {
+            resolve_url(raw, &self.query_params)?;
+        }
+
+        let inner = Rc::new(RefCell::new(Inner {
+            url: String::new(),
+            url_source: self.url,
+            fallback_urls: self.fallback_urls,
+            endpoint_index: 0,
+            query_params: self.query_params,
+            #[cfg(feature = "futures")]
+            before_reconnect: self.before_reconnect,
+            protocols: self.protocols,
+            require_negotiated_protocol: self.require_negotiated_protocol,
+            connect_on_send: self.connect_on_send,
+            binary_type: self.binary_type,
+            connect_timeout: self.connect_timeout,
+            reconnect_attempt_timeout: self.reconnect_attempt_timeout,
+            reconnect_config: self.reconnect_config,
+            heartbeat: self.heartbeat,
+            heartbeat_timer: None,
+            heartbeat_pong: self.heartbeat_pong,
+            heartbeat_pong_pending: false,
+            heartbeat_misses: 0,
+            heartbeat_sent_at: None,
+            latency: None,
+            on_latency: None,
+            clock_sync: self.clock_sync,
+            clock_offset: None,
+            heartbeat_force_reconnect: false,
+            replay_buffer_capacity: self.replay_buffer_capacity,
+            replay_filter: self.replay_filter,
+            replay_buffer: VecDeque::new(),
+            outgoing_middleware: self.outgoing_middleware,
+            incoming_middleware: self.incoming_middleware,
+            interceptors: self.interceptors,
+            connection_id: 0,
+            connect_timeout_handle: None,
+            socket: None,
+            ready_state: ReadyState::Connecting,
+            manually_closed: false,
+            reconnect_state: ReconnectState::new(),
+            next_retry_in: None,
+            clock: Rc::new(SystemClock),
+            on_event: Rc::new(RefCell::new(on_event)),
+            onopen: None,
+            onmessage: None,
+            onclose: None,
+            onerror: None,
+            retry_timeout: None,
+            close_timeout: None,
+            on_drain: None,
+            drain_poll: None,
+            queue_enabled: true,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            queue_max_bytes: None,
+            queue_bytes: 0,
+            queue_overflow_policy: QueueOverflowPolicy::default(),
+            on_queue_overflow: None,
+            pending_sends: VecDeque::new(),
+            max_message_size: self.max_message_size,
+            incoming_oversize_policy: self.incoming_oversize_policy,
+            on_oversize: None,
+            text_decode_policy: self.text_decode_policy,
+            on_text_decode_error: None,
+            validate_incoming: self.validate_incoming,
+            close_on_invalid_message: self.close_on_invalid_message,
+            on_invalid_message: None,
+            on_diagnostic: None,
+            on_reconnect_exhausted: None,
+            on_reopen: None,
+            close_on_unload: self.close_on_unload,
+            unload_listener: None,
+            pause_when_offline: self.pause_when_offline,
+            reconnect_paused: false,
+            offline_listener: None,
+            online_listener: None,
+            pause_when_hidden: self.pause_when_hidden,
+            reconnect_paused_hidden: false,
+            visibility_listener: None,
+            #[cfg(feature = "debug")]
+            debug: self.debug,
+            #[cfg(feature = "deflate")]
+            auto_gunzip: self.auto_gunzip,
+        }));
+
+        if inner.borrow().close_on_unload {
+            attach_unload_listener(&inner);
+        }
+
+        if inner.borrow().pause_when_offline {
+            attach_network_listeners(&inner);
+        }
+
+        if inner.borrow().pause_when_hidden {
+            attach_visibility_listener(&inner);
+        }
+
+        Ok(inner)
+    }
+}
+
+impl fmt::Debug for WebSocketBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebSocketBuilder")
+            .field("protocols", &self.protocols)
+            .field("binary_type", &self.binary_type)
+            .finish()
+    }
+}
+
+impl WebSocket {
+    /// Opens a connection to `url`, reconnecting automatically if it drops.
+    ///
+    /// `on_event` is called for every [`WsEvent`](enum.WsEvent.html), including the
+    /// ones generated by transparent reconnects.
+    ///
+    /// For more configuration options, use [`WebSocketBuilder`](struct.WebSocketBuilder.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BuildError::InvalidUrl`](enum.BuildError.html#variant.InvalidUrl) if
+    /// `url` can't be parsed, or doesn't resolve to the `ws`/`wss` scheme.
+    pub fn new<F>(url: &str, on_event: F) -> Result<Self, BuildError>
+    where
+        F: FnMut(WsEvent) + 'static,
+    {
+        WebSocketBuilder::new(url).build(on_event)
+    }
+
+    /// The [binary type](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/binaryType)
+    /// used for incoming binary frames. Configure this with
+    /// [`WebSocketBuilder::binary_type`](struct.WebSocketBuilder.html#method.binary_type).
+    pub fn binary_type(&self) -> web_sys::BinaryType {
+        self.inner.borrow().binary_type
+    }
+
+    /// The current [`ReadyState`](enum.ReadyState.html) of the connection.
+    pub fn ready_state(&self) -> ReadyState {
+        self.inner.borrow().ready_state
+    }
+
+    /// The subprotocol the server actually negotiated (the
+    /// `Sec-WebSocket-Protocol` response header), or empty if none was requested or
+    /// negotiated. Only meaningful once the connection is open -- see
+    /// [`WebSocketBuilder::protocols`](struct.WebSocketBuilder.html#method.protocols).
+    pub fn protocol(&self) -> String {
+        self.inner.borrow().socket.as_ref().map_or_else(String::new, |socket| socket.protocol())
+    }
+
+    /// Starts connecting a [`WebSocket`](struct.WebSocket.html) built with
+    /// [`WebSocketBuilder::build_disconnected`](struct.WebSocketBuilder.html#method.build_disconnected).
+    ///
+    /// Does nothing if the connection isn't [`ReadyState::Idle`](enum.ReadyState.html#variant.Idle),
+    /// e.g. because it's already connecting, open, or was built with
+    /// [`WebSocketBuilder::build`](struct.WebSocketBuilder.html#method.build).
+    pub fn connect(&self) {
+        if self.inner.borrow().ready_state == ReadyState::Idle {
+            connect(&self.inner);
+        }
+    }
+
+    /// How many reconnect attempts have been made since the last successful connection.
+    ///
+    /// This is `0` while connected, and while the initial connection is still pending.
+    pub fn reconnect_attempt(&self) -> u32 {
+        self.inner.borrow().reconnect_state.attempt()
+    }
+
+    /// How long until the next reconnect attempt fires, if one is scheduled.
+    ///
+    /// Returns `None` when the socket is open, or has been closed manually and is not
+    /// waiting to retry.
+    pub fn next_retry_in(&self) -> Option<Duration> {
+        self.inner.borrow().next_retry_in
+    }
+
+    /// A rolling estimate of round-trip latency, measured from each heartbeat to its
+    /// matching pong.
+    ///
+    /// Returns `None` until the first sample comes in, and always returns `None` if
+    /// [`WebSocketBuilder::expect_pong`](struct.WebSocketBuilder.html#method.expect_pong)
+    /// isn't configured -- without it there's no way to recognize which incoming
+    /// message answers which heartbeat.
+    pub fn latency(&self) -> Option<Duration> {
+        self.inner.borrow().latency
+    }
+
+    /// Registers a callback which fires with each new [`latency`](#method.latency)
+    /// sample as it's measured, e.g. to drive a connection-quality indicator.
+    pub fn on_latency<F>(&self, callback: F)
+    where
+        F: FnMut(Duration) + 'static,
+    {
+        self.inner.borrow_mut().on_latency = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// The estimated clock offset between this client and the server, in
+    /// milliseconds: positive means the server's clock is ahead of this one.
+    ///
+    /// Returns `None` until the first estimate comes in, and always returns `None`
+    /// unless [`WebSocketBuilder::sync_clock`](struct.WebSocketBuilder.html#method.sync_clock)
+    /// is configured.
+    pub fn clock_offset(&self) -> Option<i64> {
+        self.inner.borrow().clock_offset.map(|offset| offset as i64)
+    }
+
+    /// If a reconnect is currently waiting out its backoff delay, cancels the wait and
+    /// attempts it immediately instead, without bumping
+    /// [`reconnect_attempt`](#method.reconnect_attempt). Useful for reconnecting right
+    /// away on some signal the backoff schedule doesn't know about, e.g. the user
+    /// pressing a "retry now" button.
+    ///
+    /// Returns whether a pending reconnect was actually waiting to be cancelled.
+    pub fn reconnect_now(&self) -> bool {
+        let was_pending = self.inner.borrow_mut().retry_timeout.take().is_some();
+        if was_pending {
+            self.inner.borrow_mut().next_retry_in = None;
+            attempt_reconnect(&self.inner);
+        }
+        was_pending
+    }
+
+    /// Swaps in a different clock for reconnect timing to read, in place of the real
+    /// `Date.now()`.
+    ///
+    /// Not exposed publicly: it exists so a future test harness can drive
+    /// `reconnect_state` with simulated time instead of waiting out real backoff delays.
+    #[allow(dead_code)]
+    pub(crate) fn set_clock(&self, clock: Rc<dyn Clock>) {
+        self.inner.borrow_mut().clock = clock;
+    }
+
+    /// Sends a text message. If the connection is not currently open, the message is
+    /// queued and sent once it is.
+    pub fn send_text(&self, text: &str) {
+        self.send_text_with_callback(text, |_| {});
+    }
+
+    /// Sends a binary message. If the connection is not currently open, the message is
+    /// queued and sent once it is.
+    pub fn send_bytes(&self, bytes: &[u8]) {
+        self.send_bytes_with_callback(bytes, |_| {});
+    }
+
+    /// Sends a text message, reporting back whether it was handed off to the network
+    /// layer successfully.
+    ///
+    /// When the message is handed to `WebSocket.send` right away, `callback` fires
+    /// synchronously, since that call either succeeds or fails immediately. A queued
+    /// message's `callback` fires when the queue is flushed, or with an error if a full
+    /// queue rejects or drops it; a message still queued when the socket is closed is
+    /// discarded without its `callback` firing.
+    ///
+    /// If the socket is currently connecting or reconnecting, the message is queued and
+    /// sent automatically once the connection opens; see
+    /// [`set_outgoing_queue_enabled`](#method.set_outgoing_queue_enabled).
+    pub fn send_text_with_callback<F>(&self, text: &str, callback: F)
+    where
+        F: FnOnce(Result<(), SendError>) + 'static,
+    {
+        enqueue_or_send(&self.inner, OutgoingMessage::Text(Cow::Borrowed(text)), callback);
+    }
+
+    /// Sends a binary message, reporting back whether it was handed off to the network
+    /// layer successfully. See [`send_text_with_callback`](#method.send_text_with_callback).
+    pub fn send_bytes_with_callback<F>(&self, bytes: &[u8], callback: F)
+    where
+        F: FnOnce(Result<(), SendError>) + 'static,
+    {
+        enqueue_or_send(&self.inner, OutgoingMessage::Bytes(Cow::Borrowed(bytes)), callback);
+    }
+
+    /// Enables or disables automatic queueing of sends issued while connecting or
+    /// reconnecting. Enabled by default; when disabled, such sends fail immediately
+    /// instead of being buffered.
+    pub fn set_outgoing_queue_enabled(&self, enabled: bool) {
+        self.inner.borrow_mut().queue_enabled = enabled;
+    }
+
+    /// Sets the maximum number of messages the outgoing queue will hold.
+    pub fn set_outgoing_queue_capacity(&self, capacity: usize) {
+        self.inner.borrow_mut().queue_capacity = capacity;
+    }
+
+    /// Sets the maximum total size, in bytes, the outgoing queue will hold. `None`
+    /// (the default) means the queue is only bounded by
+    /// [`set_outgoing_queue_capacity`](#method.set_outgoing_queue_capacity).
+    pub fn set_outgoing_queue_max_bytes(&self, max_bytes: Option<usize>) {
+        self.inner.borrow_mut().queue_max_bytes = max_bytes;
+    }
+
+    /// Sets what happens when a send arrives while the outgoing queue is already full.
+    /// Defaults to [`QueueOverflowPolicy::Reject`](enum.QueueOverflowPolicy.html).
+    pub fn set_outgoing_queue_overflow_policy(&self, policy: QueueOverflowPolicy) {
+        self.inner.borrow_mut().queue_overflow_policy = policy;
+    }
+
+    /// Registers a callback which fires whenever the outgoing queue overflows, whether
+    /// the new message was rejected or an old one was dropped to make room for it.
+    pub fn on_queue_overflow<F>(&self, callback: F)
+    where
+        F: FnMut() + 'static,
+    {
+        self.inner.borrow_mut().on_queue_overflow = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Registers a callback which fires whenever an incoming frame exceeds the
+    /// configured [`max_message_size`](struct.WebSocketBuilder.html#method.max_message_size),
+    /// with the frame's size in bytes, regardless of whether it was dropped or truncated.
+    pub fn on_oversize<F>(&self, callback: F)
+    where
+        F: FnMut(usize) + 'static,
+    {
+        self.inner.borrow_mut().on_oversize = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Registers a callback which fires whenever a text frame could only be decoded by
+    /// substituting Unicode replacement characters for invalid UTF-16, regardless of
+    /// which [`TextDecodePolicy`](enum.TextDecodePolicy.html) is set.
+    pub fn on_text_decode_error<F>(&self, callback: F)
+    where
+        F: FnMut() + 'static,
+    {
+        self.inner.borrow_mut().on_text_decode_error = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Registers a callback which fires whenever
+    /// [`WebSocketBuilder::validate_incoming`](struct.WebSocketBuilder.html#method.validate_incoming)
+    /// rejects an incoming frame, with the frame and the reason it was rejected. The
+    /// frame is never emitted as a `WsEvent::Message`.
+    pub fn on_invalid_message<F>(&self, callback: F)
+    where
+        F: FnMut(&WsMessage, &ValidationError) + 'static,
+    {
+        self.inner.borrow_mut().on_invalid_message = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Registers a callback which fires for non-fatal internal events -- such as the
+    /// local close call failing, or a scheduled heartbeat send failing -- that the
+    /// crate would otherwise swallow silently. See [`Diagnostic`](enum.Diagnostic.html).
+    pub fn on_diagnostic<F>(&self, callback: F)
+    where
+        F: FnMut(Diagnostic) + 'static,
+    {
+        self.inner.borrow_mut().on_diagnostic = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Registers a callback which fires once the socket gives up reconnecting after
+    /// [`ReconnectConfig::max_retries`](struct.ReconnectConfig.html#method.max_retries)
+    /// consecutive failed attempts. The socket is closed by the time this fires.
+    pub fn on_reconnect_exhausted<F>(&self, callback: F)
+    where
+        F: FnMut() + 'static,
+    {
+        self.inner.borrow_mut().on_reconnect_exhausted = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Registers a callback which fires after a successful *reconnect* (not the initial
+    /// connection), before any messages queued while disconnected are flushed. Send from
+    /// here -- e.g. a "resume from sequence N" frame -- to have it go out ahead of the
+    /// queued backlog.
+    pub fn on_reopen<F>(&self, callback: F)
+    where
+        F: FnMut(&WebSocket) + 'static,
+    {
+        self.inner.borrow_mut().on_reopen = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// The number of bytes of data that have been queued but not yet transmitted to the
+    /// network, mirroring [`bufferedAmount`](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/bufferedAmount).
+    pub fn buffered_amount(&self) -> u32 {
+        self.inner
+            .borrow()
+            .socket
+            .as_ref()
+            .map(Transport::buffered_amount)
+            .unwrap_or(0)
+    }
+
+    /// Registers a callback which fires once `bufferedAmount` drains to zero after a send.
+    ///
+    /// Only one drain callback is active at a time; registering a new one replaces the
+    /// previous one.
+    pub fn on_drain<F>(&self, callback: F)
+    where
+        F: FnMut() + 'static,
+    {
+        self.inner.borrow_mut().on_drain = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Closes the connection with [`CloseCode::Normal`](enum.CloseCode.html) and stops
+    /// reconnecting.
+    pub fn close(&self) -> Result<(), CloseError> {
+        self.close_with_code(CloseCode::Normal, "")
+    }
+
+    /// Closes the connection with the given close code and reason, and stops
+    /// reconnecting.
+    ///
+    /// The socket is only fully torn down once the browser confirms the close
+    /// handshake has completed. If the peer or network never confirms it, consider
+    /// [`close_with_timeout`](#method.close_with_timeout) instead.
+    ///
+    /// Returns an error if the browser rejected the close request itself (e.g. an
+    /// invalid close code); this is also reported through
+    /// [`on_diagnostic`](#method.on_diagnostic) for callers who don't check it. Either
+    /// way, `WebSocket` stops reconnecting and drops its outgoing queue.
+    pub fn close_with_code(&self, code: CloseCode, reason: &str) -> Result<(), CloseError> {
+        close_with_code(&self.inner, code, reason)
+    }
+
+    /// Closes the connection like [`close_with_code`](#method.close_with_code), but
+    /// forces the connection to be torn down after `timeout` if the browser hasn't
+    /// confirmed the close handshake by then.
+    ///
+    /// This guards against a peer or proxy that stalls the close handshake, which
+    /// would otherwise leave the socket stuck in [`ReadyState::Closing`](enum.ReadyState.html#variant.Closing) forever.
+    pub fn close_with_timeout(&self, code: CloseCode, reason: &str, timeout: Duration) -> Result<(), CloseError> {
+        self.close_with_code(code, reason)?;
+
+        let weak_inner = Rc::downgrade(&self.inner);
+        let force_close = Timeout::new(millis_saturating(timeout), move || {
+            if let Some(inner) = weak_inner.upgrade() {
+                let still_closing = inner.borrow().ready_state == ReadyState::Closing;
+                if still_closing {
+                    finalize_close(&inner);
+                }
+            }
+        });
+
+        self.inner.borrow_mut().close_timeout = Some(force_close);
+        Ok(())
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        // The only strong references to `inner` are this handle and, transiently, the
+        // live `web_sys::WebSocket`'s own Rust-side callbacks (which hold weak
+        // references, not strong ones), so this is always the last handle. Any failure
+        // is already surfaced through `Diagnostic::CloseFailed`, so there's nothing left
+        // to do with the result here.
+        let _ = self.close();
+    }
+}
+
+// Converts a `Duration` to the millisecond count `Timeout`/`Interval` expect, saturating
+// at `u32::MAX` instead of wrapping for durations that don't fit (over ~49 days).
+fn millis_saturating(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
+
+// Detaches the Rust-side handlers from the JS socket before dropping them, so that a
+// stray event on an already-abandoned socket can't invoke a dead `Closure`.
+fn detach_socket(state: &mut Inner) {
+    if let Some(socket) = state.socket.take() {
+        socket.set_onopen(None);
+        socket.set_onmessage(None);
+        socket.set_onclose(None);
+        socket.set_onerror(None);
+    }
+    state.onopen = None;
+    state.onmessage = None;
+    state.onclose = None;
+    state.onerror = None;
+    state.heartbeat_timer = None;
+}
+
+fn finalize_close(inner: &Rc<RefCell<Inner>>) {
+    let mut state = inner.borrow_mut();
+    detach_socket(&mut state);
+    state.close_timeout = None;
+    state.ready_state = ReadyState::Closed;
+}
+
+fn close_with_code(inner: &Rc<RefCell<Inner>>, code: CloseCode, reason: &str) -> Result<(), CloseError> {
+    let close_failure = {
+        let mut state = inner.borrow_mut();
+        state.manually_closed = true;
+        state.retry_timeout = None;
+        state.next_retry_in = None;
+        state.pending_sends.clear();
+        state.queue_bytes = 0;
+
+        match state.socket.clone() {
+            Some(socket) => {
+                state.ready_state = ReadyState::Closing;
+                Transport::close(&socket, code.into(), reason).err()
+            }
+            None => {
+                state.ready_state = ReadyState::Closed;
+                None
+            }
+        }
+    };
+
+    match close_failure {
+        Some(err) => {
+            report_diagnostic(inner, Diagnostic::CloseFailed(err.clone()));
+            Err(CloseError(err))
+        }
+        None => Ok(()),
+    }
+}
+
+// `pagehide` fires reliably across browsers (including mobile, where `beforeunload` is
+// flaky) whenever the page is navigated away from, closed, or refreshed.
+fn attach_unload_listener(inner: &Rc<RefCell<Inner>>) {
+    let window = match web_sys::window() {
+        Some(window) => window,
+        None => return,
+    };
+
+    let weak_inner = Rc::downgrade(inner);
+    let listener = EventListener::new(&window, "pagehide", move |_event| {
+        if let Some(inner) = weak_inner.upgrade() {
+            let _ = close_with_code(&inner, CloseCode::GoingAway, "page is unloading");
+        }
+    });
+
+    inner.borrow_mut().unload_listener = Some(listener);
+}
+
+fn is_online() -> bool {
+    web_sys::window()
+        .map(|window| window.navigator().on_line())
+        .unwrap_or(true)
+}
+
+fn is_hidden() -> bool {
+    web_sys::window()
+        .and_then(|window| window.document())
+        .map(|document| document.hidden())
+        .unwrap_or(false)
+}
+
+fn attach_network_listeners(inner: &Rc<RefCell<Inner>>) {
+    let window = match web_sys::window() {
+        Some(window) => window,
+        None => return,
+    };
+
+    let weak_inner = Rc::downgrade(inner);
+    let offline_listener = EventListener::new(&window, "offline", move |_event| {
+        if let Some(inner) = weak_inner.upgrade() {
+            let mut state = inner.borrow_mut();
+            if state.retry_timeout.take().is_some() {
+                state.reconnect_paused = true;
+                state.next_retry_in = None;
+            }
+        }
+    });
+
+    let weak_inner = Rc::downgrade(inner);
+    let online_listener = EventListener::new(&window, "online", move |_event| {
+        let inner = match weak_inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        let was_paused = {
+            let mut state = inner.borrow_mut();
+            let was_paused = state.reconnect_paused;
+            state.reconnect_paused = false;
+            was_paused
+        };
+
+        if was_paused {
+            // Got cut short by `offline`, so this attempt shouldn't count against the
+            // backoff sequence -- the network coming back isn't a failure to reconnect.
+            inner.borrow_mut().reconnect_state.reset_attempt_count();
+            connect(&inner);
+        }
+    });
+
+    let mut state = inner.borrow_mut();
+    state.offline_listener = Some(offline_listener);
+    state.online_listener = Some(online_listener);
+}
+
+fn attach_visibility_listener(inner: &Rc<RefCell<Inner>>) {
+    let document = match web_sys::window().and_then(|window| window.document()) {
+        Some(document) => document,
+        None => return,
+    };
+
+    let weak_inner = Rc::downgrade(inner);
+    let listener = EventListener::new(&document, "visibilitychange", move |_event| {
+        let inner = match weak_inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        if is_hidden() {
+            let mut state = inner.borrow_mut();
+            if state.retry_timeout.take().is_some() {
+                state.reconnect_paused_hidden = true;
+                state.next_retry_in = None;
+            }
+            return;
+        }
+
+        let was_paused = {
+            let mut state = inner.borrow_mut();
+            let was_paused = state.reconnect_paused_hidden;
+            state.reconnect_paused_hidden = false;
+            was_paused
+        };
+
+        if was_paused {
+            inner.borrow_mut().reconnect_state.reset_attempt_count();
+            connect(&inner);
+        }
+    });
+
+    inner.borrow_mut().visibility_listener = Some(listener);
+}
+
+fn connect(inner: &Rc<RefCell<Inner>>) {
+    let raw_url = {
+        let mut state = inner.borrow_mut();
+        let fallback_urls = state.fallback_urls.clone();
+        let endpoint_index = state.endpoint_index;
+        match &mut state.url_source {
+            UrlSource::Provider(provider) => (provider.borrow_mut())(),
+            UrlSource::Static(primary) => {
+                if fallback_urls.is_empty() {
+                    Cow::Owned(primary.clone())
+                } else {
+                    let idx = endpoint_index % (fallback_urls.len() + 1);
+                    if idx == 0 {
+                        Cow::Owned(primary.clone())
+                    } else {
+                        Cow::Owned(fallback_urls[idx - 1].clone())
+                    }
+                }
+            }
+        }
+    };
+
+    let url = {
+        let state = inner.borrow();
+        match resolve_url(&raw_url, &state.query_params) {
+            Ok(url) => url,
+            Err(_) => {
+                drop(state);
+                schedule_reconnect(inner);
+                return;
+            }
+        }
+    };
+
+    inner.borrow_mut().url = url.clone();
+    #[cfg(feature = "debug")]
+    log(&inner.borrow(), &format!("connecting to {}", url));
+
+    let protocols = inner.borrow().protocols.clone();
+    let socket = if protocols.is_empty() {
+        web_sys::WebSocket::new(&url)
+    } else {
+        let protocols_array = js_sys::Array::new();
+        for protocol in &protocols {
+            protocols_array.push(&JsValue::from_str(protocol));
+        }
+        web_sys::WebSocket::new_with_str_sequence(&url, &protocols_array)
+    };
+    let socket = match socket {
+        Ok(socket) => socket,
+        Err(_) => {
+            schedule_reconnect(inner);
+            return;
+        }
+    };
+
+    socket.set_binary_type(inner.borrow().binary_type);
+
+    let connection_id = {
+        let mut state = inner.borrow_mut();
+        state.ready_state = ReadyState::Connecting;
+        state.connection_id += 1;
+        state.connection_id
+    };
+
+    let active_timeout = {
+        let state = inner.borrow();
+        if connection_id == 1 {
+            state.connect_timeout
+        } else {
+            state.reconnect_attempt_timeout
+        }
+    };
+
+    if let Some(timeout) = active_timeout {
+        let weak_inner = Rc::downgrade(inner);
+        let connect_timeout_handle = Timeout::new(millis_saturating(timeout), move || {
+            let inner = match weak_inner.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+            let stale_or_connected = {
+                let state = inner.borrow();
+                state.connection_id != connection_id || state.ready_state != ReadyState::Connecting
+            };
+            if stale_or_connected {
+                return;
+            }
+            detach_socket(&mut inner.borrow_mut());
+            schedule_reconnect(&inner);
+        });
+        inner.borrow_mut().connect_timeout_handle = Some(connect_timeout_handle);
+    }
+
+    {
+        let weak_inner = Rc::downgrade(inner);
+        let onopen = Closure::wrap(Box::new(move || {
+            let inner = match weak_inner.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+
+            let mismatch = {
+                let state = inner.borrow();
+                if state.require_negotiated_protocol && !state.protocols.is_empty() {
+                    let negotiated = state.socket.as_ref().map_or_else(String::new, |socket| socket.protocol());
+                    if state.protocols.contains(&negotiated) {
+                        None
+                    } else {
+                        Some((state.protocols.clone(), negotiated))
+                    }
+                } else {
+                    None
+                }
+            };
+
+            if let Some((requested, negotiated)) = mismatch {
+                emit(
+                    &inner.borrow(),
+                    WsEvent::ProtocolMismatch {
+                        requested,
+                        negotiated,
+                    },
+                );
+                let _ = close_with_code(&inner, CloseCode::PolicyViolation, "subprotocol not negotiated");
+                return;
+            }
+
+            let on_reopen = {
+                let mut state = inner.borrow_mut();
+                state.ready_state = ReadyState::Open;
+                state.reconnect_state.reset();
+                state.endpoint_index = 0;
+                state.next_retry_in = None;
+                state.retry_timeout = None;
+                state.connect_timeout_handle = None;
+                emit(&state, WsEvent::Open);
+                for interceptor in &state.interceptors {
+                    interceptor.borrow_mut().on_open();
+                }
+                #[cfg(feature = "debug")]
+                log(&state, "connection opened");
+                if connection_id > 1 {
+                    state.on_reopen.clone()
+                } else {
+                    None
+                }
+            };
+            if let Some(on_reopen) = on_reopen {
+                // `WebSocket`'s `Drop` closes the connection, so this borrowed handle is
+                // never dropped as a `WebSocket`; only its `Rc` is released afterward.
+                let handle = std::mem::ManuallyDrop::new(WebSocket { inner: Rc::clone(&inner) });
+                (on_reopen.borrow_mut())(&handle);
+                drop(unsafe { std::ptr::read(&handle.inner) });
+            }
+            replay_sent_messages(&inner);
+            flush_pending_sends(&inner);
+            start_heartbeat(&inner);
+        }) as Box<FnMut()>);
+        socket.set_onopen(Some(onopen.as_ref().unchecked_ref()));
+        inner.borrow_mut().onopen = Some(onopen);
+    }
+
+    {
+        let weak_inner = Rc::downgrade(inner);
+        let onmessage = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let inner = match weak_inner.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+            let mut message = if let Some((text, lossy)) = decode_text_frame(&event.data()) {
+                if lossy {
+                    let (policy, on_text_decode_error) = {
+                        let state = inner.borrow();
+                        (state.text_decode_policy, state.on_text_decode_error.clone())
+                    };
+                    if let Some(on_text_decode_error) = on_text_decode_error {
+                        (on_text_decode_error.borrow_mut())();
+                    }
+                    if policy == TextDecodePolicy::Drop {
+                        return;
+                    }
+                }
+                WsMessage::Text(text)
+            } else if inner.borrow().binary_type == web_sys::BinaryType::Blob {
+                WsMessage::Blob(event.data().unchecked_into())
+            } else {
+                let buffer = js_sys::Uint8Array::new(&event.data());
+                WsMessage::Bytes(buffer.to_vec().into())
+            };
+
+            #[cfg(feature = "deflate")]
+            {
+                if inner.borrow().auto_gunzip {
+                    if let WsMessage::Bytes(bytes) = &message {
+                        if bytes.starts_with(&[0x1f, 0x8b]) {
+                            match gunzip(bytes) {
+                                Ok(decompressed) => message = WsMessage::Bytes(decompressed.into()),
+                                Err(error) => report_diagnostic(&inner, Diagnostic::GunzipFailed(error.to_string())),
+                            }
+                        }
+                    }
+                }
+            }
+
+            let max_message_size = inner.borrow().max_message_size;
+            if let Some(max) = max_message_size {
+                let size = message.byte_len();
+                if size > max {
+                    let (policy, on_oversize) = {
+                        let state = inner.borrow();
+                        (state.incoming_oversize_policy, state.on_oversize.clone())
+                    };
+                    if let Some(on_oversize) = on_oversize {
+                        (on_oversize.borrow_mut())(size);
+                    }
+                    match policy {
+                        OversizePolicy::Drop => return,
+                        OversizePolicy::Truncate => message.truncate_to(max),
+                    }
+                }
+            }
+
+            let interceptors = inner.borrow().interceptors.clone();
+            for interceptor in &interceptors {
+                interceptor.borrow_mut().on_receive(&message);
+            }
+
+            // Runs before `incoming_middleware`/`validate_incoming` so a heartbeat
+            // reply still resets the miss counter even if a transform would otherwise
+            // consume it or a validator would otherwise reject it -- neither one is
+            // expected to know about this crate's own heartbeat protocol.
+            let expect_pong = inner.borrow().heartbeat_pong.clone();
+            if let Some((is_pong, _miss_threshold)) = expect_pong {
+                if is_pong(&message) {
+                    record_pong(&inner, &message);
+                }
+            }
+
+            let message = match apply_incoming_middleware(&inner, message) {
+                Some(message) => message,
+                None => return,
+            };
+
+            let validate_incoming = inner.borrow().validate_incoming.clone();
+            if let Some(validate_incoming) = validate_incoming {
+                if let Err(error) = (validate_incoming.borrow_mut())(&message) {
+                    let (close_on_invalid, on_invalid_message) = {
+                        let state = inner.borrow();
+                        (state.close_on_invalid_message, state.on_invalid_message.clone())
+                    };
+                    if let Some(on_invalid_message) = on_invalid_message {
+                        (on_invalid_message.borrow_mut())(&message, &error);
+                    }
+                    if close_on_invalid {
+                        let _ = close_with_code(&inner, CloseCode::InvalidPayloadData, "invalid message");
+                    }
+                    return;
+                }
+            }
+
+            let inner = inner.borrow();
+            #[cfg(feature = "debug")]
+            log(&inner, &format!("received {} bytes", message.byte_len()));
+            emit(&inner, WsEvent::Message(message));
+        }) as Box<FnMut(MessageEvent)>);
+        socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        inner.borrow_mut().onmessage = Some(onmessage);
+    }
+
+    {
+        let weak_inner = Rc::downgrade(inner);
+        let onclose = Closure::wrap(Box::new(move |event: CloseEvent| {
+            let inner = match weak_inner.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+
+            let manually_closed = inner.borrow().manually_closed;
+            let forced_reconnect = std::mem::replace(&mut inner.borrow_mut().heartbeat_force_reconnect, false);
+            let should_reconnect = forced_reconnect || CloseCode::from(event.code()).should_reconnect();
+
+            {
+                let state = inner.borrow();
+                emit(
+                    &state,
+                    WsEvent::Close {
+                        code: event.code(),
+                        reason: event.reason(),
+                        was_clean: event.was_clean(),
+                    },
+                );
+                for interceptor in &state.interceptors {
+                    interceptor.borrow_mut().on_close(event.code(), &event.reason(), event.was_clean());
+                }
+                #[cfg(feature = "debug")]
+                log(
+                    &state,
+                    &format!(
+                        "connection closed (code={}, reason={:?}, clean={})",
+                        event.code(),
+                        event.reason(),
+                        event.was_clean()
+                    ),
+                );
+            }
+
+            if manually_closed || !should_reconnect {
+                finalize_close(&inner);
+            } else {
+                detach_socket(&mut inner.borrow_mut());
+                schedule_reconnect(&inner);
+            }
+        }) as Box<FnMut(CloseEvent)>);
+        socket.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+        inner.borrow_mut().onclose = Some(onclose);
+    }
+
+    {
+        let weak_inner = Rc::downgrade(inner);
+        let onerror = Closure::wrap(Box::new(move |_event: ErrorEvent| {
+            if let Some(inner) = weak_inner.upgrade() {
+                emit(&inner.borrow(), WsEvent::Error);
+                #[cfg(feature = "debug")]
+                log(&inner.borrow(), "connection error");
+            }
+        }) as Box<FnMut(ErrorEvent)>);
+        socket.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+        inner.borrow_mut().onerror = Some(onerror);
+    }
+
+    inner.borrow_mut().socket = Some(socket);
+}
+
+fn schedule_reconnect(inner: &Rc<RefCell<Inner>>) {
+    {
+        let mut state = inner.borrow_mut();
+        state.ready_state = ReadyState::Connecting;
+        let now = state.clock.now_millis();
+        state.reconnect_state.mark_started(now);
+        if state.pause_when_offline && !is_online() {
+            state.reconnect_paused = true;
+            state.next_retry_in = None;
+            return;
+        }
+        if state.pause_when_hidden && is_hidden() {
+            state.reconnect_paused_hidden = true;
+            state.next_retry_in = None;
+            return;
+        }
+    }
+
+    let (delay, attempt, clamped) = {
+        let mut state = inner.borrow_mut();
+        let config = state.reconnect_config.clone();
+        let now = state.clock.now_millis();
+        let decision = state.reconnect_state.next(&config, now);
+        let (attempt, delay, clamped) = match decision {
+            BackoffDecision::Wait { attempt, delay, clamped } => (attempt, delay, clamped),
+            BackoffDecision::GiveUp => {
+                state.manually_closed = true;
+                state.ready_state = ReadyState::Closed;
+                state.next_retry_in = None;
+                let on_exhausted = state.on_reconnect_exhausted.clone();
+                emit(&state, WsEvent::ReconnectFailed);
+                drop(state);
+                if let Some(on_exhausted) = on_exhausted {
+                    (on_exhausted.borrow_mut())();
+                }
+                return;
+            }
+        };
+        state.endpoint_index = state.endpoint_index.wrapping_add(1);
+        state.next_retry_in = Some(delay);
+        #[cfg(feature = "debug")]
+        log(&state, &format!("scheduling reconnect attempt {} in {:?}", attempt, delay));
+        (delay, attempt, clamped)
+    };
+
+    if clamped {
+        report_diagnostic(inner, Diagnostic::BackoffClamped { attempt, delay });
+    }
+
+    emit(&inner.borrow(), WsEvent::Reconnecting { attempt, delay });
+    for interceptor in &inner.borrow().interceptors {
+        interceptor.borrow_mut().on_reconnect_attempt(attempt);
+    }
+
+    let weak_inner = Rc::downgrade(inner);
+    let timeout = Timeout::new(millis_saturating(delay), move || {
+        if let Some(inner) = weak_inner.upgrade() {
+            attempt_reconnect(&inner);
+        }
+    });
+
+    inner.borrow_mut().retry_timeout = Some(timeout);
+}
+
+// Runs the configured `before_reconnect` hook, if any, before actually reconnecting.
+// Without the `futures` feature (or without a hook configured) this just connects
+// immediately, same as the initial connection.
+fn attempt_reconnect(inner: &Rc<RefCell<Inner>>) {
+    #[cfg(feature = "futures")]
+    {
+        let hook = inner.borrow().before_reconnect.clone();
+        if let Some(hook) = hook {
+            let decision = (hook.borrow_mut())();
+            let weak_inner = Rc::downgrade(inner);
+            let promise = decision.then(move |decision| {
+                if let Some(inner) = weak_inner.upgrade() {
+                    match decision {
+                        Ok(ReconnectDecision::Proceed) => connect(&inner),
+                        Ok(ReconnectDecision::ProceedWithUrl(url)) => {
+                            inner.borrow_mut().url_source = UrlSource::Static(url.into_owned());
+                            connect(&inner);
+                        }
+                        Ok(ReconnectDecision::Cancel) | Err(()) => {
+                            let mut state = inner.borrow_mut();
+                            state.manually_closed = true;
+                            state.ready_state = ReadyState::Closed;
+                        }
+                    }
+                }
+                future::ok::<JsValue, JsValue>(JsValue::UNDEFINED)
+            });
+            future_to_promise(promise);
+            return;
+        }
+    }
+
+    connect(inner);
+}
+
+fn emit(inner: &Inner, event: WsEvent) {
+    (inner.on_event.borrow_mut())(event);
+}
+
+#[cfg(feature = "debug")]
+fn log(inner: &Inner, message: &str) {
+    if inner.debug {
+        web_sys::console::log_1(&JsValue::from_str(&format!("[gloo-websocket] {}", message)));
+    }
+}
+
+// Decodes a `MessageEvent`'s `data` as text, returning the decoded `String` and
+// whether the browser's JS-string-to-UTF-8 conversion had to substitute a Unicode
+// replacement character somewhere -- `value.as_string()` itself decodes lossily with
+// no way to tell after the fact, and a replacement character is the same single UTF-16
+// unit as the unpaired surrogate it stands in for, so this compares the JS string
+// against the decoded `String` unit by unit to notice when that happened.
+fn decode_text_frame(value: &JsValue) -> Option<(String, bool)> {
+    let text = value.as_string()?;
+    let js_text = js_sys::JsString::from(value.clone());
+    let lossy = text
+        .encode_utf16()
+        .enumerate()
+        .any(|(index, unit)| js_text.char_code_at(index as u32) != f64::from(unit));
+    Some((text, lossy))
+}
+
+// Decompresses a gzip-magic-prefixed binary frame for `WebSocketBuilder::auto_gunzip`.
+#[cfg(feature = "deflate")]
+fn gunzip(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+// Runs `WebSocketBuilder::add_interceptor`'s `on_send` hooks over `message`, skipping
+// the borrow entirely when there's nothing attached.
+fn notify_send(inner: &Rc<RefCell<Inner>>, message: &WsMessage) {
+    let interceptors = inner.borrow().interceptors.clone();
+    for interceptor in &interceptors {
+        interceptor.borrow_mut().on_send(message);
+    }
+}
+
+fn raw_send(inner: &Rc<RefCell<Inner>>, message: &WsMessage) -> Result<(), SendError> {
+    notify_send(inner, message);
+    let inner = inner.borrow();
+    #[cfg(feature = "debug")]
+    log(&inner, &format!("sending {} bytes", message.byte_len()));
+    match &inner.socket {
+        Some(socket) => {
+            let result = match message {
+                WsMessage::Text(text) => Transport::send_text(socket, text),
+                WsMessage::Bytes(bytes) => Transport::send_bytes(socket, bytes),
+                WsMessage::Blob(blob) => Transport::send_blob(socket, blob),
+            };
+            result.map_err(SendError::Failed)
+        }
+        None => Err(SendError::NotConnected),
+    }
+}
+
+// Like `raw_send`, but for a not-yet-owned `OutgoingMessage`, so the fast path of
+// sending straight to an already-open socket never has to copy the payload into a
+// `WsMessage` first.
+fn raw_send_outgoing(inner: &Rc<RefCell<Inner>>, message: &OutgoingMessage) -> Result<(), SendError> {
+    let interceptors = inner.borrow().interceptors.clone();
+    if !interceptors.is_empty() {
+        let owned = match message {
+            OutgoingMessage::Text(text) => WsMessage::Text(text.clone().into_owned()),
+            OutgoingMessage::Bytes(bytes) => WsMessage::Bytes(bytes.clone().into_owned().into()),
+        };
+        for interceptor in &interceptors {
+            interceptor.borrow_mut().on_send(&owned);
+        }
+    }
+
+    let inner = inner.borrow();
+    #[cfg(feature = "debug")]
+    log(&inner, &format!("sending {} bytes", message.byte_len()));
+    match &inner.socket {
+        Some(socket) => {
+            let result = match message {
+                OutgoingMessage::Text(text) => Transport::send_text(socket, text),
+                OutgoingMessage::Bytes(bytes) => Transport::send_bytes(socket, bytes),
+            };
+            result.map_err(SendError::Failed)
+        }
+        None => Err(SendError::NotConnected),
+    }
+}
+
+// Runs `WebSocketBuilder::outgoing_middleware`'s transforms over `message`, in
+// registration order; `None` from this means some stage dropped the message.
+fn apply_outgoing_middleware<'a>(inner: &Rc<RefCell<Inner>>, message: OutgoingMessage<'a>) -> Option<OutgoingMessage<'a>> {
+    let middleware = inner.borrow().outgoing_middleware.clone();
+    if middleware.is_empty() {
+        return Some(message);
+    }
+
+    let mut current = message.into_owned();
+    for stage in &middleware {
+        current = (stage.borrow_mut())(current)?;
+    }
+    Some(match current {
+        WsMessage::Text(text) => OutgoingMessage::Text(Cow::Owned(text)),
+        WsMessage::Bytes(bytes) => OutgoingMessage::Bytes(Cow::Owned(bytes.to_vec())),
+        WsMessage::Blob(_) => unreachable!("no outgoing middleware produces a WsMessage::Blob"),
+    })
+}
+
+// Runs `WebSocketBuilder::incoming_middleware`'s transforms over `message`, in
+// registration order; `None` from this means some stage consumed the message.
+fn apply_incoming_middleware(inner: &Rc<RefCell<Inner>>, message: WsMessage) -> Option<WsMessage> {
+    let middleware = inner.borrow().incoming_middleware.clone();
+    let mut current = message;
+    for stage in &middleware {
+        current = (stage.borrow_mut())(current)?;
+    }
+    Some(current)
+}
+
+fn enqueue_or_send<F>(inner: &Rc<RefCell<Inner>>, message: OutgoingMessage, callback: F)
+where
+    F: FnOnce(Result<(), SendError>) + 'static,
+{
+    let message = match apply_outgoing_middleware(inner, message) {
+        Some(message) => message,
+        None => {
+            callback(Ok(()));
+            return;
+        }
+    };
+
+    let max_message_size = inner.borrow().max_message_size;
+    if let Some(max) = max_message_size {
+        let size = message.byte_len();
+        if size > max {
+            callback(Err(SendError::TooLarge { size, max }));
+            return;
+        }
+    }
+
+    let triggers_connect = {
+        let state = inner.borrow();
+        state.ready_state == ReadyState::Idle && state.connect_on_send
+    };
+
+    if triggers_connect {
+        connect(inner);
+    }
+
+    let should_queue = triggers_connect || {
+        let state = inner.borrow();
+        state.queue_enabled && state.ready_state != ReadyState::Open
+    };
+
+    if should_queue {
+        enqueue(inner, message.into_owned(), Box::new(callback));
+        return;
+    }
+
+    let result = raw_send_outgoing(inner, &message);
+    let replaying = inner.borrow().replay_buffer_capacity.map_or(false, |capacity| capacity > 0);
+    if result.is_ok() && replaying {
+        record_for_replay(inner, message.into_owned());
+    }
+    start_drain_poll(inner);
+    callback(result);
+}
+
+fn enqueue(
+    inner: &Rc<RefCell<Inner>>,
+    message: WsMessage,
+    callback: Box<dyn FnOnce(Result<(), SendError>)>,
+) {
+    let byte_len = message.byte_len();
+
+    let over_capacity = {
+        let state = inner.borrow();
+        let over_count = state.pending_sends.len() >= state.queue_capacity;
+        let over_bytes = state
+            .queue_max_bytes
+            .map_or(false, |max| state.queue_bytes + byte_len > max);
+        over_count || over_bytes
+    };
+
+    if over_capacity {
+        let policy = inner.borrow().queue_overflow_policy;
+        notify_queue_overflow(inner);
+
+        match policy {
+            QueueOverflowPolicy::Reject => {
+                callback(Err(SendError::QueueFull));
+                return;
+            }
+            QueueOverflowPolicy::DropOldest => {
+                if let Some(dropped) = inner.borrow_mut().pending_sends.pop_front() {
+                    inner.borrow_mut().queue_bytes -= dropped.message.byte_len();
+                    (dropped.callback)(Err(SendError::DroppedFromQueue));
+                }
+            }
+        }
+    }
+
+    let mut state = inner.borrow_mut();
+    state.queue_bytes += byte_len;
+    state.pending_sends.push_back(QueuedSend { message, callback });
+}
+
+fn notify_queue_overflow(inner: &Rc<RefCell<Inner>>) {
+    let on_overflow = inner.borrow().on_queue_overflow.clone();
+    if let Some(on_overflow) = on_overflow {
+        (on_overflow.borrow_mut())();
+    }
+}
+
+fn report_diagnostic(inner: &Rc<RefCell<Inner>>, diagnostic: Diagnostic) {
+    let on_diagnostic = inner.borrow().on_diagnostic.clone();
+    if let Some(on_diagnostic) = on_diagnostic {
+        (on_diagnostic.borrow_mut())(diagnostic);
+    }
+}
+
+fn flush_pending_sends(inner: &Rc<RefCell<Inner>>) {
+    let pending: Vec<QueuedSend> = {
+        let mut state = inner.borrow_mut();
+        state.queue_bytes = 0;
+        state.pending_sends.drain(..).collect()
+    };
+    if pending.is_empty() {
+        return;
+    }
+    for queued in pending {
+        let result = raw_send(inner, &queued.message);
+        if result.is_ok() {
+            record_for_replay(inner, queued.message.clone());
+        }
+        (queued.callback)(result);
+    }
+    start_drain_poll(inner);
+}
+
+// Remembers `message` for `WebSocketBuilder::replay_buffer`, evicting the oldest
+// buffered message if it's now over capacity. A no-op if `replay_buffer` wasn't
+// configured.
+fn record_for_replay(inner: &Rc<RefCell<Inner>>, message: WsMessage) {
+    let mut state = inner.borrow_mut();
+    let capacity = match state.replay_buffer_capacity {
+        Some(capacity) if capacity > 0 => capacity,
+        _ => return,
+    };
+    state.replay_buffer.push_back(message);
+    while state.replay_buffer.len() > capacity {
+        state.replay_buffer.pop_front();
+    }
+}
+
+// Resends every buffered message on reconnect, dropping any `replay_filter` rejects
+// from the buffer for good and keeping the rest around for the next reconnect too.
+fn replay_sent_messages(inner: &Rc<RefCell<Inner>>) {
+    let (buffered, filter) = {
+        let mut state = inner.borrow_mut();
+        (std::mem::take(&mut state.replay_buffer), state.replay_filter.clone())
+    };
+    if buffered.is_empty() {
+        return;
+    }
+    let mut kept = VecDeque::with_capacity(buffered.len());
+    for message in buffered {
+        let replay = filter.as_ref().map_or(true, |filter| filter(&message));
+        if !replay {
+            continue;
+        }
+        let _ = raw_send(inner, &message);
+        kept.push_back(message);
+    }
+    inner.borrow_mut().replay_buffer = kept;
+}
+
+fn start_drain_poll(inner: &Rc<RefCell<Inner>>) {
+    let already_polling = inner.borrow().drain_poll.is_some();
+    if already_polling {
+        return;
+    }
+
+    let weak_inner = Rc::downgrade(inner);
+    let interval = Interval::new(DRAIN_POLL_MILLIS, move || {
+        let poll_inner = match weak_inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        let buffered = poll_inner
+            .borrow()
+            .socket
+            .as_ref()
+            .map(Transport::buffered_amount)
+            .unwrap_or(0);
+
+        if buffered == 0 {
+            let on_drain = {
+                let mut inner = poll_inner.borrow_mut();
+                inner.drain_poll = None;
+                inner.on_drain.clone()
+            };
+
+            if let Some(on_drain) = on_drain {
+                (on_drain.borrow_mut())();
+            }
+        }
+    });
+
+    inner.borrow_mut().drain_poll = Some(interval);
+}
+
+fn start_heartbeat(inner: &Rc<RefCell<Inner>>) {
+    let (interval, payload) = match inner.borrow().heartbeat.clone() {
+        Some(heartbeat) => heartbeat,
+        None => return,
+    };
+
+    {
+        let mut state = inner.borrow_mut();
+        state.heartbeat_pong_pending = false;
+        state.heartbeat_misses = 0;
+    }
+
+    let weak_inner = Rc::downgrade(inner);
+    let timer = Interval::new(millis_saturating(interval), move || {
+        let inner = match weak_inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        let miss_threshold = inner.borrow().heartbeat_pong.as_ref().map(|(_, threshold)| *threshold);
+        if let Some(miss_threshold) = miss_threshold {
+            let mut state = inner.borrow_mut();
+            if state.heartbeat_pong_pending {
+                state.heartbeat_misses += 1;
+            } else {
+                state.heartbeat_misses = 0;
+            }
+            if state.heartbeat_misses >= miss_threshold {
+                let misses = state.heartbeat_misses;
+                drop(state);
+                report_diagnostic(&inner, Diagnostic::HeartbeatMissed { misses });
+                force_reconnect(&inner);
+                return;
+            }
+            state.heartbeat_pong_pending = true;
+        }
+
+        match raw_send(&inner, &payload) {
+            Ok(()) => {
+                if miss_threshold.is_some() {
+                    let now = inner.borrow().clock.now_millis();
+                    inner.borrow_mut().heartbeat_sent_at = Some(now);
+                }
+            }
+            Err(err) => report_diagnostic(&inner, Diagnostic::HeartbeatSendFailed(err)),
+        }
+    });
+
+    inner.borrow_mut().heartbeat_timer = Some(timer);
+}
+
+// Clears the pending-pong flag and, if a heartbeat is still waiting on a reply, turns
+// its round-trip time into a new latency sample (and, if `sync_clock` is configured, a
+// new clock offset estimate).
+fn record_pong(inner: &Rc<RefCell<Inner>>, message: &WsMessage) {
+    let mut state = inner.borrow_mut();
+    state.heartbeat_pong_pending = false;
+    let sent_at = match state.heartbeat_sent_at.take() {
+        Some(sent_at) => sent_at,
+        None => return,
+    };
+    let now = state.clock.now_millis();
+    let sample = Duration::from_millis((now - sent_at).max(0.0) as u64);
+    let updated = ewma(state.latency, sample);
+    state.latency = Some(updated);
+    let on_latency = state.on_latency.clone();
+
+    let server_time = state.clock_sync.clone().and_then(|extract| extract(message));
+    if let Some(server_time) = server_time {
+        let one_way = (now - sent_at) / 2.0;
+        state.clock_offset = Some(server_time - (sent_at + one_way));
+    }
+
+    drop(state);
+    if let Some(on_latency) = on_latency {
+        (on_latency.borrow_mut())(updated);
+    }
+}
+
+// Exponentially-weighted rolling average, giving the newest sample a 20% weight --
+// smooths out one-off spikes without reacting too slowly to a genuine shift in latency.
+fn ewma(previous: Option<Duration>, sample: Duration) -> Duration {
+    match previous {
+        Some(previous) => {
+            let blended = previous.as_millis() as f64 * 0.8 + sample.as_millis() as f64 * 0.2;
+            Duration::from_millis(blended as u64)
+        }
+        None => sample,
+    }
+}
+
+// Closes the underlying socket to force a reconnect, bypassing the close code's usual
+// `should_reconnect` check -- used when this end has decided the connection is dead
+// (see `WebSocketBuilder::expect_pong`), not the server, so there's no close code that
+// would reflect that on its own.
+fn force_reconnect(inner: &Rc<RefCell<Inner>>) {
+    let close_failure = {
+        let mut state = inner.borrow_mut();
+        state.heartbeat_force_reconnect = true;
+        match &state.socket {
+            Some(socket) => Transport::close(socket, CloseCode::Normal.into(), "heartbeat missed").err(),
+            None => None,
+        }
+    };
+    if let Some(err) = close_failure {
+        report_diagnostic(inner, Diagnostic::CloseFailed(err));
+    }
+}
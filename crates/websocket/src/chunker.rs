@@ -0,0 +1,241 @@
+//! A generic chunking layer on top of [`cb::WebSocket`](../cb/struct.WebSocket.html),
+//! for payloads too big to hand the browser as a single frame -- a multi-megabyte
+//! frame can stall the whole connection for long enough to look like a dropped
+//! connection, especially on a slower device.
+//!
+//! [`Chunker::send`](struct.Chunker.html#method.send) splits `payload` into pieces of
+//! at most `chunk_size` bytes (never splitting a UTF-8 character across two chunks)
+//! and sends each as its own frame; the peer's `Chunker` reassembles them in the
+//! order they arrive and yields the whole payload, via [`Stream`], once every chunk
+//! has shown up. [`on_send_progress`](struct.Chunker.html#method.on_send_progress)
+//! and [`on_receive_progress`](struct.Chunker.html#method.on_receive_progress) fire
+//! after each chunk sent or received, for a progress bar on either end.
+//!
+//! A frame is encoded as `C<msg_id>\u{1f}<index>\u{1f}<count>\u{1f}<total_bytes>\u{1f}<chunk>`.
+//! `msg_id` only needs to be unique per sender, so each `Chunker` numbers its own
+//! outgoing messages independently; chunks for a given `msg_id` are assumed to arrive
+//! in order, which holds as long as nothing reorders the frames of a single logical
+//! message in transit -- true of this crate's own `send`, which writes every chunk of
+//! one message before returning.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+
+use futures::sync::mpsc;
+use futures::{Poll, Stream};
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+const SEPARATOR: char = '\u{1f}';
+
+/// How much of a [`send`](struct.Chunker.html#method.send) or an in-progress
+/// reassembly has completed so far.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// Bytes sent or received so far.
+    pub bytes: usize,
+    /// Total bytes in the message.
+    pub total: usize,
+}
+
+struct IncomingMessage {
+    chunks: BTreeMap<u32, String>,
+    count: u32,
+    total: usize,
+}
+
+struct Inner {
+    // `None` only in the brief window in `build_chunker` between constructing this
+    // and `WebSocketBuilder::build` returning; `on_event` can't fire until `build`
+    // returns, so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    chunk_size: usize,
+    next_msg_id: u64,
+    incoming: HashMap<u64, IncomingMessage>,
+    sender: mpsc::UnboundedSender<String>,
+    on_send_progress: Option<Rc<RefCell<dyn FnMut(Progress)>>>,
+    on_receive_progress: Option<Rc<RefCell<dyn FnMut(Progress)>>>,
+}
+
+impl Inner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("Inner.ws is set before any event can fire")
+    }
+}
+
+/// A [`cb::WebSocket`](../cb/struct.WebSocket.html) that transparently splits large
+/// outgoing payloads into chunks and reassembles incoming ones. See the
+/// [module docs](index.html) for the wire format.
+pub struct Chunker {
+    inner: Rc<RefCell<Inner>>,
+    receiver: mpsc::UnboundedReceiver<String>,
+}
+
+impl fmt::Debug for Chunker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Chunker")
+            .field("ws", &inner.ws)
+            .field("chunk_size", &inner.chunk_size)
+            .field("reassembling", &inner.incoming.len())
+            .finish()
+    }
+}
+
+impl Chunker {
+    /// Connects to `url`. Outgoing payloads over `chunk_size` bytes are split into
+    /// chunks of at most that size before being sent.
+    pub fn connect(url: &str, chunk_size: usize) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url), chunk_size)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../cb/struct.WebSocketBuilder.html).
+    /// See [`connect`](#method.connect) for what `chunk_size` does.
+    pub fn from_builder(builder: WebSocketBuilder, chunk_size: usize) -> Result<Self, BuildError> {
+        let (sender, receiver) = mpsc::unbounded();
+        let inner = Rc::new(RefCell::new(Inner {
+            ws: None,
+            chunk_size: chunk_size.max(1),
+            next_msg_id: 0,
+            incoming: HashMap::new(),
+            sender,
+            on_send_progress: None,
+            on_receive_progress: None,
+        }));
+        build_chunker(builder, inner, receiver)
+    }
+
+    /// Splits `payload` into chunks of at most `chunk_size` bytes and sends each in
+    /// turn.
+    pub fn send(&self, payload: &str) {
+        let chunks = split_chunks(payload, self.inner.borrow().chunk_size);
+        let count = chunks.len() as u32;
+        let total = payload.len();
+
+        let msg_id = {
+            let mut inner = self.inner.borrow_mut();
+            inner.next_msg_id += 1;
+            inner.next_msg_id
+        };
+
+        let mut sent = 0;
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let on_progress = {
+                let inner = self.inner.borrow();
+                inner.ws().send_text(&encode_chunk(msg_id, index as u32, count, total, chunk));
+                inner.on_send_progress.clone()
+            };
+            sent += chunk.len();
+            if let Some(on_progress) = on_progress {
+                (on_progress.borrow_mut())(Progress { bytes: sent, total });
+            }
+        }
+    }
+
+    /// Registers a callback which fires with each chunk sent by
+    /// [`send`](#method.send), to drive a send-progress indicator.
+    pub fn on_send_progress<F>(&self, callback: F)
+    where
+        F: FnMut(Progress) + 'static,
+    {
+        self.inner.borrow_mut().on_send_progress = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Registers a callback which fires with each chunk received while reassembling
+    /// an incoming message, to drive a receive-progress indicator.
+    pub fn on_receive_progress<F>(&self, callback: F)
+    where
+        F: FnMut(Progress) + 'static,
+    {
+        self.inner.borrow_mut().on_receive_progress = Some(Rc::new(RefCell::new(callback)));
+    }
+}
+
+impl Stream for Chunker {
+    type Item = String;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<String>, ()> {
+        self.receiver.poll()
+    }
+}
+
+fn build_chunker(builder: WebSocketBuilder, placeholder: Rc<RefCell<Inner>>, receiver: mpsc::UnboundedReceiver<String>) -> Result<Chunker, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(Chunker { inner: placeholder, receiver })
+}
+
+fn handle_event(inner: &Rc<RefCell<Inner>>, event: WsEvent) {
+    if let WsEvent::Message(WsMessage::Text(text)) = event {
+        if let Some((msg_id, index, count, total, chunk)) = decode_chunk(&text) {
+            on_data(inner, msg_id, index, count, total, chunk);
+        }
+    }
+}
+
+fn on_data(inner: &Rc<RefCell<Inner>>, msg_id: u64, index: u32, count: u32, total: usize, chunk: String) {
+    let (complete, progress, on_receive_progress) = {
+        let mut state = inner.borrow_mut();
+        let message = state.incoming.entry(msg_id).or_insert_with(|| IncomingMessage { chunks: BTreeMap::new(), count, total });
+        message.chunks.insert(index, chunk);
+        let received: usize = message.chunks.values().map(|chunk| chunk.len()).sum();
+        let progress = Progress { bytes: received, total: message.total };
+        let complete = message.chunks.len() as u32 >= message.count;
+        let payload = if complete {
+            state.incoming.remove(&msg_id).map(|message| message.chunks.into_iter().map(|(_, chunk)| chunk).collect::<String>())
+        } else {
+            None
+        };
+        (payload, progress, state.on_receive_progress.clone())
+    };
+
+    if let Some(on_receive_progress) = on_receive_progress {
+        (on_receive_progress.borrow_mut())(progress);
+    }
+    if let Some(payload) = complete {
+        let sender = inner.borrow().sender.clone();
+        let _ = sender.unbounded_send(payload);
+    }
+}
+
+// Splits `payload` into pieces of at most `chunk_size` bytes, never cutting a UTF-8
+// character in half. Always yields at least one chunk, even for an empty payload, so
+// an empty `send` still produces a (zero-length) frame rather than none at all.
+fn split_chunks(payload: &str, chunk_size: usize) -> Vec<&str> {
+    if payload.is_empty() {
+        return vec![""];
+    }
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < payload.len() {
+        let mut end = (start + chunk_size).min(payload.len());
+        while end < payload.len() && !payload.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(&payload[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+fn decode_chunk(text: &str) -> Option<(u64, u32, u32, usize, String)> {
+    let mut chars = text.chars();
+    if chars.next()? != 'C' {
+        return None;
+    }
+    let mut parts = chars.as_str().splitn(5, SEPARATOR);
+    let msg_id = parts.next()?.parse().ok()?;
+    let index = parts.next()?.parse().ok()?;
+    let count = parts.next()?.parse().ok()?;
+    let total = parts.next()?.parse().ok()?;
+    let chunk = parts.next()?.to_string();
+    Some((msg_id, index, count, total, chunk))
+}
+
+fn encode_chunk(msg_id: u64, index: u32, count: u32, total: usize, chunk: &str) -> String {
+    format!("C{}{}{}{}{}{}{}{}{}", msg_id, SEPARATOR, index, SEPARATOR, count, SEPARATOR, total, SEPARATOR, chunk)
+}
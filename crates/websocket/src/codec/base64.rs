@@ -0,0 +1,42 @@
+//! A [`Codec`](../trait.Codec.html) that carries binary payloads over text frames by
+//! base64-encoding them, for proxies and servers on the path that only pass through
+//! text frames.
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+/// Base64-encodes outgoing bytes into a text frame on the way out, and decodes an
+/// incoming base64 text frame back into bytes on the way in. An incoming
+/// [`WsMessage::Bytes`](../../cb/enum.WsMessage.html#variant.Bytes) frame is passed
+/// through unchanged, since the peer might not be running this same codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Base64Codec;
+
+impl Base64Codec {
+    /// Creates a new codec.
+    pub fn new() -> Self {
+        Base64Codec
+    }
+}
+
+impl Codec for Base64Codec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+    type Error = base64::DecodeError;
+
+    fn encode(&self, value: &Vec<u8>) -> WsMessage {
+        WsMessage::Text(base64::encode(value))
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<Vec<u8>, base64::DecodeError> {
+        match message {
+            WsMessage::Text(text) => base64::decode(&text),
+            WsMessage::Bytes(bytes) => Ok(bytes.to_vec()),
+            // A `Blob` can't be read synchronously, and there's no bytes here to
+            // reasonably decode a placeholder from the way the other codecs in this
+            // module do; report it as the closest existing `DecodeError` instead of
+            // inventing a variant of our own.
+            WsMessage::Blob(_) => Err(base64::DecodeError::InvalidLength),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! A [`Codec`](../trait.Codec.html) built on [`bincode`](https://docs.rs/bincode), for
+//! Rust-to-Rust deployments where both ends share message structs and want the
+//! leanest encode/decode path rather than a self-describing format.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+/// Serializes `T` to a bincode binary frame on the way out and parses it back on the
+/// way in. `T` is used for both directions -- see the [module docs](../index.html) for
+/// why an asymmetric protocol would want two separate type parameters instead.
+pub struct BincodeCodec<T> {
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T> BincodeCodec<T> {
+    /// Creates a new codec. `T` is inferred from wherever the codec ends up used.
+    pub fn new() -> Self {
+        BincodeCodec { _message: PhantomData }
+    }
+}
+
+impl<T> Default for BincodeCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for BincodeCodec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BincodeCodec").finish()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec for BincodeCodec<T> {
+    type In = T;
+    type Out = T;
+    type Error = bincode::Error;
+
+    fn encode(&self, value: &T) -> WsMessage {
+        // Mirrors `JsonCodec::encode`: a type that only ever round-trips through this
+        // codec can't hold anything `bincode` refuses to serialize.
+        WsMessage::Bytes(bincode::serialize(value).expect("T is representable as bincode").into())
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<T, bincode::Error> {
+        match message {
+            WsMessage::Bytes(bytes) => bincode::deserialize(bytes.as_ref()),
+            WsMessage::Text(text) => bincode::deserialize(text.as_bytes()),
+            WsMessage::Blob(_) => Err(serde::de::Error::custom(
+                "cannot decode a WsMessage::Blob synchronously; read it via cb::WebSocket's `futures` support first",
+            )),
+        }
+    }
+}
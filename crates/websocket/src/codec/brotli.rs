@@ -0,0 +1,80 @@
+//! A [`Codec`](../trait.Codec.html) that brotli-compresses outgoing payloads and
+//! decompresses incoming ones, selectable through the same interface as
+//! [`DeflateCodec`](../deflate/struct.DeflateCodec.html) -- reach for this one instead
+//! when a better compression ratio matters more than the extra CPU time brotli costs
+//! over DEFLATE.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+const BUFFER_SIZE: usize = 4096;
+const LG_WINDOW_SIZE: u32 = 22;
+
+/// Compresses outgoing bytes with brotli on the way out, and decompresses an
+/// incoming frame back into bytes on the way in.
+pub struct BrotliCodec {
+    quality: u32,
+}
+
+impl BrotliCodec {
+    /// Creates a codec at brotli's default quality (11, its highest).
+    pub fn new() -> Self {
+        BrotliCodec { quality: 11 }
+    }
+
+    /// Creates a codec at a specific quality (0-11), trading ratio for CPU time.
+    pub fn with_quality(quality: u32) -> Self {
+        BrotliCodec { quality }
+    }
+}
+
+impl Default for BrotliCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for BrotliCodec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BrotliCodec").field("quality", &self.quality).finish()
+    }
+}
+
+impl Codec for BrotliCodec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&self, value: &Vec<u8>) -> WsMessage {
+        let mut compressed = Vec::new();
+        {
+            let params = brotli::enc::BrotliEncoderParams { quality: self.quality as i32, lgwin: LG_WINDOW_SIZE as i32, ..Default::default() };
+            let mut writer = brotli::CompressorWriter::with_params(&mut compressed, BUFFER_SIZE, &params);
+            // Writing to an in-memory `Vec` can't fail.
+            writer.write_all(value).expect("compressing into a Vec<u8> cannot fail");
+        }
+        WsMessage::Bytes(compressed.into())
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<Vec<u8>, io::Error> {
+        let bytes = match message {
+            WsMessage::Bytes(bytes) => bytes.to_vec(),
+            WsMessage::Text(text) => text.into_bytes(),
+            WsMessage::Blob(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "cannot decode a WsMessage::Blob synchronously; read it via cb::WebSocket's `futures` support first",
+                ));
+            }
+        };
+        let mut decompressed = Vec::new();
+        {
+            let mut decompressor = brotli::Decompressor::new(&bytes[..], BUFFER_SIZE);
+            io::copy(&mut decompressor, &mut decompressed)?;
+        }
+        Ok(decompressed)
+    }
+}
@@ -0,0 +1,60 @@
+//! A [`Codec`](../trait.Codec.html) built on [`serde_cbor`](https://docs.rs/serde_cbor),
+//! for interop with backends standardized on CBOR payloads (e.g. COSE/CWT-based auth,
+//! or IoT stacks that already speak it).
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+/// Serializes `T` to a CBOR binary frame on the way out and parses it back on the way
+/// in. `T` is used for both directions -- see the [module docs](../index.html) for why
+/// an asymmetric protocol would want two separate type parameters instead.
+pub struct CborCodec<T> {
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T> CborCodec<T> {
+    /// Creates a new codec. `T` is inferred from wherever the codec ends up used.
+    pub fn new() -> Self {
+        CborCodec { _message: PhantomData }
+    }
+}
+
+impl<T> Default for CborCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for CborCodec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CborCodec").finish()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec for CborCodec<T> {
+    type In = T;
+    type Out = T;
+    type Error = serde_cbor::Error;
+
+    fn encode(&self, value: &T) -> WsMessage {
+        // Mirrors `JsonCodec::encode`: a type that only ever round-trips through this
+        // codec can't hold anything `serde_cbor` refuses to serialize.
+        WsMessage::Bytes(serde_cbor::to_vec(value).expect("T is representable as CBOR").into())
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<T, serde_cbor::Error> {
+        match message {
+            WsMessage::Bytes(bytes) => serde_cbor::from_slice(bytes.as_ref()),
+            WsMessage::Text(text) => serde_cbor::from_slice(text.as_bytes()),
+            WsMessage::Blob(_) => Err(serde::de::Error::custom(
+                "cannot decode a WsMessage::Blob synchronously; read it via cb::WebSocket's `futures` support first",
+            )),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! A [`Codec`](../trait.Codec.html) that DEFLATE-compresses outgoing payloads and
+//! decompresses incoming ones, for when both ends agree on it (e.g. by negotiating a
+//! `deflate` subprotocol through [`NegotiatedCodec`](../negotiated/struct.NegotiatedCodec.html)) --
+//! browsers don't expose any way to ask for `permessage-deflate` from application
+//! code, so this does the same thing a layer up, at the frame payload instead of the
+//! WebSocket extension level.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+pub use flate2::Compression;
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+/// Compresses outgoing bytes with DEFLATE on the way out, and decompresses an
+/// incoming frame back into bytes on the way in.
+pub struct DeflateCodec {
+    level: Compression,
+}
+
+impl DeflateCodec {
+    /// Creates a codec compressing at [`Compression::default`]'s level.
+    pub fn new() -> Self {
+        DeflateCodec { level: Compression::default() }
+    }
+
+    /// Creates a codec compressing at a specific level, trading ratio for CPU time.
+    pub fn with_level(level: Compression) -> Self {
+        DeflateCodec { level }
+    }
+}
+
+impl Default for DeflateCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for DeflateCodec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DeflateCodec").field("level", &self.level.level()).finish()
+    }
+}
+
+impl Codec for DeflateCodec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&self, value: &Vec<u8>) -> WsMessage {
+        let mut encoder = DeflateEncoder::new(Vec::new(), self.level);
+        // Writing to an in-memory `Vec` can't fail.
+        encoder.write_all(value).expect("compressing into a Vec<u8> cannot fail");
+        WsMessage::Bytes(encoder.finish().expect("compressing into a Vec<u8> cannot fail").into())
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<Vec<u8>, io::Error> {
+        let bytes = match message {
+            WsMessage::Bytes(bytes) => bytes.to_vec(),
+            WsMessage::Text(text) => text.into_bytes(),
+            WsMessage::Blob(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "cannot decode a WsMessage::Blob synchronously; read it via cb::WebSocket's `futures` support first",
+                ));
+            }
+        };
+        let mut decoder = DeflateDecoder::new(&bytes[..]);
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
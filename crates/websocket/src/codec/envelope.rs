@@ -0,0 +1,145 @@
+//! A [`Codec`](../trait.Codec.html) adapter that wraps every outgoing payload in a
+//! small header carrying a message id and the sender's client timestamp, so layers
+//! built on top -- an RPC request/response matcher, or [`crate::dedup`] -- have
+//! something to key on without the wrapped codec's own format needing to say
+//! anything about ids or time.
+//!
+//! # Wire format (version 1)
+//!
+//! ```text
+//! +---------+------------------+------------------------+---------+
+//! | version |        id        |      timestamp_ms       | payload |
+//! | 1 byte  |  8 bytes (LE)    |       8 bytes (LE)       |   ...   |
+//! +---------+------------------+------------------------+---------+
+//! ```
+//!
+//! `version` lets a future format change be detected instead of misread; there's
+//! only one today. `id` is assigned by [`EnvelopeCodec`] itself, starting at `0` and
+//! incrementing by one per [`encode`](EnvelopeCodec::encode) call -- it says nothing
+//! about ordering across a reconnect (unlike [`crate::reliable`]'s sequence numbers),
+//! just "this frame is distinct from every other frame this codec has sent". Frames
+//! are always sent as binary, regardless of what the wrapped codec would have chosen.
+
+use std::error::Error as StdError;
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 1 + 8 + 8;
+
+/// A decoded frame plus the metadata [`EnvelopeCodec`] attached when it was sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope<T> {
+    /// The id [`EnvelopeCodec`] assigned this frame when it was encoded, unique
+    /// within the sending codec's lifetime.
+    pub id: u64,
+    /// The sender's `js_sys::Date::now()`, in milliseconds, when the frame was
+    /// encoded.
+    pub timestamp_ms: u64,
+    /// The wrapped codec's decoded payload.
+    pub payload: T,
+}
+
+/// Wraps a `Codec` so every outgoing payload is tagged with an id and a client
+/// timestamp, and every incoming one is delivered as an [`Envelope`] carrying both
+/// back out. See the [module docs](index.html) for the wire format.
+pub struct EnvelopeCodec<C> {
+    inner: C,
+    next_id: Cell<u64>,
+}
+
+impl<C> EnvelopeCodec<C> {
+    /// Wraps `inner`, starting the id counter at `0`.
+    pub fn new(inner: C) -> Self {
+        EnvelopeCodec { inner, next_id: Cell::new(0) }
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for EnvelopeCodec<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EnvelopeCodec").field("inner", &self.inner).field("next_id", &self.next_id.get()).finish()
+    }
+}
+
+impl<C: Codec> Codec for EnvelopeCodec<C> {
+    type In = Envelope<C::In>;
+    type Out = C::Out;
+    type Error = EnvelopeError<C::Error>;
+
+    fn encode(&self, value: &C::Out) -> WsMessage {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let timestamp_ms = js_sys::Date::now() as u64;
+
+        let payload = match self.inner.encode(value) {
+            WsMessage::Bytes(bytes) => bytes.to_vec(),
+            WsMessage::Text(text) => text.into_bytes(),
+            WsMessage::Blob(_) => unreachable!("no Codec encodes to a WsMessage::Blob"),
+        };
+        let mut framed = Vec::with_capacity(HEADER_LEN + payload.len());
+        framed.push(VERSION);
+        framed.extend_from_slice(&id.to_le_bytes());
+        framed.extend_from_slice(&timestamp_ms.to_le_bytes());
+        framed.extend_from_slice(&payload);
+        WsMessage::Bytes(framed.into())
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<Envelope<C::In>, EnvelopeError<C::Error>> {
+        let bytes = match message {
+            WsMessage::Bytes(bytes) => bytes.to_vec(),
+            WsMessage::Text(text) => text.into_bytes(),
+            // A `Blob` can't be read synchronously; there's no header to read out of
+            // an empty buffer, so this reports it the same way a truncated frame
+            // would rather than guessing which path it would have taken.
+            WsMessage::Blob(_) => Vec::new(),
+        };
+        if bytes.len() < HEADER_LEN {
+            return Err(EnvelopeError::Truncated);
+        }
+        let version = bytes[0];
+        if version != VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+        let id = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+        let timestamp_ms = u64::from_le_bytes(bytes[9..17].try_into().unwrap());
+        let payload = self
+            .inner
+            .decode(WsMessage::Bytes(bytes[HEADER_LEN..].to_vec().into()))
+            .map_err(EnvelopeError::Inner)?;
+        Ok(Envelope { id, timestamp_ms, payload })
+    }
+}
+
+/// Why an [`EnvelopeCodec`] failed to decode an incoming frame.
+#[derive(Debug)]
+pub enum EnvelopeError<E> {
+    /// The frame was shorter than the envelope header.
+    Truncated,
+    /// The frame's header declared a version this build doesn't understand.
+    UnsupportedVersion(u8),
+    /// The header was fine, but the wrapped codec failed to decode the payload.
+    Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for EnvelopeError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EnvelopeError::Truncated => write!(f, "frame is too short to contain an envelope header"),
+            EnvelopeError::UnsupportedVersion(version) => write!(f, "unsupported envelope version {}", version),
+            EnvelopeError::Inner(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl<E: StdError + 'static> StdError for EnvelopeError<E> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            EnvelopeError::Inner(error) => Some(error),
+            EnvelopeError::Truncated | EnvelopeError::UnsupportedVersion(_) => None,
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! A [`Codec`](../trait.Codec.html) built on `serde_json`, for any message type that's
+//! `Serialize` and `DeserializeOwned`.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+/// Serializes `T` to a JSON text frame on the way out and parses it back on the way
+/// in. `T` is used for both directions -- see the [module docs](../index.html) for
+/// why an asymmetric protocol would want two separate type parameters instead.
+pub struct JsonCodec<T> {
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T> JsonCodec<T> {
+    /// Creates a new codec. `T` is inferred from wherever the codec ends up used.
+    pub fn new() -> Self {
+        JsonCodec { _message: PhantomData }
+    }
+}
+
+impl<T> Default for JsonCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for JsonCodec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JsonCodec").finish()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec for JsonCodec<T> {
+    type In = T;
+    type Out = T;
+    type Error = serde_json::Error;
+
+    fn encode(&self, value: &T) -> WsMessage {
+        // A type that only ever round-trips through this codec (the bound `decode`
+        // requires) can't hold the handful of things `serde_json` refuses to
+        // serialize, like a map with non-string keys or a `NaN` float.
+        WsMessage::Text(serde_json::to_string(value).expect("T is representable as JSON"))
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<T, serde_json::Error> {
+        match message {
+            WsMessage::Text(text) => serde_json::from_str(&text),
+            WsMessage::Bytes(bytes) => serde_json::from_slice(bytes.as_ref()),
+            WsMessage::Blob(_) => Err(serde::de::Error::custom(
+                "cannot decode a WsMessage::Blob synchronously; read it via cb::WebSocket's `futures` support first",
+            )),
+        }
+    }
+}
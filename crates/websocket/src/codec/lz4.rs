@@ -0,0 +1,47 @@
+//! A [`Codec`](../trait.Codec.html) built on [`lz4_flex`](https://docs.rs/lz4_flex),
+//! for high-frequency streams (telemetry, game state) where compression latency
+//! matters more than ratio -- LZ4 trades the space savings a
+//! [`DeflateCodec`](../deflate/struct.DeflateCodec.html) or
+//! [`BrotliCodec`](../brotli/struct.BrotliCodec.html) gets for being dramatically
+//! cheaper to run on every frame.
+
+use lz4_flex::block::DecompressError;
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+/// Compresses outgoing bytes with LZ4 on the way out, and decompresses an incoming
+/// frame back into bytes on the way in.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4Codec;
+
+impl Lz4Codec {
+    /// Creates a new codec.
+    pub fn new() -> Self {
+        Lz4Codec
+    }
+}
+
+impl Codec for Lz4Codec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+    type Error = DecompressError;
+
+    fn encode(&self, value: &Vec<u8>) -> WsMessage {
+        // The compressed frame is self-describing (the uncompressed length is
+        // prepended), so `decode` doesn't need the sender's original size out of band.
+        WsMessage::Bytes(lz4_flex::compress_prepend_size(value).into())
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<Vec<u8>, DecompressError> {
+        let bytes = match message {
+            WsMessage::Bytes(bytes) => bytes.to_vec(),
+            WsMessage::Text(text) => text.into_bytes(),
+            // A `Blob` can't be read synchronously; an empty buffer is too short to
+            // even hold the prepended length, so this fails the same way a truncated
+            // frame would rather than inventing a `DecompressError` of our own.
+            WsMessage::Blob(_) => Vec::new(),
+        };
+        lz4_flex::decompress_size_prepended(&bytes)
+    }
+}
@@ -0,0 +1,75 @@
+//! A pluggable serialization extension point, so an application working with typed
+//! messages doesn't have to hand-roll `encode`/`decode` helpers next to every socket
+//! that needs one.
+//!
+//! [`Codec`] is deliberately minimal: [`encode`](Codec::encode) and
+//! [`decode`](Codec::decode) are just conversions between application types and
+//! [`WsMessage`](../cb/enum.WsMessage.html) -- the same type
+//! [`cb::WebSocket`](../cb/struct.WebSocket.html) already sends
+//! ([`send_text`](../cb/struct.WebSocket.html#method.send_text) /
+//! [`send_bytes`](../cb/struct.WebSocket.html#method.send_bytes), depending on which
+//! variant `encode` returns) and delivers through
+//! [`WsEvent::Message`](../cb/enum.WsEvent.html#variant.Message). Every serialization
+//! format this crate ships a codec for -- JSON, MessagePack, CBOR, ... -- is one small
+//! `Codec` impl rather than its own bespoke module full of ad hoc `to_string`/`parse`
+//! helpers, and any layer built on top of a socket can stay generic over `Codec`
+//! instead of hardcoding one format.
+//!
+//! `In` and `Out` are separate associated types rather than one shared message type
+//! because plenty of real protocols aren't symmetric -- a client might send a request
+//! struct and receive a differently shaped response or event enum.
+
+use crate::cb::WsMessage;
+
+pub mod envelope;
+pub mod negotiated;
+pub mod threshold;
+
+#[cfg(feature = "json")]
+pub mod json;
+
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+
+#[cfg(feature = "cbor")]
+pub mod cbor;
+
+#[cfg(feature = "bincode")]
+pub mod bincode;
+
+#[cfg(feature = "prost")]
+pub mod prost;
+
+#[cfg(feature = "flatbuffers")]
+pub mod flatbuffers;
+
+#[cfg(feature = "base64")]
+pub mod base64;
+
+#[cfg(feature = "deflate")]
+pub mod deflate;
+
+#[cfg(feature = "brotli")]
+pub mod brotli;
+
+#[cfg(feature = "lz4")]
+pub mod lz4;
+
+/// Converts between an application's typed messages and the raw
+/// [`WsMessage`](../cb/enum.WsMessage.html)s a [`cb::WebSocket`](../cb/struct.WebSocket.html)
+/// actually sends and receives. See the [module docs](index.html) for how the two
+/// sides fit together.
+pub trait Codec {
+    /// The type a decoded incoming message is delivered as.
+    type In;
+    /// The type an outgoing message is encoded from.
+    type Out;
+    /// Why an incoming message failed to decode.
+    type Error;
+
+    /// Encodes `value` into the frame that carries it over the wire.
+    fn encode(&self, value: &Self::Out) -> WsMessage;
+
+    /// Decodes an incoming frame into `In`, or reports why it isn't one.
+    fn decode(&self, message: WsMessage) -> Result<Self::In, Self::Error>;
+}
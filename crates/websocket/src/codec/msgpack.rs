@@ -0,0 +1,60 @@
+//! A [`Codec`](../trait.Codec.html) built on [`rmp-serde`](https://docs.rs/rmp-serde),
+//! for apps that want serde ergonomics but a more compact binary frame than JSON, e.g.
+//! over a metered or high-latency connection.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+/// Serializes `T` to a MessagePack binary frame on the way out and parses it back on
+/// the way in. `T` is used for both directions -- see the [module docs](../index.html)
+/// for why an asymmetric protocol would want two separate type parameters instead.
+pub struct MsgpackCodec<T> {
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T> MsgpackCodec<T> {
+    /// Creates a new codec. `T` is inferred from wherever the codec ends up used.
+    pub fn new() -> Self {
+        MsgpackCodec { _message: PhantomData }
+    }
+}
+
+impl<T> Default for MsgpackCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for MsgpackCodec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MsgpackCodec").finish()
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec for MsgpackCodec<T> {
+    type In = T;
+    type Out = T;
+    type Error = rmp_serde::decode::Error;
+
+    fn encode(&self, value: &T) -> WsMessage {
+        // Mirrors `JsonCodec::encode`: a type that only ever round-trips through this
+        // codec can't hold anything `rmp_serde` refuses to serialize.
+        WsMessage::Bytes(rmp_serde::to_vec(value).expect("T is representable as MessagePack").into())
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<T, rmp_serde::decode::Error> {
+        match message {
+            WsMessage::Bytes(bytes) => rmp_serde::from_slice(bytes.as_ref()),
+            WsMessage::Text(text) => rmp_serde::from_slice(text.as_bytes()),
+            WsMessage::Blob(_) => Err(serde::de::Error::custom(
+                "cannot decode a WsMessage::Blob synchronously; read it via cb::WebSocket's `futures` support first",
+            )),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Automatic codec selection by the subprotocol a socket negotiates, so a client
+//! willing to speak more than one wire format can offer all of them and simply use
+//! whichever one the server picked, instead of hardcoding one.
+//!
+//! [`NegotiatedCodec`] holds one [`Codec`] per subprotocol name; build it up with
+//! [`register`](NegotiatedCodec::register), request every registered name via
+//! [`WebSocketBuilder::protocols`](../../cb/struct.WebSocketBuilder.html#method.protocols),
+//! then pass whatever [`cb::WebSocket::protocol`](../../cb/struct.WebSocket.html#method.protocol)
+//! reports once the connection opens to [`encode`](NegotiatedCodec::encode) and
+//! [`decode`](NegotiatedCodec::decode). Every registered codec has to agree on
+//! `In`/`Out` -- there's one application message type regardless of which wire format
+//! got picked -- but each can fail to decode in its own way, so codecs are boxed with
+//! their `Error` erased to `Box<dyn Error>` before being stored.
+
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+trait ErasedCodec<In, Out> {
+    fn encode(&self, value: &Out) -> WsMessage;
+    fn decode(&self, message: WsMessage) -> Result<In, Box<dyn StdError>>;
+}
+
+impl<C> ErasedCodec<C::In, C::Out> for C
+where
+    C: Codec,
+    C::Error: StdError + 'static,
+{
+    fn encode(&self, value: &C::Out) -> WsMessage {
+        Codec::encode(self, value)
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<C::In, Box<dyn StdError>> {
+        Codec::decode(self, message).map_err(|error| Box::new(error) as Box<dyn StdError>)
+    }
+}
+
+/// A set of [`Codec`]s to choose between by negotiated subprotocol. See the
+/// [module docs](index.html).
+pub struct NegotiatedCodec<In, Out> {
+    codecs: HashMap<String, Box<dyn ErasedCodec<In, Out>>>,
+}
+
+impl<In, Out> fmt::Debug for NegotiatedCodec<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NegotiatedCodec").field("protocols", &self.codecs.keys().collect::<Vec<_>>()).finish()
+    }
+}
+
+impl<In, Out> Default for NegotiatedCodec<In, Out> {
+    fn default() -> Self {
+        NegotiatedCodec { codecs: HashMap::new() }
+    }
+}
+
+impl<In, Out> NegotiatedCodec<In, Out> {
+    /// Creates an empty set of codecs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `codec` to be used when the server negotiates `protocol`. Calling
+    /// this again with a `protocol` already registered replaces its codec.
+    pub fn register<C>(mut self, protocol: impl Into<String>, codec: C) -> Self
+    where
+        C: Codec<In = In, Out = Out> + 'static,
+        C::Error: StdError + 'static,
+    {
+        self.codecs.insert(protocol.into(), Box::new(codec));
+        self
+    }
+
+    /// Encodes `value` with the codec registered for `protocol`, or `None` if nothing
+    /// is registered under that name.
+    pub fn encode(&self, protocol: &str, value: &Out) -> Option<WsMessage> {
+        self.codecs.get(protocol).map(|codec| codec.encode(value))
+    }
+
+    /// Decodes `message` with the codec registered for `protocol`, or `None` if
+    /// nothing is registered under that name.
+    pub fn decode(&self, protocol: &str, message: WsMessage) -> Option<Result<In, Box<dyn StdError>>> {
+        self.codecs.get(protocol).map(|codec| codec.decode(message))
+    }
+}
@@ -0,0 +1,58 @@
+//! A [`Codec`](../trait.Codec.html) for `prost`-generated message types, encoding to
+//! binary protobuf frames -- for gRPC-adjacent backends that want the wasm client to
+//! reuse the same `.proto` definitions as everything else, without a serde detour.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use prost::Message;
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+/// Encodes `T` to a protobuf binary frame on the way out and decodes it back on the
+/// way in, via `prost`'s own `Message` impl rather than serde. `T` is used for both
+/// directions -- see the [module docs](../index.html) for why an asymmetric protocol
+/// would want two separate type parameters instead.
+pub struct ProstCodec<T> {
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T> ProstCodec<T> {
+    /// Creates a new codec. `T` is inferred from wherever the codec ends up used.
+    pub fn new() -> Self {
+        ProstCodec { _message: PhantomData }
+    }
+}
+
+impl<T> Default for ProstCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for ProstCodec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProstCodec").finish()
+    }
+}
+
+impl<T: Message + Default> Codec for ProstCodec<T> {
+    type In = T;
+    type Out = T;
+    type Error = prost::DecodeError;
+
+    fn encode(&self, value: &T) -> WsMessage {
+        WsMessage::Bytes(value.encode_to_vec().into())
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<T, prost::DecodeError> {
+        match message {
+            WsMessage::Bytes(bytes) => T::decode(bytes.as_ref()),
+            WsMessage::Text(text) => T::decode(text.as_bytes()),
+            WsMessage::Blob(_) => Err(prost::DecodeError::new(
+                "cannot decode a WsMessage::Blob synchronously; read it via cb::WebSocket's `futures` support first",
+            )),
+        }
+    }
+}
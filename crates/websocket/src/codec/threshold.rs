@@ -0,0 +1,115 @@
+//! A [`Codec`](../trait.Codec.html) adapter that only bothers compressing payloads
+//! worth the CPU cost, for cases where most frames are small (control messages, short
+//! updates) but a few are large enough that a
+//! [`DeflateCodec`](../deflate/struct.DeflateCodec.html) or
+//! [`BrotliCodec`](../brotli/struct.BrotliCodec.html) is worth running on them.
+//!
+//! [`ThresholdCodec`] wraps any byte-oriented codec and prepends a one-byte marker so
+//! [`decode`](ThresholdCodec::decode) knows, per frame, whether the sender skipped
+//! compression -- there's no way to tell a compressed payload from an uncompressed one
+//! by looking at it alone.
+
+use std::error::Error as StdError;
+use std::fmt;
+
+use crate::cb::WsMessage;
+use crate::codec::Codec;
+
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// Wraps a byte-oriented `Codec` so payloads under `threshold` bytes are sent as-is
+/// instead of being run through it. See the [module docs](index.html).
+pub struct ThresholdCodec<C> {
+    inner: C,
+    threshold: usize,
+}
+
+impl<C> ThresholdCodec<C> {
+    /// Wraps `inner`, passing payloads smaller than `threshold` bytes through
+    /// uncompressed instead of encoding them with it.
+    pub fn new(inner: C, threshold: usize) -> Self {
+        ThresholdCodec { inner, threshold }
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for ThresholdCodec<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThresholdCodec").field("inner", &self.inner).field("threshold", &self.threshold).finish()
+    }
+}
+
+impl<C> Codec for ThresholdCodec<C>
+where
+    C: Codec<In = Vec<u8>, Out = Vec<u8>>,
+{
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+    type Error = ThresholdError<C::Error>;
+
+    fn encode(&self, value: &Vec<u8>) -> WsMessage {
+        if value.len() < self.threshold {
+            let mut framed = Vec::with_capacity(value.len() + 1);
+            framed.push(RAW);
+            framed.extend_from_slice(value);
+            WsMessage::Bytes(framed.into())
+        } else {
+            let encoded = match self.inner.encode(value) {
+                WsMessage::Bytes(bytes) => bytes.to_vec(),
+                WsMessage::Text(text) => text.into_bytes(),
+                WsMessage::Blob(_) => unreachable!("no Codec encodes to a WsMessage::Blob"),
+            };
+            let mut framed = Vec::with_capacity(encoded.len() + 1);
+            framed.push(COMPRESSED);
+            framed.extend_from_slice(&encoded);
+            WsMessage::Bytes(framed.into())
+        }
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<Vec<u8>, ThresholdError<C::Error>> {
+        let bytes = match message {
+            WsMessage::Bytes(bytes) => bytes.to_vec(),
+            WsMessage::Text(text) => text.into_bytes(),
+            // A `Blob` can't be read synchronously; there's no marker byte to read out
+            // of an empty buffer, so this reports it the same way a truncated frame
+            // would rather than guessing which path it would have taken.
+            WsMessage::Blob(_) => Vec::new(),
+        };
+        match bytes.split_first() {
+            Some((&RAW, rest)) => Ok(rest.to_vec()),
+            Some((&COMPRESSED, rest)) => {
+                self.inner.decode(WsMessage::Bytes(rest.to_vec().into())).map_err(ThresholdError::Inner)
+            }
+            Some((_, _)) | None => Err(ThresholdError::MissingMarker),
+        }
+    }
+}
+
+/// Why a [`ThresholdCodec`] failed to decode an incoming frame.
+#[derive(Debug)]
+pub enum ThresholdError<E> {
+    /// The frame was empty or started with a byte other than the raw/compressed
+    /// marker this codec writes.
+    MissingMarker,
+    /// The marker said the payload was compressed, but the wrapped codec failed to
+    /// decode it.
+    Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for ThresholdError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThresholdError::MissingMarker => write!(f, "frame is missing the raw/compressed marker byte"),
+            ThresholdError::Inner(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl<E: StdError + 'static> StdError for ThresholdError<E> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            ThresholdError::MissingMarker => None,
+            ThresholdError::Inner(error) => Some(error),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Transport abstraction shared by the [`cb`](../cb/index.html) and
+//! [`fut`](../fut/index.html) APIs.
+//!
+//! `WebSocket` talks to the underlying socket exclusively through this trait rather
+//! than calling `web_sys::WebSocket`'s methods directly, so a mock transport (for
+//! tests) or an alternate one (e.g. `WebTransport`, or a native socket off-Wasm) could
+//! stand in for it without touching the rest of the crate. `fut` doesn't need its own
+//! copy of this: it's built entirely on top of `cb::WebSocket`'s public API, so it
+//! picks up whatever implementation `cb` is using for free.
+
+use std::fmt;
+use std::time::Duration;
+
+use wasm_bindgen::JsValue;
+
+use crate::cb::ReconnectConfig;
+
+/// A source of "now", abstracted so the reconnect/backoff logic that reads it doesn't
+/// have to call `js_sys::Date::now()` directly.
+///
+/// `WebSocket` uses [`SystemClock`](struct.SystemClock.html) everywhere by default; the
+/// only reason this is a trait rather than a bare function is so a test harness can swap
+/// in a fake clock and drive reconnect timing deterministically instead of waiting on
+/// real delays.
+pub(crate) trait Clock: fmt::Debug {
+    /// Milliseconds since the epoch, as returned by `js_sys::Date::now`.
+    fn now_millis(&self) -> f64;
+}
+
+/// The [`Clock`](trait.Clock.html) `WebSocket` uses outside of tests.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> f64 {
+        js_sys::Date::now()
+    }
+}
+
+/// The operations `WebSocket` needs from an open (or opening) socket.
+///
+/// Implemented here for [`web_sys::WebSocket`](https://docs.rs/web-sys/*/web_sys/struct.WebSocket.html).
+pub(crate) trait Transport {
+    /// Sends a UTF-8 text frame.
+    fn send_text(&self, text: &str) -> Result<(), JsValue>;
+
+    /// Sends a binary frame.
+    fn send_bytes(&self, bytes: &[u8]) -> Result<(), JsValue>;
+
+    /// Sends a binary frame whose payload is a browser `Blob`.
+    fn send_blob(&self, blob: &web_sys::Blob) -> Result<(), JsValue>;
+
+    /// Initiates the closing handshake.
+    fn close(&self, code: u16, reason: &str) -> Result<(), JsValue>;
+
+    /// Bytes queued by previous sends that haven't yet been handed off to the network layer.
+    fn buffered_amount(&self) -> u32;
+}
+
+impl Transport for web_sys::WebSocket {
+    fn send_text(&self, text: &str) -> Result<(), JsValue> {
+        self.send_with_str(text)
+    }
+
+    fn send_bytes(&self, bytes: &[u8]) -> Result<(), JsValue> {
+        self.send_with_u8_array(bytes)
+    }
+
+    fn send_blob(&self, blob: &web_sys::Blob) -> Result<(), JsValue> {
+        self.send_with_blob(blob)
+    }
+
+    fn close(&self, code: u16, reason: &str) -> Result<(), JsValue> {
+        self.close_with_code_and_reason(code, reason)
+    }
+
+    fn buffered_amount(&self) -> u32 {
+        web_sys::WebSocket::buffered_amount(self)
+    }
+}
+
+/// The reconnect/backoff state machine.
+///
+/// This is the one place that decides whether to wait and try again or give up; `cb`
+/// drives it from its reconnect timer, and any future frontend would drive the same
+/// instance the same way, so a fix here (or in a
+/// [`BackoffStrategy`](../cb/trait.BackoffStrategy.html) impl) can't end up applying to
+/// one frontend and not another.
+#[derive(Debug)]
+pub(crate) struct ReconnectState {
+    attempt: u32,
+    started_at_millis: Option<f64>,
+}
+
+impl ReconnectState {
+    pub(crate) fn new() -> Self {
+        ReconnectState {
+            attempt: 0,
+            started_at_millis: None,
+        }
+    }
+
+    /// Forgets any in-progress reconnect cycle, as if the socket had just opened.
+    pub(crate) fn reset(&mut self) {
+        self.attempt = 0;
+        self.started_at_millis = None;
+    }
+
+    /// Resets the attempt counter without touching the elapsed-time clock, for a
+    /// reconnect that got interrupted (e.g. by the network or tab going away) in a way
+    /// that shouldn't count against the backoff sequence but also isn't a fresh
+    /// connection as far as `max_elapsed_time` is concerned.
+    pub(crate) fn reset_attempt_count(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Which reconnect attempt is next, counting from `0`.
+    pub(crate) fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Starts the elapsed-time clock used by `ReconnectConfig::max_elapsed_time`, if it
+    /// isn't running already. Idempotent, and also called by
+    /// [`next`](#method.next) -- callers only need this directly if they want the clock
+    /// running before they know whether they'll actually call `next` (e.g. because a
+    /// pause might intervene first).
+    pub(crate) fn mark_started(&mut self, now_millis: f64) {
+        if self.started_at_millis.is_none() {
+            self.started_at_millis = Some(now_millis);
+        }
+    }
+
+    /// Decides what to do about the next reconnect attempt, given `config` and the
+    /// current time (as returned by `js_sys::Date::now`).
+    ///
+    /// On [`BackoffDecision::Wait`](enum.BackoffDecision.html#variant.Wait), the
+    /// attempt counter has already been advanced for next time; on
+    /// [`BackoffDecision::GiveUp`](enum.BackoffDecision.html#variant.GiveUp) it's
+    /// left alone, since the caller is expected to close the connection for good.
+    pub(crate) fn next(&mut self, config: &ReconnectConfig, now_millis: f64) -> BackoffDecision {
+        self.mark_started(now_millis);
+
+        let retries_exhausted = config.max_retries.map_or(false, |max_retries| self.attempt >= max_retries);
+        let elapsed_exhausted = match (config.max_elapsed_time, self.started_at_millis) {
+            (Some(max_elapsed_time), Some(started_at)) => {
+                now_millis - started_at >= max_elapsed_time.as_millis() as f64
+            }
+            _ => false,
+        };
+
+        if retries_exhausted || elapsed_exhausted {
+            return BackoffDecision::GiveUp;
+        }
+
+        let attempt = self.attempt;
+        let clamped = config.strategy.is_clamped(attempt);
+        let raw_delay = config.strategy.delay(attempt);
+        let jitter = (config.jitter)(attempt).max(0.0);
+        let delay = Duration::from_millis((raw_delay.as_millis() as f64 * jitter) as u64);
+        self.attempt += 1;
+
+        BackoffDecision::Wait { attempt, delay, clamped }
+    }
+}
+
+/// What [`ReconnectState::next`](struct.ReconnectState.html#method.next) decided to do.
+pub(crate) enum BackoffDecision {
+    /// Wait `delay`, then make reconnect attempt number `attempt`.
+    Wait {
+        /// Which attempt this is, counting from `0`.
+        attempt: u32,
+        /// How long to wait before making it.
+        delay: Duration,
+        /// Whether `delay` was clamped by the configured
+        /// [`BackoffStrategy`](../cb/trait.BackoffStrategy.html).
+        clamped: bool,
+    },
+    /// Stop reconnecting: retries or elapsed time are exhausted.
+    GiveUp,
+}
@@ -0,0 +1,237 @@
+//! An end-to-end AES-GCM encryption layer on top of [`cb::WebSocket`](../cb/struct.WebSocket.html),
+//! for connections that pass through a relay that shouldn't be able to read the
+//! payloads it forwards.
+//!
+//! [`Encrypted::connect`] takes a raw AES-GCM key -- both ends need to already agree
+//! on it, by whatever out-of-band exchange the application uses -- and imports it
+//! through the browser's `SubtleCrypto` as soon as the socket opens. Importing a key
+//! is asynchronous, so [`send`](Encrypted::send) calls made before that finishes are
+//! queued and flushed in order once it does; nothing before that point ever reaches
+//! the wire unencrypted.
+//!
+//! Every outgoing frame gets a fresh random 12-byte IV, sent as the first 12 bytes of
+//! the frame ahead of the ciphertext -- AES-GCM requires a unique IV per message
+//! under the same key, and generating one per send (rather than, say, a counter) means
+//! there's no shared state to keep in sync across a reconnect. Incoming frames are
+//! decrypted the same way and delivered through [`Stream`], as
+//! [`DecryptError`](enum.DecryptError.html) if the peer's key doesn't match or the
+//! frame was tampered with in transit.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use futures::sync::mpsc;
+use futures::{future, Future, Poll, Stream};
+use js_sys::Uint8Array;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use web_sys::{AesGcmParams, CryptoKey};
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+const IV_LEN: usize = 12;
+
+struct Inner {
+    // `None` only in the brief window in `build_encrypted` between constructing this
+    // and `WebSocketBuilder::build` returning; `on_event` can't fire until `build`
+    // returns, so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    key: Option<CryptoKey>,
+    pending_outgoing: Vec<Vec<u8>>,
+    sender: mpsc::UnboundedSender<Result<Vec<u8>, DecryptError>>,
+}
+
+impl Inner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("Inner.ws is set before any event can fire")
+    }
+}
+
+/// Why an incoming frame couldn't be decrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecryptError {
+    /// The frame was shorter than the 12-byte IV every encrypted frame starts with.
+    Truncated,
+    /// `SubtleCrypto.decrypt` rejected the ciphertext -- a key mismatch or a frame
+    /// tampered with in transit both surface this way; AES-GCM doesn't distinguish
+    /// the two.
+    Rejected,
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecryptError::Truncated => write!(f, "frame is shorter than the prepended IV"),
+            DecryptError::Rejected => write!(f, "decryption failed (wrong key or tampered frame)"),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// An encrypted [`cb::WebSocket`](../cb/struct.WebSocket.html). See the
+/// [module docs](index.html).
+pub struct Encrypted {
+    inner: Rc<RefCell<Inner>>,
+    receiver: mpsc::UnboundedReceiver<Result<Vec<u8>, DecryptError>>,
+}
+
+impl fmt::Debug for Encrypted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Encrypted").field("ws", &inner.ws).field("key_ready", &inner.key.is_some()).finish()
+    }
+}
+
+impl Encrypted {
+    /// Connects to `url`, importing `raw_key` (an AES-GCM key, typically 16 or 32
+    /// bytes) as soon as the connection opens.
+    pub fn connect(url: &str, raw_key: Vec<u8>) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url), raw_key)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../cb/struct.WebSocketBuilder.html).
+    /// See [`connect`](#method.connect) for what `raw_key` is.
+    pub fn from_builder(builder: WebSocketBuilder, raw_key: Vec<u8>) -> Result<Self, BuildError> {
+        let (sender, receiver) = mpsc::unbounded();
+        let inner = Rc::new(RefCell::new(Inner { ws: None, key: None, pending_outgoing: Vec::new(), sender }));
+        build_encrypted(builder, inner, receiver, raw_key)
+    }
+
+    /// Encrypts and sends `payload`. Queued until the key finishes importing if
+    /// called before the connection has opened (or before that import resolves).
+    pub fn send(&self, payload: Vec<u8>) {
+        let key = self.inner.borrow().key.clone();
+        match key {
+            Some(key) => encrypt_and_send(&self.inner, key, payload),
+            None => self.inner.borrow_mut().pending_outgoing.push(payload),
+        }
+    }
+}
+
+impl Stream for Encrypted {
+    type Item = Result<Vec<u8>, DecryptError>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, ()> {
+        self.receiver.poll()
+    }
+}
+
+fn build_encrypted(
+    builder: WebSocketBuilder,
+    placeholder: Rc<RefCell<Inner>>,
+    receiver: mpsc::UnboundedReceiver<Result<Vec<u8>, DecryptError>>,
+    raw_key: Vec<u8>,
+) -> Result<Encrypted, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, &raw_key, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(Encrypted { inner: placeholder, receiver })
+}
+
+fn handle_event(inner: &Rc<RefCell<Inner>>, raw_key: &[u8], event: WsEvent) {
+    match event {
+        WsEvent::Open => import_key(inner, raw_key.to_vec()),
+        WsEvent::Message(WsMessage::Bytes(bytes)) => decrypt_incoming(inner, bytes.to_vec()),
+        // Text and Blob frames aren't something this layer ever sends; there's
+        // nothing sensible to decrypt them as.
+        _ => {}
+    }
+}
+
+fn subtle() -> web_sys::SubtleCrypto {
+    web_sys::window().expect("Encrypted requires a Window (browser main thread)").crypto().expect("browser exposes window.crypto").subtle()
+}
+
+fn import_key(inner: &Rc<RefCell<Inner>>, raw_key: Vec<u8>) {
+    let usages = js_sys::Array::of2(&"encrypt".into(), &"decrypt".into());
+    let promise = subtle()
+        .import_key_with_str("raw", &Uint8Array::from(raw_key.as_slice()), "AES-GCM", false, &usages)
+        .expect("importKey does not fail synchronously");
+
+    let inner = inner.clone();
+    let flushed = JsFuture::from(promise).then(move |result| {
+        if let Ok(key) = result {
+            let key: CryptoKey = key.unchecked_into();
+            let pending = {
+                let mut state = inner.borrow_mut();
+                state.key = Some(key.clone());
+                mem::take(&mut state.pending_outgoing)
+            };
+            for payload in pending {
+                encrypt_and_send(&inner, key.clone(), payload);
+            }
+        }
+        // The key import failing has nothing sensible to report to -- there's no
+        // caller waiting on `connect` anymore -- so a failed import just leaves
+        // `send` queuing forever, same as one that's merely still in flight.
+        future::ok::<wasm_bindgen::JsValue, wasm_bindgen::JsValue>(wasm_bindgen::JsValue::UNDEFINED)
+    });
+    future_to_promise(flushed);
+}
+
+fn encrypt_and_send(inner: &Rc<RefCell<Inner>>, key: CryptoKey, mut payload: Vec<u8>) {
+    let mut iv = [0u8; IV_LEN];
+    subtle_crypto_random(&mut iv);
+    let params = AesGcmParams::new("AES-GCM", &Uint8Array::from(&iv[..]));
+    let promise = subtle().encrypt_with_object_and_u8_array(&params, &key, &mut payload).expect("encrypting a byte slice does not fail synchronously");
+
+    let inner = inner.clone();
+    let sent = JsFuture::from(promise).then(move |result| {
+        if let Ok(ciphertext) = result {
+            let ciphertext = Uint8Array::new(&ciphertext).to_vec();
+            let mut framed = Vec::with_capacity(IV_LEN + ciphertext.len());
+            framed.extend_from_slice(&iv);
+            framed.extend_from_slice(&ciphertext);
+            inner.borrow().ws().send_bytes(&framed);
+        }
+        future::ok::<wasm_bindgen::JsValue, wasm_bindgen::JsValue>(wasm_bindgen::JsValue::UNDEFINED)
+    });
+    future_to_promise(sent);
+}
+
+fn decrypt_incoming(inner: &Rc<RefCell<Inner>>, frame: Vec<u8>) {
+    if frame.len() < IV_LEN {
+        let _ = inner.borrow().sender.unbounded_send(Err(DecryptError::Truncated));
+        return;
+    }
+    let key = match inner.borrow().key.clone() {
+        Some(key) => key,
+        // A frame arriving before this end's own key import resolves can't be
+        // decrypted yet; there's nowhere to buffer it that wouldn't reorder
+        // messages relative to ones that do decrypt, so it's reported the same as
+        // a tampered frame rather than silently dropped.
+        None => {
+            let _ = inner.borrow().sender.unbounded_send(Err(DecryptError::Rejected));
+            return;
+        }
+    };
+    let (iv, ciphertext) = frame.split_at(IV_LEN);
+    let params = AesGcmParams::new("AES-GCM", &Uint8Array::from(iv));
+    let promise = subtle()
+        .decrypt_with_object_and_u8_array(&params, &key, &mut ciphertext.to_vec())
+        .expect("decrypting a byte slice does not fail synchronously");
+
+    let inner = inner.clone();
+    let delivered = JsFuture::from(promise).then(move |result| {
+        let outcome = match result {
+            Ok(plaintext) => Ok(Uint8Array::new(&plaintext).to_vec()),
+            Err(_) => Err(DecryptError::Rejected),
+        };
+        let _ = inner.borrow().sender.unbounded_send(outcome);
+        future::ok::<wasm_bindgen::JsValue, wasm_bindgen::JsValue>(wasm_bindgen::JsValue::UNDEFINED)
+    });
+    future_to_promise(delivered);
+}
+
+fn subtle_crypto_random(buf: &mut [u8]) {
+    web_sys::window()
+        .expect("Encrypted requires a Window (browser main thread)")
+        .crypto()
+        .expect("browser exposes window.crypto")
+        .get_random_values_with_u8_array(buf)
+        .expect("getRandomValues does not fail for a 12-byte buffer");
+}
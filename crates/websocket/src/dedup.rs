@@ -0,0 +1,163 @@
+//! A generic duplicate-filtering layer on top of [`cb::WebSocket`](../cb/struct.WebSocket.html),
+//! for servers that resend at-least-once -- after a reconnect, say -- and would
+//! otherwise cause the same update to be applied twice.
+//!
+//! [`Dedup`] doesn't know what makes one of your messages a duplicate of another;
+//! the caller supplies a `key_of` closure that pulls a comparison key out of a
+//! message's raw text (a sequence number, an id field, a hash of the payload,
+//! whatever uniquely identifies "this logical update" for the server in question).
+//! A message `key_of` returns `None` for is passed straight through -- there's
+//! nothing to compare it against, so it can't be deduplicated. The last `capacity`
+//! distinct keys are remembered; once that fills up, the oldest key is forgotten to
+//! make room for the newest, so this costs bounded memory rather than growing for
+//! the life of the connection. [`crate::reliable`] already does its own sequence-based
+//! dedup internally as part of guaranteeing in-order delivery -- reach for `Dedup`
+//! instead when the server doesn't speak that envelope but still repeats itself.
+//!
+//! [`reliable_key`] is a ready-made `key_of` for a server that tags messages with an
+//! idempotency key using [`crate::reliable`]'s own frame format, without necessarily
+//! running the rest of that module's ack/retransmit machinery.
+
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::rc::Rc;
+
+use futures::sync::mpsc;
+use futures::{Poll, Stream};
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+struct Inner<Key> {
+    // `None` only in the brief window in `build_dedup` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build`
+    // returns, so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    capacity: usize,
+    seen: HashSet<Key>,
+    seen_order: VecDeque<Key>,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl<Key> Inner<Key> {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("Inner.ws is set before any event can fire")
+    }
+}
+
+/// A [`cb::WebSocket`](../cb/struct.WebSocket.html) that filters out incoming
+/// messages it's already delivered. See the [module docs](index.html) for how
+/// duplicates are recognized.
+pub struct Dedup<Key: Eq + Hash + Clone + 'static> {
+    inner: Rc<RefCell<Inner<Key>>>,
+    receiver: mpsc::UnboundedReceiver<String>,
+}
+
+impl<Key: Eq + Hash + Clone + 'static> fmt::Debug for Dedup<Key> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Dedup").field("ws", &inner.ws).field("remembered", &inner.seen.len()).finish()
+    }
+}
+
+impl<Key: Eq + Hash + Clone + 'static> Dedup<Key> {
+    /// Connects to `url`, remembering the last `capacity` distinct keys `key_of`
+    /// produces for incoming messages.
+    pub fn connect<KeyOf>(url: &str, capacity: usize, key_of: KeyOf) -> Result<Self, BuildError>
+    where
+        KeyOf: Fn(&str) -> Option<Key> + 'static,
+    {
+        Self::from_builder(WebSocketBuilder::new(url), capacity, key_of)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../cb/struct.WebSocketBuilder.html).
+    /// See [`connect`](#method.connect) for what `capacity` and `key_of` do.
+    pub fn from_builder<KeyOf>(builder: WebSocketBuilder, capacity: usize, key_of: KeyOf) -> Result<Self, BuildError>
+    where
+        KeyOf: Fn(&str) -> Option<Key> + 'static,
+    {
+        let (sender, receiver) = mpsc::unbounded();
+        let inner = Rc::new(RefCell::new(Inner { ws: None, capacity, seen: HashSet::new(), seen_order: VecDeque::new(), sender }));
+        build_dedup(builder, inner, receiver, Rc::new(key_of))
+    }
+
+    /// Sends `text` as-is; outgoing messages are never deduplicated.
+    pub fn send_text(&self, text: &str) {
+        self.inner.borrow().ws().send_text(text);
+    }
+}
+
+impl<Key: Eq + Hash + Clone + 'static> Stream for Dedup<Key> {
+    type Item = String;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<String>, ()> {
+        self.receiver.poll()
+    }
+}
+
+fn build_dedup<Key, KeyOf>(
+    builder: WebSocketBuilder,
+    placeholder: Rc<RefCell<Inner<Key>>>,
+    receiver: mpsc::UnboundedReceiver<String>,
+    key_of: Rc<KeyOf>,
+) -> Result<Dedup<Key>, BuildError>
+where
+    Key: Eq + Hash + Clone + 'static,
+    KeyOf: Fn(&str) -> Option<Key> + 'static,
+{
+    let dispatch = placeholder.clone();
+    let dispatch_key_of = key_of.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, &dispatch_key_of, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(Dedup { inner: placeholder, receiver })
+}
+
+fn handle_event<Key, KeyOf>(inner: &Rc<RefCell<Inner<Key>>>, key_of: &Rc<KeyOf>, event: WsEvent)
+where
+    Key: Eq + Hash + Clone + 'static,
+    KeyOf: Fn(&str) -> Option<Key> + 'static,
+{
+    if let WsEvent::Message(WsMessage::Text(text)) = event {
+        on_data(inner, key_of, text);
+    }
+}
+
+/// A `key_of` for [`Dedup::connect`](struct.Dedup.html#method.connect)/[`from_builder`](struct.Dedup.html#method.from_builder)
+/// that pulls the idempotency key back out of a [`crate::reliable`]-formatted data
+/// frame (`D<seq>\u{1f}<key>\u{1f}<payload>`), ignoring everything else -- acks and
+/// any frame this end doesn't recognize just produce `None`, so they pass through
+/// undeduplicated rather than being dropped.
+pub fn reliable_key(text: &str) -> Option<String> {
+    let mut chars = text.chars();
+    if chars.next()? != 'D' {
+        return None;
+    }
+    let mut parts = chars.as_str().splitn(3, '\u{1f}');
+    parts.next()?; // seq, unused here
+    Some(parts.next()?.to_string())
+}
+
+fn on_data<Key, KeyOf>(inner: &Rc<RefCell<Inner<Key>>>, key_of: &Rc<KeyOf>, text: String)
+where
+    Key: Eq + Hash + Clone + 'static,
+    KeyOf: Fn(&str) -> Option<Key> + 'static,
+{
+    let mut inner = inner.borrow_mut();
+    if let Some(key) = key_of(&text) {
+        if inner.seen.contains(&key) {
+            return;
+        }
+        if inner.capacity > 0 {
+            inner.seen.insert(key.clone());
+            inner.seen_order.push_back(key);
+            while inner.seen_order.len() > inner.capacity {
+                if let Some(oldest) = inner.seen_order.pop_front() {
+                    inner.seen.remove(&oldest);
+                }
+            }
+        }
+    }
+    let _ = inner.sender.unbounded_send(text);
+}
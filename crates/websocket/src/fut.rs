@@ -0,0 +1,83 @@
+//! `Future`-backed extensions to the [`cb::WebSocket`](../cb/struct.WebSocket.html) API.
+
+use std::cell::RefCell;
+
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use futures::Future;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::FileReader;
+
+use crate::cb::{WebSocket, WsMessage};
+
+impl WebSocket {
+    /// Returns a future that resolves once all data buffered by previous sends has been
+    /// handed off to the network layer, i.e. once `bufferedAmount` drains to zero.
+    ///
+    /// This is built on top of [`on_drain`](../cb/struct.WebSocket.html#method.on_drain),
+    /// so calling `flush` replaces any previously registered drain callback.
+    pub fn flush(&self) -> impl Future<Item = (), Error = ()> {
+        if self.buffered_amount() == 0 {
+            return Either::A(future::ok(()));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let sender = RefCell::new(Some(sender));
+
+        self.on_drain(move || {
+            if let Some(sender) = sender.borrow_mut().take() {
+                let _ = sender.send(());
+            }
+        });
+
+        Either::B(receiver.map_err(|_| ()))
+    }
+}
+
+impl WsMessage {
+    /// Resolves to the message's bytes.
+    ///
+    /// For [`WsMessage::Text`](../cb/enum.WsMessage.html#variant.Text) and
+    /// [`WsMessage::Bytes`](../cb/enum.WsMessage.html#variant.Bytes) this resolves
+    /// immediately, since their payload is already in Wasm memory. For
+    /// [`WsMessage::Blob`](../cb/enum.WsMessage.html#variant.Blob) it reads the blob
+    /// with a [`FileReader`](https://docs.rs/web-sys/*/web_sys/struct.FileReader.html),
+    /// which is where the actual asynchrony — and the memory copy this variant exists
+    /// to defer — happens.
+    pub fn bytes(&self) -> impl Future<Item = Vec<u8>, Error = JsValue> {
+        match self {
+            WsMessage::Text(text) => Either::A(future::ok(text.clone().into_bytes())),
+            WsMessage::Bytes(bytes) => Either::A(future::ok(bytes.to_vec())),
+            WsMessage::Blob(blob) => Either::B(read_blob(blob)),
+        }
+    }
+}
+
+fn read_blob(blob: &web_sys::Blob) -> impl Future<Item = Vec<u8>, Error = JsValue> {
+    let (sender, receiver) = oneshot::channel();
+    let sender = RefCell::new(Some(sender));
+
+    let reader = FileReader::new().expect("constructing a FileReader should not fail");
+    let reader_for_onload = reader.clone();
+    let onloadend = Closure::wrap(Box::new(move |_event: web_sys::ProgressEvent| {
+        let result = reader_for_onload.result();
+        if let Some(sender) = sender.borrow_mut().take() {
+            let _ = sender.send(result);
+        }
+    }) as Box<FnMut(web_sys::ProgressEvent)>);
+    reader.set_onloadend(Some(onloadend.as_ref().unchecked_ref()));
+    // The reader only needs to live until `onloadend` fires once; there's no owner to
+    // pin it to, so let wasm-bindgen keep it alive for us.
+    onloadend.forget();
+
+    if let Err(err) = reader.read_as_array_buffer(blob) {
+        return Either::A(future::err(err));
+    }
+
+    Either::B(receiver.then(|result| match result {
+        Ok(Ok(buffer)) => Ok(js_sys::Uint8Array::new(&buffer).to_vec()),
+        Ok(Err(err)) => Err(err),
+        Err(_canceled) => Err(JsValue::from_str("blob read was dropped before it finished")),
+    }))
+}
@@ -0,0 +1,221 @@
+//! An HMAC signing-and-verification layer on top of [`cb::WebSocket`](../cb/struct.WebSocket.html),
+//! for a peer that needs to know a frame actually came from the holder of a shared
+//! secret and wasn't altered on the way -- unlike [`crate::crypto`], the payload
+//! itself stays readable in transit; this only guards its integrity.
+//!
+//! [`Signed::connect`] takes the raw HMAC secret, imports it through `SubtleCrypto`
+//! once the socket opens (asynchronous, same as [`crate::crypto::Encrypted`]:
+//! [`send`](Signed::send) calls made before that finishes are queued and flushed in
+//! order once it does), and appends a SHA-256 HMAC tag to every outgoing frame.
+//! Incoming frames are verified the same way and delivered through [`Stream`], as
+//! [`VerifyError`](enum.VerifyError.html) if the tag doesn't match -- a wrong secret
+//! or a tampered frame both surface this way, since `SubtleCrypto.verify` doesn't
+//! distinguish the two.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use futures::sync::mpsc;
+use futures::{future, Future, Poll, Stream};
+use js_sys::Uint8Array;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use web_sys::{CryptoKey, HmacImportParams};
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+const TAG_LEN: usize = 32; // SHA-256 output size.
+
+struct Inner {
+    // `None` only in the brief window in `build_signed` between constructing this
+    // and `WebSocketBuilder::build` returning; `on_event` can't fire until `build`
+    // returns, so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    key: Option<CryptoKey>,
+    pending_outgoing: Vec<Vec<u8>>,
+    sender: mpsc::UnboundedSender<Result<Vec<u8>, VerifyError>>,
+}
+
+impl Inner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("Inner.ws is set before any event can fire")
+    }
+}
+
+/// Why an incoming frame failed HMAC verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The frame was shorter than the 32-byte tag every signed frame ends with.
+    Truncated,
+    /// `SubtleCrypto.verify` rejected the tag -- a secret mismatch or a frame
+    /// tampered with in transit both surface this way.
+    Rejected,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Truncated => write!(f, "frame is shorter than the appended HMAC tag"),
+            VerifyError::Rejected => write!(f, "HMAC verification failed (wrong secret or tampered frame)"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// An HMAC-signed [`cb::WebSocket`](../cb/struct.WebSocket.html). See the
+/// [module docs](index.html).
+pub struct Signed {
+    inner: Rc<RefCell<Inner>>,
+    receiver: mpsc::UnboundedReceiver<Result<Vec<u8>, VerifyError>>,
+}
+
+impl fmt::Debug for Signed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Signed").field("ws", &inner.ws).field("key_ready", &inner.key.is_some()).finish()
+    }
+}
+
+impl Signed {
+    /// Connects to `url`, importing `secret` as an HMAC-SHA256 key as soon as the
+    /// connection opens.
+    pub fn connect(url: &str, secret: Vec<u8>) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url), secret)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../cb/struct.WebSocketBuilder.html).
+    /// See [`connect`](#method.connect) for what `secret` is.
+    pub fn from_builder(builder: WebSocketBuilder, secret: Vec<u8>) -> Result<Self, BuildError> {
+        let (sender, receiver) = mpsc::unbounded();
+        let inner = Rc::new(RefCell::new(Inner { ws: None, key: None, pending_outgoing: Vec::new(), sender }));
+        build_signed(builder, inner, receiver, secret)
+    }
+
+    /// Signs and sends `payload`. Queued until the key finishes importing if called
+    /// before the connection has opened (or before that import resolves).
+    pub fn send(&self, payload: Vec<u8>) {
+        let key = self.inner.borrow().key.clone();
+        match key {
+            Some(key) => sign_and_send(&self.inner, key, payload),
+            None => self.inner.borrow_mut().pending_outgoing.push(payload),
+        }
+    }
+}
+
+impl Stream for Signed {
+    type Item = Result<Vec<u8>, VerifyError>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, ()> {
+        self.receiver.poll()
+    }
+}
+
+fn build_signed(
+    builder: WebSocketBuilder,
+    placeholder: Rc<RefCell<Inner>>,
+    receiver: mpsc::UnboundedReceiver<Result<Vec<u8>, VerifyError>>,
+    secret: Vec<u8>,
+) -> Result<Signed, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, &secret, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(Signed { inner: placeholder, receiver })
+}
+
+fn handle_event(inner: &Rc<RefCell<Inner>>, secret: &[u8], event: WsEvent) {
+    match event {
+        WsEvent::Open => import_key(inner, secret.to_vec()),
+        WsEvent::Message(WsMessage::Bytes(bytes)) => verify_incoming(inner, bytes.to_vec()),
+        // Text and Blob frames aren't something this layer ever sends; there's
+        // nothing sensible to verify them as.
+        _ => {}
+    }
+}
+
+fn subtle() -> web_sys::SubtleCrypto {
+    web_sys::window().expect("Signed requires a Window (browser main thread)").crypto().expect("browser exposes window.crypto").subtle()
+}
+
+fn import_key(inner: &Rc<RefCell<Inner>>, secret: Vec<u8>) {
+    let usages = js_sys::Array::of2(&"sign".into(), &"verify".into());
+    let algorithm = HmacImportParams::new("HMAC", &"SHA-256".into());
+    let promise = subtle()
+        .import_key_with_object("raw", &Uint8Array::from(secret.as_slice()), &algorithm, false, &usages)
+        .expect("importKey does not fail synchronously");
+
+    let inner = inner.clone();
+    let flushed = JsFuture::from(promise).then(move |result| {
+        if let Ok(key) = result {
+            let key: CryptoKey = key.unchecked_into();
+            let pending = {
+                let mut state = inner.borrow_mut();
+                state.key = Some(key.clone());
+                mem::take(&mut state.pending_outgoing)
+            };
+            for payload in pending {
+                sign_and_send(&inner, key.clone(), payload);
+            }
+        }
+        // The key import failing has nothing sensible to report to -- there's no
+        // caller waiting on `connect` anymore -- so a failed import just leaves
+        // `send` queuing forever, same as one that's merely still in flight.
+        future::ok::<JsValue, JsValue>(JsValue::UNDEFINED)
+    });
+    future_to_promise(flushed);
+}
+
+fn sign_and_send(inner: &Rc<RefCell<Inner>>, key: CryptoKey, mut payload: Vec<u8>) {
+    let promise = subtle().sign_with_str_and_u8_array("HMAC", &key, &mut payload).expect("signing a byte slice does not fail synchronously");
+
+    let inner = inner.clone();
+    let sent = JsFuture::from(promise).then(move |result| {
+        if let Ok(tag) = result {
+            let tag = Uint8Array::new(&tag).to_vec();
+            let mut framed = Vec::with_capacity(payload.len() + tag.len());
+            framed.extend_from_slice(&payload);
+            framed.extend_from_slice(&tag);
+            inner.borrow().ws().send_bytes(&framed);
+        }
+        future::ok::<JsValue, JsValue>(JsValue::UNDEFINED)
+    });
+    future_to_promise(sent);
+}
+
+fn verify_incoming(inner: &Rc<RefCell<Inner>>, frame: Vec<u8>) {
+    if frame.len() < TAG_LEN {
+        let _ = inner.borrow().sender.unbounded_send(Err(VerifyError::Truncated));
+        return;
+    }
+    let key = match inner.borrow().key.clone() {
+        Some(key) => key,
+        // A frame arriving before this end's own key import resolves can't be
+        // verified yet; there's nowhere to buffer it that wouldn't reorder messages
+        // relative to ones that do verify, so it's reported the same as a tampered
+        // frame rather than silently dropped.
+        None => {
+            let _ = inner.borrow().sender.unbounded_send(Err(VerifyError::Rejected));
+            return;
+        }
+    };
+    let (payload, tag) = frame.split_at(frame.len() - TAG_LEN);
+    let mut payload = payload.to_vec();
+    let mut tag = tag.to_vec();
+    let promise = subtle()
+        .verify_with_str_and_u8_array_and_u8_array("HMAC", &key, &mut tag, &mut payload)
+        .expect("verifying a byte slice does not fail synchronously");
+
+    let inner = inner.clone();
+    let delivered = JsFuture::from(promise).then(move |result| {
+        let outcome = match result {
+            Ok(valid) if valid.as_bool() == Some(true) => Ok(payload.clone()),
+            _ => Err(VerifyError::Rejected),
+        };
+        let _ = inner.borrow().sender.unbounded_send(outcome);
+        future::ok::<JsValue, JsValue>(JsValue::UNDEFINED)
+    });
+    future_to_promise(delivered);
+}
@@ -0,0 +1,90 @@
+/*!
+
+A self-reconnecting `WebSocket` client for the browser.
+
+Unlike [`web-sys`](https://crates.io/crates/web-sys)'s raw `WebSocket`, this crate's
+[`cb::WebSocket`](cb/struct.WebSocket.html) keeps itself open: if the connection drops
+it schedules a reconnect automatically, and it exposes the state of that process so
+callers can react to it (e.g. to show "reconnecting..." in a UI).
+
+Reconnect timers are scheduled through [`gloo-timers`](https://crates.io/crates/gloo-timers)
+rather than `window.setTimeout` directly, so this works the same inside a dedicated or
+shared worker as it does on the main thread. A few optional, window-specific niceties
+(see [`WebSocketBuilder::pause_when_offline`](cb/struct.WebSocketBuilder.html#method.pause_when_offline),
+[`pause_when_hidden`](cb/struct.WebSocketBuilder.html#method.pause_when_hidden), and
+[`close_on_unload`](cb/struct.WebSocketBuilder.html#method.close_on_unload)) simply have
+no effect there, since the browser APIs they build on don't exist outside a window.
+
+## Callback API
+
+```no_run
+use gloo_websocket::cb::{WebSocket, WsEvent};
+
+let ws = WebSocket::new("wss://example.com/socket", move |event| match event {
+    WsEvent::Open => {}
+    WsEvent::Message(message) => {}
+    WsEvent::Close { code, reason, was_clean } => {}
+    WsEvent::Error => {}
+    WsEvent::ProtocolMismatch { requested, negotiated } => {}
+    WsEvent::Reconnecting { attempt, delay } => {}
+    WsEvent::ReconnectFailed => {}
+})
+.unwrap();
+```
+
+*/
+#![deny(missing_docs, missing_debug_implementations)]
+
+#[cfg(feature = "futures")]
+extern crate futures_rs as futures;
+
+mod common;
+
+pub mod cb;
+
+pub mod codec;
+
+pub mod layer;
+
+#[cfg(feature = "futures")]
+pub mod fut;
+
+#[cfg(feature = "futures")]
+pub mod requester;
+
+#[cfg(feature = "futures")]
+pub mod multiplexer;
+
+#[cfg(feature = "futures")]
+pub mod reliable;
+
+#[cfg(feature = "futures")]
+pub mod dedup;
+
+#[cfg(feature = "futures")]
+pub mod chunker;
+
+#[cfg(feature = "futures")]
+pub mod ndjson;
+
+#[cfg(feature = "futures")]
+pub mod crypto;
+
+#[cfg(feature = "futures")]
+pub mod hmac;
+
+#[cfg(feature = "futures")]
+pub mod typed;
+
+#[cfg(any(
+    feature = "graphql-ws",
+    feature = "stomp",
+    feature = "mqtt",
+    feature = "phoenix",
+    feature = "action-cable",
+    feature = "signalr",
+    feature = "wamp",
+    feature = "socketio",
+    feature = "sockjs"
+))]
+pub mod protocols;
@@ -0,0 +1,353 @@
+//! A generic topic-based multiplexing layer on top of [`cb::WebSocket`](../cb/struct.WebSocket.html),
+//! for sharing one physical connection across many logical streams without committing
+//! to any particular backend protocol's own multiplexing (Phoenix's channels, WAMP's
+//! subscriptions, ...) -- this is the bring-your-own-backend version of that idea.
+//!
+//! [`Multiplexer::channel`](struct.Multiplexer.html#method.channel) hands out a
+//! [`Channel`](struct.Channel.html) scoped to one topic: call
+//! [`send`](struct.Channel.html#method.send) to frame a payload with the topic and send
+//! it, and read [`Stream`](struct.Channel.html) for payloads framed with that same
+//! topic arriving from the server. `Channel` doesn't implement futures' `Sink` trait --
+//! like every other per-topic handle in this crate, sends are synchronous (`cb::WebSocket`
+//! already queues and paces them), so there's no backpressure to model.
+//!
+//! Frames are `<topic>\u{1f}<payload>`, joined with the ASCII unit separator -- a
+//! character unlikely to show up in a topic id and, unlike a comma or colon, never
+//! legal JSON, so a JSON payload round-trips without escaping. `Topic` only needs to
+//! round-trip through [`ToString`]/[`FromStr`] to be usable here; there's no
+//! requirement that the server speak this exact framing, so this is best suited to a
+//! custom backend that's free to adopt it, not an existing one.
+//!
+//! Multiplexing is purely local bookkeeping -- there's no per-topic handshake with the
+//! server -- so every [`Channel`](struct.Channel.html) survives a reconnect
+//! automatically; there's nothing for this module to redo once the connection comes
+//! back.
+//!
+//! [`Multiplexer::open_channel`](struct.Multiplexer.html#method.open_channel) is a
+//! heavier alternative to [`channel`](struct.Multiplexer.html#method.channel), for
+//! when a topic deserves an explicit lifecycle instead of just being data a `Channel`
+//! happens to be subscribed to: it runs a tiny control-frame handshake (an `Open`
+//! frame the peer acks, and a `Close` frame either side can send) and gives the
+//! returned [`SubConnection`](struct.SubConnection.html) its own bounded inbox, so a
+//! slow consumer on one virtual connection can't let its backlog grow without bound
+//! or starve the others. Control frames are distinguished from `Channel`'s plain
+//! `<topic>\u{1f}<payload>` frames by a leading separator, so the two styles coexist
+//! freely on the same multiplexer.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::hash::Hash;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use futures::sync::mpsc;
+use futures::{Poll, Stream};
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+const SEPARATOR: char = '\u{1f}';
+
+struct ChannelEntry<Topic> {
+    id: u64,
+    topic: Topic,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+/// Where a [`SubConnection`](struct.SubConnection.html) is in its open/close
+/// lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    /// `open_channel` sent its `Open` frame; waiting on the peer's ack.
+    Opening,
+    /// The peer acked; data frames can flow.
+    Open,
+    /// Closed locally (via [`SubConnection::close`](struct.SubConnection.html#method.close))
+    /// or by the peer sending its own `Close` frame.
+    Closed,
+}
+
+struct SubEntry<Topic> {
+    id: u64,
+    topic: Topic,
+    state: Rc<RefCell<ChannelState>>,
+    on_state: Option<Rc<RefCell<dyn FnMut(ChannelState)>>>,
+    sender: mpsc::Sender<String>,
+}
+
+struct Inner<Topic> {
+    // `None` only in the brief window in `build_multiplexer` between constructing this
+    // and `WebSocketBuilder::build` returning; `on_event` can't fire until `build`
+    // returns, so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    next_channel_id: u64,
+    channels: Vec<ChannelEntry<Topic>>,
+    sub_connections: Vec<SubEntry<Topic>>,
+}
+
+impl<Topic> Inner<Topic> {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("Inner.ws is set before any event can fire")
+    }
+}
+
+/// A [`cb::WebSocket`](../cb/struct.WebSocket.html) multiplexed into topic-scoped
+/// [`Channel`](struct.Channel.html)s.
+pub struct Multiplexer<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> {
+    inner: Rc<RefCell<Inner<Topic>>>,
+}
+
+impl<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> fmt::Debug for Multiplexer<Topic> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Multiplexer")
+            .field("ws", &inner.ws)
+            .field("channels", &inner.channels.len())
+            .field("sub_connections", &inner.sub_connections.len())
+            .finish()
+    }
+}
+
+impl<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> Multiplexer<Topic> {
+    /// Connects to `url`.
+    pub fn connect(url: &str) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url))
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../cb/struct.WebSocketBuilder.html).
+    pub fn from_builder(builder: WebSocketBuilder) -> Result<Self, BuildError> {
+        let inner = Rc::new(RefCell::new(Inner { ws: None, next_channel_id: 0, channels: Vec::new(), sub_connections: Vec::new() }));
+        build_multiplexer(builder, inner)
+    }
+
+    /// Returns a handle scoped to `topic`. Calling this more than once for the same
+    /// topic is fine -- each handle gets its own copy of every payload framed with it.
+    pub fn channel(&self, topic: Topic) -> Channel<Topic> {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut inner = self.inner.borrow_mut();
+        inner.next_channel_id += 1;
+        let id = inner.next_channel_id;
+        inner.channels.push(ChannelEntry { id, topic: topic.clone(), sender });
+        drop(inner);
+        Channel { id, topic, client: self.inner.clone(), receiver }
+    }
+
+    /// Opens a [`SubConnection`](struct.SubConnection.html) scoped to `topic`: sends
+    /// an `Open` control frame and returns a handle that starts in
+    /// [`ChannelState::Opening`](enum.ChannelState.html#variant.Opening) until the
+    /// peer's ack moves it to `Open`. `capacity` bounds how many undelivered messages
+    /// the returned handle's inbox holds before new ones for this topic are dropped,
+    /// so one slow consumer can't grow without bound or affect any other topic.
+    pub fn open_channel(&self, topic: Topic, capacity: usize) -> SubConnection<Topic> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let state = Rc::new(RefCell::new(ChannelState::Opening));
+        let mut inner = self.inner.borrow_mut();
+        inner.next_channel_id += 1;
+        let id = inner.next_channel_id;
+        inner.sub_connections.push(SubEntry { id, topic: topic.clone(), state: state.clone(), on_state: None, sender });
+        inner.ws().send_text(&encode_control('O', &topic.to_string()));
+        drop(inner);
+        SubConnection { id, topic, client: self.inner.clone(), state, receiver }
+    }
+}
+
+/// A handle scoped to one topic, and the stream of payloads framed with it.
+pub struct Channel<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> {
+    id: u64,
+    topic: Topic,
+    client: Rc<RefCell<Inner<Topic>>>,
+    receiver: mpsc::UnboundedReceiver<String>,
+}
+
+impl<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> fmt::Debug for Channel<Topic> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Channel").field("topic", &self.topic.to_string()).finish()
+    }
+}
+
+impl<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> Channel<Topic> {
+    /// Frames `payload` with this channel's topic and sends it.
+    pub fn send(&self, payload: &str) {
+        let inner = self.client.borrow();
+        inner.ws().send_text(&encode_frame(&self.topic.to_string(), payload));
+    }
+}
+
+impl<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> Stream for Channel<Topic> {
+    type Item = String;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<String>, ()> {
+        self.receiver.poll()
+    }
+}
+
+impl<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> Drop for Channel<Topic> {
+    fn drop(&mut self) {
+        self.client.borrow_mut().channels.retain(|entry| entry.id != self.id);
+    }
+}
+
+/// A handle to a [`Multiplexer::open_channel`](struct.Multiplexer.html#method.open_channel)
+/// virtual connection: its own bounded inbox of payloads framed with its topic, plus
+/// the open/close lifecycle described in the [module docs](index.html).
+pub struct SubConnection<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> {
+    id: u64,
+    topic: Topic,
+    client: Rc<RefCell<Inner<Topic>>>,
+    state: Rc<RefCell<ChannelState>>,
+    receiver: mpsc::Receiver<String>,
+}
+
+impl<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> fmt::Debug for SubConnection<Topic> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SubConnection").field("topic", &self.topic.to_string()).field("state", &*self.state.borrow()).finish()
+    }
+}
+
+impl<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> SubConnection<Topic> {
+    /// Where this connection is in its open/close lifecycle.
+    pub fn state(&self) -> ChannelState {
+        *self.state.borrow()
+    }
+
+    /// Frames `payload` with this connection's topic and sends it, the same as
+    /// [`Channel::send`](struct.Channel.html#method.send) -- nothing here checks
+    /// [`state`](#method.state) first, so sending before the peer's ack (or after a
+    /// close) is a caller error the wire format doesn't catch.
+    pub fn send(&self, payload: &str) {
+        let inner = self.client.borrow();
+        inner.ws().send_text(&encode_frame(&self.topic.to_string(), payload));
+    }
+
+    /// Registers a callback which fires whenever this connection's
+    /// [`state`](#method.state) changes, whether the peer acked the open, either side
+    /// sent a `Close`, or [`close`](#method.close) was called locally. Registering a
+    /// new callback replaces the previous one.
+    pub fn on_state<F>(&self, callback: F)
+    where
+        F: FnMut(ChannelState) + 'static,
+    {
+        let mut inner = self.client.borrow_mut();
+        if let Some(entry) = inner.sub_connections.iter_mut().find(|entry| entry.id == self.id) {
+            entry.on_state = Some(Rc::new(RefCell::new(callback)));
+        }
+    }
+
+    /// Sends a `Close` control frame and moves this connection to
+    /// [`ChannelState::Closed`](enum.ChannelState.html#variant.Closed) locally; the
+    /// peer doesn't need to ack it. Idempotent -- closing an already-closed
+    /// connection just resends the frame.
+    pub fn close(&self) {
+        self.client.borrow().ws().send_text(&encode_control('C', &self.topic.to_string()));
+        set_state(&self.client, &self.topic, ChannelState::Closed);
+    }
+}
+
+impl<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> Stream for SubConnection<Topic> {
+    type Item = String;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<String>, ()> {
+        self.receiver.poll()
+    }
+}
+
+impl<Topic: Eq + Hash + Clone + ToString + FromStr + 'static> Drop for SubConnection<Topic> {
+    fn drop(&mut self) {
+        self.client.borrow_mut().sub_connections.retain(|entry| entry.id != self.id);
+    }
+}
+
+fn build_multiplexer<Topic>(builder: WebSocketBuilder, placeholder: Rc<RefCell<Inner<Topic>>>) -> Result<Multiplexer<Topic>, BuildError>
+where
+    Topic: Eq + Hash + Clone + ToString + FromStr + 'static,
+{
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(Multiplexer { inner: placeholder })
+}
+
+fn handle_event<Topic>(inner: &Rc<RefCell<Inner<Topic>>>, event: WsEvent)
+where
+    Topic: Eq + Clone + FromStr + 'static,
+{
+    if let WsEvent::Message(WsMessage::Text(text)) = event {
+        on_data(inner, &text);
+    }
+}
+
+fn on_data<Topic>(inner: &Rc<RefCell<Inner<Topic>>>, text: &str)
+where
+    Topic: Eq + Clone + FromStr,
+{
+    let mut chars = text.chars();
+    if chars.next() == Some(SEPARATOR) {
+        on_control(inner, chars.as_str());
+        return;
+    }
+    let mut parts = text.splitn(2, SEPARATOR);
+    let topic_str = match parts.next() {
+        Some(topic_str) => topic_str,
+        None => return,
+    };
+    let payload = match parts.next() {
+        Some(payload) => payload,
+        None => return,
+    };
+    let topic: Topic = match topic_str.parse() {
+        Ok(topic) => topic,
+        Err(_) => return,
+    };
+    let mut inner = inner.borrow_mut();
+    for entry in inner.channels.iter().filter(|entry| entry.topic == topic) {
+        let _ = entry.sender.unbounded_send(payload.to_string());
+    }
+    for entry in inner.sub_connections.iter_mut().filter(|entry| entry.topic == topic && *entry.state.borrow() == ChannelState::Open) {
+        let _ = entry.sender.try_send(payload.to_string());
+    }
+}
+
+/// Handles a control frame (everything after its leading separator): `O<topic>` is an
+/// open request, acked immediately with `A<topic>` since this module never rejects
+/// one; `A<topic>` moves a matching [`SubConnection`](struct.SubConnection.html) from
+/// `Opening` to `Open`; `C<topic>` moves it to `Closed`.
+fn on_control<Topic>(inner: &Rc<RefCell<Inner<Topic>>>, rest: &str)
+where
+    Topic: Eq + Clone + FromStr,
+{
+    let mut chars = rest.chars();
+    let kind = match chars.next() {
+        Some(kind) => kind,
+        None => return,
+    };
+    let topic_str = chars.as_str();
+    let topic: Topic = match topic_str.parse() {
+        Ok(topic) => topic,
+        Err(_) => return,
+    };
+    match kind {
+        'O' => inner.borrow().ws().send_text(&encode_control('A', topic_str)),
+        'A' => set_state(inner, &topic, ChannelState::Open),
+        'C' => set_state(inner, &topic, ChannelState::Closed),
+        _ => {}
+    }
+}
+
+fn set_state<Topic: Eq + Clone>(inner: &Rc<RefCell<Inner<Topic>>>, topic: &Topic, new_state: ChannelState) {
+    let inner = inner.borrow();
+    for entry in inner.sub_connections.iter().filter(|entry| &entry.topic == topic) {
+        *entry.state.borrow_mut() = new_state;
+        if let Some(on_state) = &entry.on_state {
+            (on_state.borrow_mut())(new_state);
+        }
+    }
+}
+
+fn encode_frame(topic: &str, payload: &str) -> String {
+    format!("{}{}{}", topic, SEPARATOR, payload)
+}
+
+fn encode_control(kind: char, topic: &str) -> String {
+    format!("{}{}{}", SEPARATOR, kind, topic)
+}
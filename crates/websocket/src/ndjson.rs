@@ -0,0 +1,125 @@
+//! A newline-delimited framing adapter on top of [`cb::WebSocket`](../cb/struct.WebSocket.html),
+//! for backends that pack multiple JSON documents (or any other line-oriented text)
+//! into a single WebSocket frame separated by `\n`, rather than sending one frame per
+//! document.
+//!
+//! Incoming text frames are split on `\n` and yielded one line at a time via
+//! [`Stream`]; a frame that ends mid-line (the backend flushed a partial write) has
+//! its trailing partial line held back and prepended to the next frame rather than
+//! yielded early. Outgoing, [`send_line`](struct.Ndjson.html#method.send_line) sends
+//! one line per frame and [`send_batch`](struct.Ndjson.html#method.send_batch) packs
+//! several into one, each line terminated by `\n`, mirroring what this adapter
+//! expects to receive.
+//!
+//! A line is never expected to contain an embedded `\n` -- that's what NDJSON (and
+//! line-oriented text generally) assumes -- so this doesn't escape or otherwise
+//! transform line content beyond the split itself.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use futures::sync::mpsc;
+use futures::{Poll, Stream};
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+struct Inner {
+    // `None` only in the brief window in `build_ndjson` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build`
+    // returns, so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    pending_line: String,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl Inner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("Inner.ws is set before any event can fire")
+    }
+}
+
+/// A [`cb::WebSocket`](../cb/struct.WebSocket.html) that frames text as
+/// newline-delimited lines rather than one frame per message. See the
+/// [module docs](index.html) for why.
+pub struct Ndjson {
+    inner: Rc<RefCell<Inner>>,
+    receiver: mpsc::UnboundedReceiver<String>,
+}
+
+impl fmt::Debug for Ndjson {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Ndjson").field("ws", &inner.ws).finish()
+    }
+}
+
+impl Ndjson {
+    /// Connects to `url`.
+    pub fn connect(url: &str) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url))
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../cb/struct.WebSocketBuilder.html).
+    pub fn from_builder(builder: WebSocketBuilder) -> Result<Self, BuildError> {
+        let (sender, receiver) = mpsc::unbounded();
+        let inner = Rc::new(RefCell::new(Inner { ws: None, pending_line: String::new(), sender }));
+        build_ndjson(builder, inner, receiver)
+    }
+
+    /// Sends `line`, followed by `\n`, as its own frame. `line` shouldn't contain a
+    /// `\n`; if it does, the peer will see it as more than one logical line.
+    pub fn send_line(&self, line: &str) {
+        self.inner.borrow().ws().send_text(&format!("{}\n", line));
+    }
+
+    /// Sends `lines` as a single frame, each one followed by `\n`.
+    pub fn send_batch(&self, lines: &[&str]) {
+        if lines.is_empty() {
+            return;
+        }
+        let mut batch = lines.join("\n");
+        batch.push('\n');
+        self.inner.borrow().ws().send_text(&batch);
+    }
+}
+
+impl Stream for Ndjson {
+    type Item = String;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<String>, ()> {
+        self.receiver.poll()
+    }
+}
+
+fn build_ndjson(builder: WebSocketBuilder, placeholder: Rc<RefCell<Inner>>, receiver: mpsc::UnboundedReceiver<String>) -> Result<Ndjson, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(Ndjson { inner: placeholder, receiver })
+}
+
+fn handle_event(inner: &Rc<RefCell<Inner>>, event: WsEvent) {
+    if let WsEvent::Message(WsMessage::Text(text)) = event {
+        on_data(inner, &text);
+    }
+}
+
+fn on_data(inner: &Rc<RefCell<Inner>>, text: &str) {
+    let mut state = inner.borrow_mut();
+    state.pending_line.push_str(text);
+    let split_at = state.pending_line.rfind('\n');
+    let complete = match split_at {
+        Some(split_at) => {
+            let remainder = state.pending_line[split_at + 1..].to_string();
+            let complete = state.pending_line[..split_at].to_string();
+            state.pending_line = remainder;
+            complete
+        }
+        None => return,
+    };
+    for line in complete.split('\n') {
+        let _ = state.sender.unbounded_send(line.to_string());
+    }
+}
@@ -0,0 +1,339 @@
+//! A client for [Rails ActionCable](https://guides.rubyonrails.org/action_cable_overview.html),
+//! so a Rails backend's channels can be consumed without going through ActionCable's own
+//! JavaScript consumer.
+//!
+//! Subscribe to a channel with [`ActionCableClient::channel`](struct.ActionCableClient.html#method.channel),
+//! await [`Channel::confirmed`](struct.Channel.html#method.confirmed) if you need to know
+//! the server accepted the subscription, send with [`Channel::perform`](struct.Channel.html#method.perform),
+//! and read broadcasts from the channel's [`Stream`](struct.Channel.html) of JSON payloads.
+//! `welcome` and `ping` are handled internally and never surfaced; ActionCable's ping is
+//! sent by the server on an interval purely so proxies don't time out the connection, and
+//! expects no reply, so unlike this crate's other protocol modules there's no client-side
+//! heartbeat timer here.
+//!
+//! ActionCable subscriptions are tied to the connection that created them, so every
+//! channel still held is resubscribed (and has to be reconfirmed) whenever
+//! [`cb::WebSocket`](../../cb/struct.WebSocket.html) reconnects.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Poll, Stream};
+use serde_json::Value;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+/// The server rejected a subscription.
+#[derive(Debug, Clone, Copy)]
+pub struct SubscriptionRejected;
+
+impl fmt::Display for SubscriptionRejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the server rejected the subscription")
+    }
+}
+
+impl std::error::Error for SubscriptionRejected {}
+
+/// The server closed the connection with [`disconnect`](struct.ActionCableClient.html#method.on_disconnect),
+/// rather than the underlying socket simply dropping.
+#[derive(Debug, Clone)]
+pub struct Disconnect {
+    /// The reason the server gave, if any (e.g. `"unauthorized"`).
+    pub reason: Option<String>,
+    /// Whether the server wants the client to attempt to reconnect.
+    pub reconnect: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SubscriptionState {
+    Pending,
+    Confirmed,
+    Rejected,
+}
+
+struct ChannelEntry {
+    id: u64,
+    identifier: String,
+    state: SubscriptionState,
+    waiters: Vec<oneshot::Sender<Result<(), SubscriptionRejected>>>,
+    messages: mpsc::UnboundedSender<Value>,
+}
+
+struct ClientInner {
+    // `None` only in the brief window in `build_client` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build` returns,
+    // so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    next_channel_id: u64,
+    channels: Vec<ChannelEntry>,
+    disconnect_callback: Option<Rc<RefCell<dyn FnMut(Disconnect)>>>,
+}
+
+impl ClientInner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("ClientInner.ws is set before any event can fire")
+    }
+}
+
+/// An ActionCable connection, built on a [`cb::WebSocket`](../../cb/struct.WebSocket.html).
+#[derive(Clone)]
+pub struct ActionCableClient {
+    inner: Rc<RefCell<ClientInner>>,
+}
+
+impl fmt::Debug for ActionCableClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("ActionCableClient").field("ws", &inner.ws).field("channels", &inner.channels.len()).finish()
+    }
+}
+
+impl ActionCableClient {
+    /// Connects to `url`, e.g. `wss://example.com/cable`.
+    pub fn connect(url: &str) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url))
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../../cb/struct.WebSocketBuilder.html).
+    pub fn from_builder(builder: WebSocketBuilder) -> Result<Self, BuildError> {
+        let inner = Rc::new(RefCell::new(ClientInner {
+            ws: None,
+            next_channel_id: 0,
+            channels: Vec::new(),
+            disconnect_callback: None,
+        }));
+        build_client(builder, inner)
+    }
+
+    /// Registers a callback for the server-initiated `disconnect` message, e.g. because
+    /// the session was invalidated. `reconnect` reports whether the server wants the
+    /// client to try reconnecting; this crate's own reconnect logic runs regardless, so
+    /// a `false` here is a hint to call [`WebSocket::close`](../../cb/struct.WebSocket.html#method.close)
+    /// yourself rather than something this client enforces.
+    pub fn on_disconnect<F>(&self, callback: F)
+    where
+        F: FnMut(Disconnect) + 'static,
+    {
+        self.inner.borrow_mut().disconnect_callback = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Subscribes to the channel identified by `identifier`, e.g.
+    /// `json!({"channel": "ChatChannel", "room": "1"})`. The subscribe command is sent
+    /// immediately; await [`Channel::confirmed`](struct.Channel.html#method.confirmed)
+    /// to know once the server has accepted it.
+    pub fn channel(&self, identifier: Value) -> Channel {
+        let identifier = encode_json(&identifier);
+        let (sender, receiver) = mpsc::unbounded();
+        let id = {
+            let mut inner = self.inner.borrow_mut();
+            inner.next_channel_id += 1;
+            let id = inner.next_channel_id;
+            inner.channels.push(ChannelEntry {
+                id,
+                identifier: identifier.clone(),
+                state: SubscriptionState::Pending,
+                waiters: Vec::new(),
+                messages: sender,
+            });
+            inner.ws().send_text(&encode_command("subscribe", &identifier, None));
+            id
+        };
+        Channel { id, identifier, client: self.inner.clone(), messages: receiver }
+    }
+}
+
+/// A handle to a channel subscription, and the stream of broadcasts sent to it.
+///
+/// Dropping this unsubscribes.
+pub struct Channel {
+    id: u64,
+    identifier: String,
+    client: Rc<RefCell<ClientInner>>,
+    messages: mpsc::UnboundedReceiver<Value>,
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Channel").field("identifier", &self.identifier).finish()
+    }
+}
+
+impl Channel {
+    /// Resolves once the server has confirmed (or rejected) the subscription.
+    pub fn confirmed(&self) -> impl Future<Item = (), Error = SubscriptionRejected> {
+        let mut inner = self.client.borrow_mut();
+        let entry = match find_entry_mut(&mut inner, self.id) {
+            Some(entry) => entry,
+            None => return future::Either::A(future::err(SubscriptionRejected)),
+        };
+        match entry.state {
+            SubscriptionState::Confirmed => future::Either::A(future::ok(())),
+            SubscriptionState::Rejected => future::Either::A(future::err(SubscriptionRejected)),
+            SubscriptionState::Pending => {
+                let (sender, receiver) = oneshot::channel();
+                entry.waiters.push(sender);
+                future::Either::B(receiver.then(|result| match result {
+                    Ok(result) => result,
+                    Err(_canceled) => Err(SubscriptionRejected),
+                }))
+            }
+        }
+    }
+
+    /// Performs `action` on the channel, merging `{"action": action}` into `data`
+    /// before sending it -- the convention `ActionCable::Channel#perform` expects on
+    /// the Rails side.
+    pub fn perform(&self, action: &str, data: Value) {
+        let mut data = match data {
+            Value::Object(map) => map,
+            _ => serde_json::Map::new(),
+        };
+        data.insert("action".to_string(), Value::String(action.to_string()));
+        let inner = self.client.borrow();
+        inner.ws().send_text(&encode_command("message", &self.identifier, Some(&encode_json(&Value::Object(data)))));
+    }
+}
+
+impl Stream for Channel {
+    type Item = Value;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Value>, ()> {
+        self.messages.poll()
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let mut inner = self.client.borrow_mut();
+        inner.channels.retain(|entry| entry.id != self.id);
+        inner.ws().send_text(&encode_command("unsubscribe", &self.identifier, None));
+    }
+}
+
+fn find_entry_mut(inner: &mut ClientInner, id: u64) -> Option<&mut ChannelEntry> {
+    inner.channels.iter_mut().find(|entry| entry.id == id)
+}
+
+fn build_client(builder: WebSocketBuilder, placeholder: Rc<RefCell<ClientInner>>) -> Result<ActionCableClient, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    let reopened = placeholder.clone();
+    ws.on_reopen(move |_ws| on_reopen(&reopened));
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(ActionCableClient { inner: placeholder })
+}
+
+fn handle_event(client: &Rc<RefCell<ClientInner>>, event: WsEvent) {
+    match event {
+        WsEvent::Message(WsMessage::Text(text)) => on_data(client, &text),
+        WsEvent::Close { .. } | WsEvent::Error => on_disconnect_socket(client),
+        _ => {}
+    }
+}
+
+// Runs on every close of the underlying socket, including one a reconnect follows:
+// drop every pending `confirmed()` waiter, which completes its future with
+// `SubscriptionRejected` rather than leaving it hanging forever.
+fn on_disconnect_socket(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    for entry in &mut inner.channels {
+        entry.state = SubscriptionState::Pending;
+        entry.waiters.clear();
+    }
+}
+
+// Runs after every reconnect, before queued sends flush. ActionCable forgets every
+// subscription when the connection that made it drops -- so this is where each
+// channel still held gets resubscribed. The resubscribe is fire-and-forget: it doesn't
+// resolve any `confirmed()` future left over from before the drop, which already
+// failed when the connection did.
+fn on_reopen(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    let identifiers: Vec<String> = inner.channels.iter().map(|entry| entry.identifier.clone()).collect();
+    for entry in &mut inner.channels {
+        entry.state = SubscriptionState::Pending;
+    }
+    for identifier in identifiers {
+        inner.ws().send_text(&encode_command("subscribe", &identifier, None));
+    }
+}
+
+fn on_data(client: &Rc<RefCell<ClientInner>>, text: &str) {
+    let value: Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let object = match value.as_object() {
+        Some(object) => object,
+        None => return,
+    };
+    match object.get("type").and_then(Value::as_str) {
+        Some("welcome") | Some("ping") => {}
+        Some("confirm_subscription") => on_subscription_result(client, object, true),
+        Some("reject_subscription") => on_subscription_result(client, object, false),
+        Some("disconnect") => on_disconnect(client, object),
+        _ => on_message(client, object),
+    }
+}
+
+fn on_subscription_result(client: &Rc<RefCell<ClientInner>>, object: &serde_json::Map<String, Value>, confirmed: bool) {
+    let identifier = match object.get("identifier").and_then(Value::as_str) {
+        Some(identifier) => identifier,
+        None => return,
+    };
+    let mut inner = client.borrow_mut();
+    let entry = match inner.channels.iter_mut().find(|entry| entry.identifier == identifier) {
+        Some(entry) => entry,
+        None => return,
+    };
+    entry.state = if confirmed { SubscriptionState::Confirmed } else { SubscriptionState::Rejected };
+    let waiters = std::mem::take(&mut entry.waiters);
+    for waiter in waiters {
+        let _ = waiter.send(if confirmed { Ok(()) } else { Err(SubscriptionRejected) });
+    }
+}
+
+fn on_disconnect(client: &Rc<RefCell<ClientInner>>, object: &serde_json::Map<String, Value>) {
+    let disconnect = Disconnect {
+        reason: object.get("reason").and_then(Value::as_str).map(str::to_string),
+        reconnect: object.get("reconnect").and_then(Value::as_bool).unwrap_or(true),
+    };
+    let callback = client.borrow().disconnect_callback.clone();
+    if let Some(callback) = callback {
+        (callback.borrow_mut())(disconnect);
+    }
+}
+
+fn on_message(client: &Rc<RefCell<ClientInner>>, object: &serde_json::Map<String, Value>) {
+    let identifier = match object.get("identifier").and_then(Value::as_str) {
+        Some(identifier) => identifier,
+        None => return,
+    };
+    let message = match object.get("message") {
+        Some(message) => message.clone(),
+        None => return,
+    };
+    let inner = client.borrow();
+    if let Some(entry) = inner.channels.iter().find(|entry| entry.identifier == identifier) {
+        let _ = entry.messages.unbounded_send(message);
+    }
+}
+
+fn encode_command(command: &str, identifier: &str, data: Option<&str>) -> String {
+    let mut object = serde_json::Map::new();
+    object.insert("command".to_string(), Value::String(command.to_string()));
+    object.insert("identifier".to_string(), Value::String(identifier.to_string()));
+    if let Some(data) = data {
+        object.insert("data".to_string(), Value::String(data.to_string()));
+    }
+    encode_json(&Value::Object(object))
+}
+
+fn encode_json(value: &Value) -> String {
+    // `Value` serialization never fails.
+    serde_json::to_string(value).expect("serializing a serde_json::Value cannot fail")
+}
@@ -0,0 +1,496 @@
+//! A subscriptions-over-WebSocket client speaking either of the two dialects in common
+//! use: the current [`graphql-transport-ws`](https://github.com/enisdenjo/graphql-ws/blob/master/PROTOCOL.md)
+//! protocol, or the older Apollo
+//! [`subscriptions-transport-ws`](https://github.com/apollographql/subscriptions-transport-ws/blob/master/PROTOCOL.md)
+//! protocol many production servers still speak exclusively. Pick one with
+//! [`Dialect`](enum.Dialect.html).
+//!
+//! Connection lifecycle, reconnects, and backoff are all inherited from
+//! [`cb::WebSocket`](../../cb/struct.WebSocket.html); this module only adds the
+//! connection handshake, subscribe/unsubscribe framing, and keepalive handling on top
+//! of it. Every time the underlying socket (re)connects, the handshake runs again and
+//! any subscriptions that were still active are resent, so a reconnect resumes them
+//! rather than leaving them hanging.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use futures::sync::mpsc;
+use futures::{Async, Poll, Stream};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+/// Which subscriptions-over-WebSocket wire format to speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// The current protocol, negotiated with the `graphql-transport-ws` subprotocol.
+    GraphQlTransportWs,
+    /// The older Apollo protocol, negotiated with the `graphql-ws` subprotocol.
+    ///
+    /// Despite the name clash with the modern protocol's library, this is the
+    /// *subprotocol string* `subscriptions-transport-ws` itself negotiates.
+    SubscriptionsTransportWs,
+}
+
+impl Dialect {
+    fn subprotocol(self) -> &'static str {
+        match self {
+            Dialect::GraphQlTransportWs => "graphql-transport-ws",
+            Dialect::SubscriptionsTransportWs => "graphql-ws",
+        }
+    }
+}
+
+/// A GraphQL operation to send to [`GraphQlClient::subscribe`](struct.GraphQlClient.html#method.subscribe).
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    /// The query, mutation, or subscription document.
+    pub query: String,
+    /// The operation to run, if `query` defines more than one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation_name: Option<String>,
+    /// Variables referenced by the document.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Value>,
+}
+
+impl Request {
+    /// Creates a request for a document with no variables or named operation.
+    pub fn new(query: impl Into<String>) -> Self {
+        Request {
+            query: query.into(),
+            operation_name: None,
+            variables: None,
+        }
+    }
+
+    /// Sets the variables to send with the request.
+    pub fn variables(mut self, variables: Value) -> Self {
+        self.variables = Some(variables);
+        self
+    }
+
+    /// Sets which operation in `query` to run.
+    pub fn operation_name(mut self, operation_name: impl Into<String>) -> Self {
+        self.operation_name = Some(operation_name.into());
+        self
+    }
+}
+
+/// An error surfaced by a [`Subscription`](struct.Subscription.html).
+#[derive(Debug, Clone)]
+pub enum GraphQlError {
+    /// The server sent an error message for this operation, carrying the raw GraphQL
+    /// error payload from the protocol.
+    Server(Value),
+}
+
+impl fmt::Display for GraphQlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphQlError::Server(errors) => write!(f, "the server reported an error: {}", errors),
+        }
+    }
+}
+
+impl std::error::Error for GraphQlError {}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage<'a> {
+    ConnectionInit {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    Subscribe {
+        id: &'a str,
+        payload: &'a Request,
+    },
+    Complete {
+        id: &'a str,
+    },
+    Pong {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    ConnectionAck {
+        #[serde(default)]
+        #[allow(dead_code)]
+        payload: Option<Value>,
+    },
+    Next {
+        id: String,
+        payload: Value,
+    },
+    Error {
+        id: String,
+        payload: Value,
+    },
+    Complete {
+        id: String,
+    },
+    Ping {
+        #[serde(default)]
+        payload: Option<Value>,
+    },
+    Pong {
+        #[serde(default)]
+        #[allow(dead_code)]
+        payload: Option<Value>,
+    },
+}
+
+// The Apollo `subscriptions-transport-ws` dialect: same shape of handshake and
+// per-operation lifecycle, but different message names, and a server-driven
+// keepalive (`ka`) that doesn't expect a reply, instead of `graphql-transport-ws`'s
+// client-driven ping/pong.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LegacyClientMessage<'a> {
+    ConnectionInit {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+    },
+    Start {
+        id: &'a str,
+        payload: &'a Request,
+    },
+    Stop {
+        id: &'a str,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LegacyServerMessage {
+    ConnectionAck {
+        #[serde(default)]
+        #[allow(dead_code)]
+        payload: Option<Value>,
+    },
+    ConnectionError {
+        #[serde(default)]
+        payload: Option<Value>,
+    },
+    Data {
+        id: String,
+        payload: Value,
+    },
+    Error {
+        id: String,
+        payload: Value,
+    },
+    Complete {
+        id: String,
+    },
+    Ka {
+        #[serde(default)]
+        #[allow(dead_code)]
+        payload: Option<Value>,
+    },
+}
+
+struct Active {
+    request: Request,
+    sender: mpsc::UnboundedSender<Result<Value, GraphQlError>>,
+}
+
+struct ClientInner {
+    // `None` only in the brief window in `build_client` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build` returns,
+    // so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    dialect: Dialect,
+    acked: bool,
+    connection_payload: Option<Value>,
+    next_id: u64,
+    subscriptions: HashMap<String, Active>,
+}
+
+impl ClientInner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("ClientInner.ws is set before any event can fire")
+    }
+}
+
+/// A subscriptions-over-WebSocket client, built on a [`cb::WebSocket`](../../cb/struct.WebSocket.html).
+#[derive(Clone)]
+pub struct GraphQlClient {
+    inner: Rc<RefCell<ClientInner>>,
+}
+
+impl fmt::Debug for GraphQlClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("GraphQlClient")
+            .field("ws", &inner.ws)
+            .field("dialect", &inner.dialect)
+            .field("acked", &inner.acked)
+            .field("subscriptions", &inner.subscriptions.len())
+            .finish()
+    }
+}
+
+impl GraphQlClient {
+    /// Connects to `url`, requesting the `graphql-transport-ws` subprotocol.
+    pub fn new(url: &str) -> Result<Self, BuildError> {
+        Self::new_with_dialect(url, Dialect::GraphQlTransportWs)
+    }
+
+    /// Connects to `url` speaking the given [`Dialect`](enum.Dialect.html).
+    pub fn new_with_dialect(url: &str, dialect: Dialect) -> Result<Self, BuildError> {
+        Self::from_builder_with_dialect(WebSocketBuilder::new(url), dialect)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../../cb/struct.WebSocketBuilder.html),
+    /// requesting the `graphql-transport-ws` subprotocol.
+    pub fn from_builder(builder: WebSocketBuilder) -> Result<Self, BuildError> {
+        Self::from_builder_with_dialect(builder, Dialect::GraphQlTransportWs)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../../cb/struct.WebSocketBuilder.html),
+    /// speaking the given [`Dialect`](enum.Dialect.html).
+    pub fn from_builder_with_dialect(builder: WebSocketBuilder, dialect: Dialect) -> Result<Self, BuildError> {
+        let inner = Rc::new(RefCell::new(ClientInner {
+            ws: None,
+            dialect,
+            acked: false,
+            connection_payload: None,
+            next_id: 0,
+            subscriptions: HashMap::new(),
+        }));
+
+        build_client(builder, inner)
+    }
+
+    /// Sets the payload sent as part of the connection-init message. Applies to the
+    /// next (re)connection; does not itself trigger one.
+    pub fn set_connection_payload(&self, payload: Option<Value>) {
+        self.inner.borrow_mut().connection_payload = payload;
+    }
+
+    /// Starts a subscription, sending it right away if the connection has already
+    /// completed its handshake, or as soon as it does otherwise.
+    ///
+    /// Dropping the returned [`Subscription`](struct.Subscription.html) unsubscribes
+    /// and stops forwarding further messages.
+    pub fn subscribe(&self, request: Request) -> Subscription {
+        let (sender, receiver) = mpsc::unbounded();
+
+        let id = {
+            let mut inner = self.inner.borrow_mut();
+            inner.next_id += 1;
+            let id = inner.next_id.to_string();
+            if inner.acked {
+                send_subscribe(&inner, &id, &request);
+            }
+            inner.subscriptions.insert(id.clone(), Active { request, sender });
+            id
+        };
+
+        Subscription {
+            id,
+            client: self.inner.clone(),
+            receiver,
+        }
+    }
+}
+
+/// An in-flight subscription's stream of payloads.
+///
+/// Each item is the raw payload of a data message (an object with `data` and,
+/// possibly, `errors`); a `GraphQlError` ends the stream when the server sends an
+/// error message instead.
+pub struct Subscription {
+    id: String,
+    client: Rc<RefCell<ClientInner>>,
+    receiver: mpsc::UnboundedReceiver<Result<Value, GraphQlError>>,
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Subscription").field("id", &self.id).finish()
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Value;
+    type Error = GraphQlError;
+
+    fn poll(&mut self) -> Poll<Option<Value>, GraphQlError> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(Some(Ok(value)))) => Ok(Async::Ready(Some(value))),
+            Ok(Async::Ready(Some(Err(err)))) => Err(err),
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut inner = self.client.borrow_mut();
+        if inner.subscriptions.remove(&self.id).is_some() {
+            send_stop(&inner, &self.id);
+        }
+    }
+}
+
+fn build_client(builder: WebSocketBuilder, placeholder: Rc<RefCell<ClientInner>>) -> Result<GraphQlClient, BuildError> {
+    // `on_event` needs a handle to `ClientInner` before `ClientInner.ws` can exist
+    // (it's the return value of `build`, which takes `on_event`), so `ws` is filled in
+    // immediately after `build` succeeds, before any event could plausibly have fired
+    // (wasm is single-threaded and `build` hasn't returned yet).
+    let dialect = placeholder.borrow().dialect;
+    let dispatch = placeholder.clone();
+    let ws = builder
+        .protocols(vec![dialect.subprotocol()])
+        .require_negotiated_protocol(true)
+        .build(move |event| handle_event(&dispatch, event))?;
+
+    placeholder.borrow_mut().ws = Some(ws);
+
+    Ok(GraphQlClient { inner: placeholder })
+}
+
+fn handle_event(client: &Rc<RefCell<ClientInner>>, event: WsEvent) {
+    match event {
+        WsEvent::Open => on_open(client),
+        WsEvent::Message(WsMessage::Text(text)) => on_message(client, &text),
+        WsEvent::Close { .. } | WsEvent::Error => {
+            client.borrow_mut().acked = false;
+        }
+        _ => {}
+    }
+}
+
+fn on_open(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.acked = false;
+    send_connection_init(&inner);
+}
+
+fn on_message(client: &Rc<RefCell<ClientInner>>, text: &str) {
+    let dialect = client.borrow().dialect;
+    match dialect {
+        Dialect::GraphQlTransportWs => on_modern_message(client, text),
+        Dialect::SubscriptionsTransportWs => on_legacy_message(client, text),
+    }
+}
+
+fn on_modern_message(client: &Rc<RefCell<ClientInner>>, text: &str) {
+    let message: ServerMessage = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(_) => return,
+    };
+
+    match message {
+        ServerMessage::ConnectionAck { .. } => on_ack(client),
+        ServerMessage::Next { id, payload } => on_data(client, &id, payload),
+        ServerMessage::Error { id, payload } => on_error(client, &id, payload),
+        ServerMessage::Complete { id } => on_complete(client, &id),
+        ServerMessage::Ping { payload } => {
+            let inner = client.borrow();
+            if let Ok(text) = serde_json::to_string(&ClientMessage::Pong { payload }) {
+                inner.ws().send_text(&text);
+            }
+        }
+        ServerMessage::Pong { .. } => {}
+    }
+}
+
+fn on_legacy_message(client: &Rc<RefCell<ClientInner>>, text: &str) {
+    let message: LegacyServerMessage = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(_) => return,
+    };
+
+    match message {
+        LegacyServerMessage::ConnectionAck { .. } => on_ack(client),
+        LegacyServerMessage::ConnectionError { payload } => {
+            // The legacy protocol has no per-subscription id for a connection-level
+            // error; there's nothing to route it to, so it's treated like a close.
+            let _ = payload;
+            client.borrow_mut().acked = false;
+        }
+        LegacyServerMessage::Data { id, payload } => on_data(client, &id, payload),
+        LegacyServerMessage::Error { id, payload } => on_error(client, &id, payload),
+        LegacyServerMessage::Complete { id } => on_complete(client, &id),
+        // No reply expected; the server pings to keep intermediaries from timing the
+        // connection out, the same way `cb::WebSocket`'s own heartbeat would.
+        LegacyServerMessage::Ka { .. } => {}
+    }
+}
+
+fn on_ack(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.acked = true;
+    let pending: Vec<(String, Request)> = inner
+        .subscriptions
+        .iter()
+        .map(|(id, active)| (id.clone(), active.request.clone()))
+        .collect();
+    for (id, request) in &pending {
+        send_subscribe(&inner, id, request);
+    }
+}
+
+fn on_data(client: &Rc<RefCell<ClientInner>>, id: &str, payload: Value) {
+    let inner = client.borrow();
+    if let Some(active) = inner.subscriptions.get(id) {
+        let _ = active.sender.unbounded_send(Ok(payload));
+    }
+}
+
+fn on_error(client: &Rc<RefCell<ClientInner>>, id: &str, payload: Value) {
+    if let Some(active) = client.borrow_mut().subscriptions.remove(id) {
+        let _ = active.sender.unbounded_send(Err(GraphQlError::Server(payload)));
+    }
+}
+
+fn on_complete(client: &Rc<RefCell<ClientInner>>, id: &str) {
+    // Dropping the sender ends the `Subscription`'s stream; the channel simply has no
+    // more senders once this one goes, no explicit close needed.
+    client.borrow_mut().subscriptions.remove(id);
+}
+
+fn send_connection_init(inner: &ClientInner) {
+    let payload = inner.connection_payload.clone();
+    let text = match inner.dialect {
+        Dialect::GraphQlTransportWs => serde_json::to_string(&ClientMessage::ConnectionInit { payload }),
+        Dialect::SubscriptionsTransportWs => serde_json::to_string(&LegacyClientMessage::ConnectionInit { payload }),
+    };
+    if let Ok(text) = text {
+        inner.ws().send_text(&text);
+    }
+}
+
+fn send_subscribe(inner: &ClientInner, id: &str, request: &Request) {
+    let text = match inner.dialect {
+        Dialect::GraphQlTransportWs => serde_json::to_string(&ClientMessage::Subscribe { id, payload: request }),
+        Dialect::SubscriptionsTransportWs => serde_json::to_string(&LegacyClientMessage::Start { id, payload: request }),
+    };
+    if let Ok(text) = text {
+        inner.ws().send_text(&text);
+    }
+}
+
+fn send_stop(inner: &ClientInner, id: &str) {
+    let text = match inner.dialect {
+        Dialect::GraphQlTransportWs => serde_json::to_string(&ClientMessage::Complete { id }),
+        Dialect::SubscriptionsTransportWs => serde_json::to_string(&LegacyClientMessage::Stop { id }),
+    };
+    if let Ok(text) = text {
+        inner.ws().send_text(&text);
+    }
+}
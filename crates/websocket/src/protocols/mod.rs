@@ -0,0 +1,28 @@
+//! Application-level protocols implemented on top of the reconnecting socket.
+
+#[cfg(feature = "graphql-ws")]
+pub mod graphql;
+
+#[cfg(feature = "stomp")]
+pub mod stomp;
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+
+#[cfg(feature = "phoenix")]
+pub mod phoenix;
+
+#[cfg(feature = "action-cable")]
+pub mod action_cable;
+
+#[cfg(feature = "signalr")]
+pub mod signalr;
+
+#[cfg(feature = "wamp")]
+pub mod wamp;
+
+#[cfg(feature = "socketio")]
+pub mod socketio;
+
+#[cfg(feature = "sockjs")]
+pub mod sockjs;
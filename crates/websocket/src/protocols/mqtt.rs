@@ -0,0 +1,683 @@
+//! An [MQTT 3.1.1](http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html)
+//! client speaking the `mqtt` WebSocket subprotocol, for brokers (Mosquitto, EMQX,
+//! AWS IoT, ...) that expose a WebSocket listener alongside their usual TCP one.
+//!
+//! Supports [`connect`](struct.MqttClient.html#method.connect),
+//! [`publish`](struct.MqttClient.html#method.publish), and
+//! [`subscribe`](struct.MqttClient.html#method.subscribe) at QoS 0 and 1. QoS 2 is not
+//! implemented. Published messages at QoS 1 are sent once and PUBACK is noted, but
+//! there is no resend-on-timeout queue -- a message that never gets a PUBACK back is
+//! not retried. A WebSocket binary frame may carry several whole MQTT packets back to
+//! back; a packet split across frames is not reassembled. Connection lifecycle,
+//! reconnects, and backoff are all inherited from
+//! [`cb::WebSocket`](../../cb/struct.WebSocket.html); on every (re)connect CONNECT is
+//! resent and, once CONNACK comes back, every still-active subscription is resent in
+//! one SUBSCRIBE packet so a reconnect resumes them.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::sync::mpsc;
+use futures::{Async, Poll, Stream};
+use gloo_timers::callback::Interval;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+/// The delivery guarantee requested for a publish or subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QoS {
+    /// Fire and forget; the broker does not acknowledge receipt.
+    AtMostOnce,
+    /// The broker acknowledges receipt with a PUBACK.
+    AtLeastOnce,
+}
+
+impl QoS {
+    fn as_u8(self) -> u8 {
+        match self {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(QoS::AtMostOnce),
+            1 => Some(QoS::AtLeastOnce),
+            _ => None,
+        }
+    }
+}
+
+/// A last-will message the broker publishes on `topic` if this client disconnects
+/// without sending DISCONNECT first.
+#[derive(Debug, Clone)]
+pub struct Will {
+    /// The topic to publish the will message to.
+    pub topic: String,
+    /// The will message's payload.
+    pub payload: Vec<u8>,
+    /// The QoS to publish the will message at.
+    pub qos: QoS,
+    /// Whether the broker should retain the will message.
+    pub retain: bool,
+}
+
+/// A message delivered to a [`Subscription`](struct.Subscription.html).
+#[derive(Debug, Clone)]
+pub struct Publish {
+    /// The topic the message was published to.
+    pub topic: String,
+    /// The message payload.
+    pub payload: Vec<u8>,
+    /// The QoS the message was delivered at.
+    pub qos: QoS,
+    /// Whether the broker is holding this message as the topic's retained message.
+    pub retain: bool,
+}
+
+/// Why CONNACK refused the connection, per the MQTT 3.1.1 connect return codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectError {
+    /// The broker doesn't support the requested protocol version.
+    UnacceptableProtocolVersion,
+    /// The client identifier was rejected.
+    IdentifierRejected,
+    /// The broker is unavailable.
+    ServerUnavailable,
+    /// The username or password was malformed.
+    BadUsernameOrPassword,
+    /// The client is not authorized to connect.
+    NotAuthorized,
+    /// A return code outside the range defined by the spec.
+    Unknown(u8),
+}
+
+impl ConnectError {
+    fn from_return_code(code: u8) -> Self {
+        match code {
+            1 => ConnectError::UnacceptableProtocolVersion,
+            2 => ConnectError::IdentifierRejected,
+            3 => ConnectError::ServerUnavailable,
+            4 => ConnectError::BadUsernameOrPassword,
+            5 => ConnectError::NotAuthorized,
+            other => ConnectError::Unknown(other),
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::UnacceptableProtocolVersion => write!(f, "broker rejected the protocol version"),
+            ConnectError::IdentifierRejected => write!(f, "broker rejected the client identifier"),
+            ConnectError::ServerUnavailable => write!(f, "broker unavailable"),
+            ConnectError::BadUsernameOrPassword => write!(f, "broker rejected the username or password"),
+            ConnectError::NotAuthorized => write!(f, "not authorized"),
+            ConnectError::Unknown(code) => write!(f, "unrecognized connect return code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+struct Active {
+    id: u64,
+    filter: String,
+    qos: QoS,
+    sender: mpsc::UnboundedSender<Publish>,
+}
+
+struct ClientInner {
+    // `None` only in the brief window in `build_client` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build` returns,
+    // so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    client_id: String,
+    username: Option<String>,
+    password: Option<String>,
+    clean_session: bool,
+    keep_alive: Duration,
+    will: Option<Will>,
+    connected: bool,
+    connect_error_callback: Option<Rc<RefCell<dyn FnMut(ConnectError)>>>,
+    keep_alive_timer: Option<Interval>,
+    next_packet_id: u16,
+    next_subscription_id: u64,
+    subscriptions: Vec<Active>,
+}
+
+impl ClientInner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("ClientInner.ws is set before any event can fire")
+    }
+
+    fn allocate_packet_id(&mut self) -> u16 {
+        self.next_packet_id = self.next_packet_id.wrapping_add(1);
+        if self.next_packet_id == 0 {
+            self.next_packet_id = 1;
+        }
+        self.next_packet_id
+    }
+}
+
+/// An MQTT client, built on a [`cb::WebSocket`](../../cb/struct.WebSocket.html).
+#[derive(Clone)]
+pub struct MqttClient {
+    inner: Rc<RefCell<ClientInner>>,
+}
+
+impl fmt::Debug for MqttClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("MqttClient")
+            .field("ws", &inner.ws)
+            .field("client_id", &inner.client_id)
+            .field("connected", &inner.connected)
+            .field("subscriptions", &inner.subscriptions.len())
+            .finish()
+    }
+}
+
+impl MqttClient {
+    /// Connects to `url`, identifying as `client_id`.
+    pub fn connect(url: &str, client_id: impl Into<String>) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url), client_id)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../../cb/struct.WebSocketBuilder.html).
+    pub fn from_builder(builder: WebSocketBuilder, client_id: impl Into<String>) -> Result<Self, BuildError> {
+        let inner = Rc::new(RefCell::new(ClientInner {
+            ws: None,
+            client_id: client_id.into(),
+            username: None,
+            password: None,
+            clean_session: true,
+            keep_alive: Duration::from_secs(60),
+            will: None,
+            connected: false,
+            connect_error_callback: None,
+            keep_alive_timer: None,
+            next_packet_id: 0,
+            next_subscription_id: 0,
+            subscriptions: Vec::new(),
+        }));
+        build_client(builder, inner)
+    }
+
+    /// Sets the username/password sent with CONNECT. Applies to the next
+    /// (re)connection; does not itself trigger one.
+    pub fn set_credentials(&self, username: impl Into<String>, password: impl Into<String>) {
+        let mut inner = self.inner.borrow_mut();
+        inner.username = Some(username.into());
+        inner.password = Some(password.into());
+    }
+
+    /// Sets whether CONNECT asks the broker to discard any previous session state for
+    /// this client identifier. Defaults to `true`, since without persisted session
+    /// state there's nothing to resume and the broker would otherwise queue QoS 1
+    /// messages for a session this client has no way to pick back up.
+    pub fn set_clean_session(&self, clean_session: bool) {
+        self.inner.borrow_mut().clean_session = clean_session;
+    }
+
+    /// Sets the keep-alive interval sent with CONNECT. While connected, a PINGREQ is
+    /// sent every `keep_alive` to let the broker know the client is still alive,
+    /// whether or not other packets went out in between. Applies to the next
+    /// (re)connection; does not itself trigger one.
+    pub fn set_keep_alive(&self, keep_alive: Duration) {
+        self.inner.borrow_mut().keep_alive = keep_alive;
+    }
+
+    /// Sets the will message the broker publishes if this client disconnects
+    /// ungracefully. Applies to the next (re)connection; does not itself trigger one.
+    pub fn set_will(&self, will: Will) {
+        self.inner.borrow_mut().will = Some(will);
+    }
+
+    /// Whether CONNACK has been received and accepted the connection.
+    pub fn is_connected(&self) -> bool {
+        self.inner.borrow().connected
+    }
+
+    /// Registers a callback which fires when CONNACK refuses the connection (e.g. a
+    /// rejected client identifier or bad credentials). The underlying socket still
+    /// reconnects per its usual backoff afterward, same as any other close -- the
+    /// broker is not consulted again until then.
+    pub fn on_connect_error<F>(&self, callback: F)
+    where
+        F: FnMut(ConnectError) + 'static,
+    {
+        self.inner.borrow_mut().connect_error_callback = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Publishes `payload` to `topic`.
+    pub fn publish(&self, topic: &str, payload: impl Into<Vec<u8>>, qos: QoS, retain: bool) {
+        let mut inner = self.inner.borrow_mut();
+        let packet_id = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => Some(inner.allocate_packet_id()),
+        };
+        let packet = encode_publish(false, qos, retain, packet_id, topic, &payload.into());
+        inner.ws().send_bytes(&packet);
+    }
+
+    /// Subscribes to `filter`, which may use the `+` (single level) and `#` (multiple
+    /// trailing levels) wildcards.
+    ///
+    /// Dropping the returned [`Subscription`](struct.Subscription.html) sends
+    /// UNSUBSCRIBE once no other subscription on the same filter remains.
+    pub fn subscribe(&self, filter: impl Into<String>, qos: QoS) -> Subscription {
+        let (sender, receiver) = mpsc::unbounded();
+        let filter = filter.into();
+        let id = {
+            let mut inner = self.inner.borrow_mut();
+            inner.next_subscription_id += 1;
+            let id = inner.next_subscription_id;
+            if inner.connected {
+                let packet_id = inner.allocate_packet_id();
+                inner.ws().send_bytes(&encode_subscribe(packet_id, &[(filter.clone(), qos)]));
+            }
+            inner.subscriptions.push(Active { id, filter: filter.clone(), qos, sender });
+            id
+        };
+        Subscription { id, filter, client: self.inner.clone(), receiver }
+    }
+
+    /// Sends DISCONNECT, telling the broker to drop the will message and not
+    /// reconnect. The underlying socket itself is untouched -- call
+    /// [`cb::WebSocket::close`](../../cb/struct.WebSocket.html#method.close) through
+    /// whatever owns this client if it should stop reconnecting too.
+    pub fn disconnect(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.ws().send_bytes(&encode_fixed_header(DISCONNECT, 0, &[]));
+        inner.connected = false;
+        inner.keep_alive_timer = None;
+    }
+}
+
+/// An active subscription's stream of delivered [`Publish`](struct.Publish.html)
+/// messages.
+pub struct Subscription {
+    id: u64,
+    filter: String,
+    client: Rc<RefCell<ClientInner>>,
+    receiver: mpsc::UnboundedReceiver<Publish>,
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Subscription").field("filter", &self.filter).finish()
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Publish;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Publish>, ()> {
+        self.receiver.poll()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut inner = self.client.borrow_mut();
+        inner.subscriptions.retain(|active| active.id != self.id);
+        let still_wanted = inner.subscriptions.iter().any(|active| active.filter == self.filter);
+        if !still_wanted && inner.connected {
+            let packet_id = inner.allocate_packet_id();
+            inner.ws().send_bytes(&encode_unsubscribe(packet_id, &[self.filter.clone()]));
+        }
+    }
+}
+
+fn build_client(builder: WebSocketBuilder, placeholder: Rc<RefCell<ClientInner>>) -> Result<MqttClient, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder
+        .protocols(vec!["mqtt"])
+        .require_negotiated_protocol(true)
+        .build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(MqttClient { inner: placeholder })
+}
+
+fn handle_event(client: &Rc<RefCell<ClientInner>>, event: WsEvent) {
+    match event {
+        WsEvent::Open => on_open(client),
+        WsEvent::Message(WsMessage::Bytes(bytes)) => on_data(client, &bytes),
+        WsEvent::Close { .. } | WsEvent::Error => on_disconnect(client),
+        _ => {}
+    }
+}
+
+fn on_open(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.connected = false;
+    let packet = encode_connect(&inner);
+    inner.ws().send_bytes(&packet);
+}
+
+fn on_disconnect(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.connected = false;
+    inner.keep_alive_timer = None;
+}
+
+fn on_data(client: &Rc<RefCell<ClientInner>>, bytes: &[u8]) {
+    let mut offset = 0;
+    // A single WebSocket binary message can carry more than one MQTT packet back to
+    // back, so keep decoding until the buffer is exhausted.
+    while offset < bytes.len() {
+        let packet = match decode_packet(&bytes[offset..]) {
+            Some((packet, consumed)) => {
+                offset += consumed;
+                packet
+            }
+            None => break,
+        };
+        dispatch_packet(client, packet);
+    }
+}
+
+enum Packet {
+    ConnAck { return_code: u8 },
+    Publish { message: Publish, packet_id: Option<u16> },
+    Other,
+}
+
+fn dispatch_packet(client: &Rc<RefCell<ClientInner>>, packet: Packet) {
+    match packet {
+        Packet::ConnAck { return_code } => on_connack(client, return_code),
+        Packet::Publish { message, packet_id } => on_publish(client, message, packet_id),
+        Packet::Other => {}
+    }
+}
+
+fn on_connack(client: &Rc<RefCell<ClientInner>>, return_code: u8) {
+    if return_code != 0 {
+        let callback = client.borrow().connect_error_callback.clone();
+        if let Some(callback) = callback {
+            (callback.borrow_mut())(ConnectError::from_return_code(return_code));
+        }
+        return;
+    }
+    let resubscribe: Vec<(String, QoS)>;
+    {
+        let mut inner = client.borrow_mut();
+        inner.connected = true;
+        inner.keep_alive_timer = start_keep_alive(client, inner.keep_alive);
+        resubscribe = inner.subscriptions.iter().map(|active| (active.filter.clone(), active.qos)).collect();
+    }
+    if !resubscribe.is_empty() {
+        let mut inner = client.borrow_mut();
+        let packet_id = inner.allocate_packet_id();
+        inner.ws().send_bytes(&encode_subscribe(packet_id, &resubscribe));
+    }
+}
+
+fn start_keep_alive(client: &Rc<RefCell<ClientInner>>, keep_alive: Duration) -> Option<Interval> {
+    let millis = millis_saturating(keep_alive);
+    if millis == 0 {
+        return None;
+    }
+    let weak_client = Rc::downgrade(client);
+    Some(Interval::new(millis, move || {
+        if let Some(client) = weak_client.upgrade() {
+            client.borrow().ws().send_bytes(&encode_fixed_header(PINGREQ, 0, &[]));
+        }
+    }))
+}
+
+fn on_publish(client: &Rc<RefCell<ClientInner>>, publish: Publish, packet_id: Option<u16>) {
+    let inner = client.borrow();
+    for active in &inner.subscriptions {
+        if topic_matches(&active.filter, &publish.topic) {
+            let _ = active.sender.unbounded_send(publish.clone());
+        }
+    }
+    if let Some(packet_id) = packet_id {
+        inner.ws().send_bytes(&encode_fixed_header(PUBACK, 0, &packet_id.to_be_bytes()));
+    }
+}
+
+/// Matches a received `topic` against a subscription `filter`, per the MQTT 3.1.1
+/// wildcard rules: `+` matches exactly one topic level, `#` (only legal as the final
+/// level) matches that level and everything after it, and neither wildcard matches a
+/// topic whose first level starts with `$` unless the filter itself starts with `$`.
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(f), Some(t)) if f == t => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+fn encode_connect(inner: &ClientInner) -> Vec<u8> {
+    let mut flags = 0u8;
+    if inner.clean_session {
+        flags |= 0x02;
+    }
+    let mut payload = Vec::new();
+    encode_mqtt_string(&mut payload, &inner.client_id);
+    if let Some(will) = &inner.will {
+        flags |= 0x04;
+        flags |= will.qos.as_u8() << 3;
+        if will.retain {
+            flags |= 0x20;
+        }
+        encode_mqtt_string(&mut payload, &will.topic);
+        payload.extend_from_slice(&(will.payload.len() as u16).to_be_bytes());
+        payload.extend_from_slice(&will.payload);
+    }
+    if let Some(username) = &inner.username {
+        flags |= 0x80;
+        encode_mqtt_string(&mut payload, username);
+    }
+    if let Some(password) = &inner.password {
+        flags |= 0x40;
+        encode_mqtt_string(&mut payload, password);
+    }
+
+    let mut variable_header = Vec::new();
+    encode_mqtt_string(&mut variable_header, "MQTT");
+    variable_header.push(4); // protocol level: MQTT 3.1.1
+    variable_header.push(flags);
+    let keep_alive_secs = (millis_saturating(inner.keep_alive) / 1000).min(u32::from(u16::MAX)) as u16;
+    variable_header.extend_from_slice(&keep_alive_secs.to_be_bytes());
+
+    let mut body = variable_header;
+    body.extend_from_slice(&payload);
+    encode_fixed_header(CONNECT, 0, &body)
+}
+
+fn encode_publish(dup: bool, qos: QoS, retain: bool, packet_id: Option<u16>, topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut flags = qos.as_u8() << 1;
+    if dup {
+        flags |= 0x08;
+    }
+    if retain {
+        flags |= 0x01;
+    }
+    let mut body = Vec::new();
+    encode_mqtt_string(&mut body, topic);
+    if let Some(packet_id) = packet_id {
+        body.extend_from_slice(&packet_id.to_be_bytes());
+    }
+    body.extend_from_slice(payload);
+    encode_fixed_header(PUBLISH, flags, &body)
+}
+
+fn encode_subscribe(packet_id: u16, filters: &[(String, QoS)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    for (filter, qos) in filters {
+        encode_mqtt_string(&mut body, filter);
+        body.push(qos.as_u8());
+    }
+    encode_fixed_header(SUBSCRIBE, 0x02, &body)
+}
+
+fn encode_unsubscribe(packet_id: u16, filters: &[String]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    for filter in filters {
+        encode_mqtt_string(&mut body, filter);
+    }
+    encode_fixed_header(UNSUBSCRIBE, 0x02, &body)
+}
+
+fn encode_fixed_header(packet_type: u8, flags: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![(packet_type << 4) | flags];
+    out.extend_from_slice(&encode_remaining_length(body.len()));
+    out.extend_from_slice(body);
+    out
+}
+
+fn encode_mqtt_string(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn encode_remaining_length(mut length: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    out
+}
+
+fn decode_remaining_length(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut length = 0usize;
+    let mut multiplier = 1usize;
+    for (consumed, byte) in bytes.iter().enumerate().take(4) {
+        length += (*byte & 0x7F) as usize * multiplier;
+        if byte & 0x80 == 0 {
+            return Some((length, consumed + 1));
+        }
+        multiplier *= 128;
+    }
+    None
+}
+
+fn decode_mqtt_string(bytes: &[u8]) -> Option<(String, usize)> {
+    if bytes.len() < 2 {
+        return None;
+    }
+    let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    let end = 2 + len;
+    let value = std::str::from_utf8(bytes.get(2..end)?).ok()?.to_string();
+    Some((value, end))
+}
+
+// Decodes a single packet at the start of `bytes`, returning it along with how many
+// bytes it consumed so the caller can continue decoding the rest of the buffer.
+fn decode_packet(bytes: &[u8]) -> Option<(Packet, usize)> {
+    if bytes.is_empty() {
+        return None;
+    }
+    let packet_type = bytes[0] >> 4;
+    let flags = bytes[0] & 0x0F;
+    let (remaining_length, length_bytes) = decode_remaining_length(&bytes[1..])?;
+    let header_len = 1 + length_bytes;
+    let total_len = header_len + remaining_length;
+    let body = bytes.get(header_len..total_len)?;
+
+    let packet = match packet_type {
+        CONNACK if body.len() >= 2 => Packet::ConnAck { return_code: body[1] },
+        PUBLISH => decode_publish(flags, body)?,
+        // SUBACK/UNSUBACK/PINGRESP are recognized but there's nothing to act on: this
+        // client doesn't track in-flight SUBSCRIBE/UNSUBSCRIBE packet ids, and PINGRESP
+        // just confirms the broker is alive.
+        SUBACK | UNSUBACK | PINGRESP => Packet::Other,
+        _ => Packet::Other,
+    };
+    Some((packet, total_len))
+}
+
+fn decode_publish(flags: u8, body: &[u8]) -> Option<Packet> {
+    let qos = QoS::from_u8((flags >> 1) & 0x03)?;
+    let retain = flags & 0x01 != 0;
+    let (topic, mut offset) = decode_mqtt_string(body)?;
+    let packet_id = if qos == QoS::AtMostOnce {
+        None
+    } else {
+        let packet_id = u16::from_be_bytes([*body.get(offset)?, *body.get(offset + 1)?]);
+        offset += 2;
+        Some(packet_id)
+    };
+    let payload = body.get(offset..)?.to_vec();
+    Some(Packet::Publish { message: Publish { topic, payload, qos, retain }, packet_id })
+}
+
+// Mirrors `cb::millis_saturating`: `Interval` takes a `u32` millisecond count, but
+// `Duration` doesn't fit in one, so this saturates instead of panicking on overflow.
+fn millis_saturating(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_remaining_length, encode_remaining_length};
+
+    #[test]
+    fn remaining_length_roundtrips_single_byte_values() {
+        for &length in &[0, 1, 127] {
+            let encoded = encode_remaining_length(length);
+            assert_eq!(encoded.len(), 1);
+            assert_eq!(decode_remaining_length(&encoded), Some((length, 1)));
+        }
+    }
+
+    #[test]
+    fn remaining_length_roundtrips_at_byte_boundaries() {
+        for &length in &[128, 16_383, 16_384, 2_097_151, 2_097_152, 268_435_455] {
+            let encoded = encode_remaining_length(length);
+            assert_eq!(decode_remaining_length(&encoded), Some((length, encoded.len())));
+        }
+    }
+
+    #[test]
+    fn remaining_length_reports_trailing_bytes_left_unconsumed() {
+        let mut encoded = encode_remaining_length(64);
+        encoded.push(0xAA);
+        assert_eq!(decode_remaining_length(&encoded), Some((64, 1)));
+    }
+
+    #[test]
+    fn remaining_length_rejects_a_run_with_no_terminating_byte() {
+        let unterminated = [0x80, 0x80, 0x80, 0x80];
+        assert_eq!(decode_remaining_length(&unterminated), None);
+    }
+}
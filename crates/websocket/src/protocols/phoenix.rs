@@ -0,0 +1,437 @@
+//! A client for [Phoenix Channels](https://hexdocs.pm/phoenix/channels.html), the
+//! WebSocket pub/sub layer built into the Phoenix web framework, so an Elixir/Phoenix
+//! backend can be talked to without going through Phoenix's own JavaScript client.
+//!
+//! Speaks the v2 wire format (`[join_ref, ref, topic, event, payload]` JSON arrays),
+//! which is what `Phoenix.Socket` has negotiated by default since Phoenix 1.4. Join a
+//! topic with [`PhoenixSocket::channel`](struct.PhoenixSocket.html#method.channel) and
+//! [`Channel::join`](struct.Channel.html#method.join), send with
+//! [`push`](struct.Channel.html#method.push), and read broadcasts from the channel's
+//! [`Stream`](struct.Channel.html) of [`ChannelEvent`](struct.ChannelEvent.html)s.
+//! Heartbeats are sent automatically. Connection lifecycle, reconnects, and backoff
+//! are all inherited from [`cb::WebSocket`](../../cb/struct.WebSocket.html); every
+//! channel still joined when the socket reconnects is rejoined with a fresh
+//! `join_ref`, though the rejoin itself is fire-and-forget -- it does not resolve the
+//! original [`join`](struct.Channel.html#method.join) future, which fails as soon as
+//! the connection that carried it drops.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Poll, Stream};
+use gloo_timers::callback::Interval;
+use serde_json::Value;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+/// A successful or failed reply to a [`join`](struct.Channel.html#method.join) or
+/// [`push`](struct.Channel.html#method.push).
+#[derive(Debug, Clone)]
+pub struct Reply {
+    /// `"ok"` or `"error"`, verbatim from the server.
+    pub status: String,
+    /// The reply's payload, shaped however the channel's `handle_in`/`join` callback
+    /// chose to shape it.
+    pub response: Value,
+}
+
+/// A broadcast from a channel: something other than a direct reply to a push, e.g. a
+/// server-initiated event, or Phoenix's own `"phx_close"`/`"phx_error"`.
+#[derive(Debug, Clone)]
+pub struct ChannelEvent {
+    /// The event name.
+    pub event: String,
+    /// The event's payload.
+    pub payload: Value,
+}
+
+/// The channel was left, the socket disconnected, or the channel was dropped before a
+/// reply to a [`join`](struct.Channel.html#method.join)/[`push`](struct.Channel.html#method.push)
+/// arrived.
+#[derive(Debug, Clone, Copy)]
+pub struct PushCanceled;
+
+impl fmt::Display for PushCanceled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the channel was closed before a reply arrived")
+    }
+}
+
+impl std::error::Error for PushCanceled {}
+
+struct ChannelEntry {
+    id: u64,
+    topic: String,
+    // `Some` only while actively joined on the *current* connection; cleared on
+    // disconnect and on `"phx_close"`/`"phx_error"`, independent of `want_joined`.
+    join_ref: Option<u64>,
+    // Whether the caller has asked to be joined and hasn't left; drives whether this
+    // channel is rejoined after a reconnect.
+    want_joined: bool,
+    join_payload: Value,
+    pending: HashMap<u64, oneshot::Sender<Reply>>,
+    events: mpsc::UnboundedSender<ChannelEvent>,
+}
+
+struct SocketInner {
+    // `None` only in the brief window in `build_socket` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build` returns,
+    // so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    heartbeat_interval: Duration,
+    heartbeat_timer: Option<Interval>,
+    next_ref: u64,
+    next_channel_id: u64,
+    channels: Vec<ChannelEntry>,
+}
+
+impl SocketInner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("SocketInner.ws is set before any event can fire")
+    }
+
+    fn allocate_ref(&mut self) -> u64 {
+        self.next_ref += 1;
+        self.next_ref
+    }
+}
+
+/// A connection to a Phoenix endpoint, built on a [`cb::WebSocket`](../../cb/struct.WebSocket.html).
+#[derive(Clone)]
+pub struct PhoenixSocket {
+    inner: Rc<RefCell<SocketInner>>,
+}
+
+impl fmt::Debug for PhoenixSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("PhoenixSocket").field("ws", &inner.ws).field("channels", &inner.channels.len()).finish()
+    }
+}
+
+impl PhoenixSocket {
+    /// Connects to `url`, e.g. `wss://example.com/socket/websocket?vsn=2.0.0`.
+    ///
+    /// The `vsn=2.0.0` query parameter is how a Phoenix endpoint picks the wire
+    /// format this client speaks; it is not added automatically, since
+    /// [`WebSocketBuilder::query_param`](../../cb/struct.WebSocketBuilder.html#method.query_param)
+    /// already covers it for callers using [`from_builder`](#method.from_builder),
+    /// and appending it here for plain `url`-only construction would be surprising if
+    /// `url` already carries other query parameters Phoenix expects (e.g. a token).
+    pub fn connect(url: &str) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url))
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../../cb/struct.WebSocketBuilder.html).
+    pub fn from_builder(builder: WebSocketBuilder) -> Result<Self, BuildError> {
+        let inner = Rc::new(RefCell::new(SocketInner {
+            ws: None,
+            heartbeat_interval: Duration::from_secs(30),
+            heartbeat_timer: None,
+            next_ref: 0,
+            next_channel_id: 0,
+            channels: Vec::new(),
+        }));
+        build_socket(builder, inner)
+    }
+
+    /// Sets how often a heartbeat is sent. Phoenix's default endpoint timeout expects
+    /// one at least every 30 seconds, which is also this client's default. Takes
+    /// effect on the next (re)connection.
+    pub fn set_heartbeat_interval(&self, interval: Duration) {
+        self.inner.borrow_mut().heartbeat_interval = interval;
+    }
+
+    /// Returns a handle for `topic`. Doesn't join it -- call
+    /// [`Channel::join`](struct.Channel.html#method.join) to do that.
+    pub fn channel(&self, topic: impl Into<String>) -> Channel {
+        let (sender, receiver) = mpsc::unbounded();
+        let topic = topic.into();
+        let id = {
+            let mut inner = self.inner.borrow_mut();
+            inner.next_channel_id += 1;
+            let id = inner.next_channel_id;
+            inner.channels.push(ChannelEntry {
+                id,
+                topic: topic.clone(),
+                join_ref: None,
+                want_joined: false,
+                join_payload: Value::Null,
+                pending: HashMap::new(),
+                events: sender,
+            });
+            id
+        };
+        Channel { id, topic, socket: self.inner.clone(), events: receiver }
+    }
+}
+
+/// A handle to a joined (or not-yet-joined) Phoenix channel, and the stream of
+/// [`ChannelEvent`](struct.ChannelEvent.html)s broadcast to it.
+///
+/// Dropping this leaves the channel if it was joined.
+pub struct Channel {
+    id: u64,
+    topic: String,
+    socket: Rc<RefCell<SocketInner>>,
+    events: mpsc::UnboundedReceiver<ChannelEvent>,
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Channel").field("topic", &self.topic).finish()
+    }
+}
+
+impl Channel {
+    /// Joins the channel, sending `payload` as the join parameters.
+    ///
+    /// The returned future resolves to the server's reply to the join. Calling this
+    /// again (e.g. to rejoin with different parameters) sends a fresh `phx_join`.
+    pub fn join(&self, payload: Value) -> impl Future<Item = Reply, Error = PushCanceled> {
+        let mut inner = self.socket.borrow_mut();
+        let join_ref = inner.allocate_ref();
+        let receiver = match find_entry_mut(&mut inner, self.id) {
+            Some(entry) => {
+                entry.join_ref = Some(join_ref);
+                entry.want_joined = true;
+                entry.join_payload = payload.clone();
+                track_reply(entry, join_ref)
+            }
+            None => return future::Either::A(future::err(PushCanceled)),
+        };
+        inner.ws().send_text(&encode_message(Some(join_ref), Some(join_ref), &self.topic, "phx_join", &payload));
+        future::Either::B(receiver.map_err(|_| PushCanceled))
+    }
+
+    /// Leaves the channel.
+    pub fn leave(&self) -> impl Future<Item = Reply, Error = PushCanceled> {
+        let mut inner = self.socket.borrow_mut();
+        let join_ref = match find_entry_mut(&mut inner, self.id).and_then(|entry| entry.join_ref) {
+            Some(join_ref) => join_ref,
+            None => return future::Either::A(future::err(PushCanceled)),
+        };
+        let msg_ref = inner.allocate_ref();
+        let receiver = {
+            let entry = find_entry_mut(&mut inner, self.id).expect("looked up above");
+            entry.want_joined = false;
+            track_reply(entry, msg_ref)
+        };
+        inner.ws().send_text(&encode_message(Some(join_ref), Some(msg_ref), &self.topic, "phx_leave", &Value::Null));
+        future::Either::B(receiver.map_err(|_| PushCanceled))
+    }
+
+    /// Sends `event` with `payload` on this channel. The channel must already be
+    /// joined.
+    pub fn push(&self, event: &str, payload: Value) -> impl Future<Item = Reply, Error = PushCanceled> {
+        let mut inner = self.socket.borrow_mut();
+        let join_ref = match find_entry_mut(&mut inner, self.id).and_then(|entry| entry.join_ref) {
+            Some(join_ref) => join_ref,
+            None => return future::Either::A(future::err(PushCanceled)),
+        };
+        let msg_ref = inner.allocate_ref();
+        let receiver = {
+            let entry = find_entry_mut(&mut inner, self.id).expect("looked up above");
+            track_reply(entry, msg_ref)
+        };
+        inner.ws().send_text(&encode_message(Some(join_ref), Some(msg_ref), &self.topic, event, &payload));
+        future::Either::B(receiver.map_err(|_| PushCanceled))
+    }
+}
+
+impl Stream for Channel {
+    type Item = ChannelEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<ChannelEvent>, ()> {
+        self.events.poll()
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let mut inner = self.socket.borrow_mut();
+        let join_ref = find_entry_mut(&mut inner, self.id).and_then(|entry| entry.join_ref);
+        inner.channels.retain(|entry| entry.id != self.id);
+        if let Some(join_ref) = join_ref {
+            inner.ws().send_text(&encode_message(Some(join_ref), None, &self.topic, "phx_leave", &Value::Null));
+        }
+    }
+}
+
+fn track_reply(entry: &mut ChannelEntry, msg_ref: u64) -> oneshot::Receiver<Reply> {
+    let (sender, receiver) = oneshot::channel();
+    entry.pending.insert(msg_ref, sender);
+    receiver
+}
+
+fn find_entry_mut(inner: &mut SocketInner, id: u64) -> Option<&mut ChannelEntry> {
+    inner.channels.iter_mut().find(|entry| entry.id == id)
+}
+
+fn build_socket(builder: WebSocketBuilder, placeholder: Rc<RefCell<SocketInner>>) -> Result<PhoenixSocket, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    let reopened = placeholder.clone();
+    ws.on_reopen(move |_ws| on_reopen(&reopened));
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(PhoenixSocket { inner: placeholder })
+}
+
+fn handle_event(socket: &Rc<RefCell<SocketInner>>, event: WsEvent) {
+    match event {
+        WsEvent::Open => on_open(socket),
+        WsEvent::Message(WsMessage::Text(text)) => on_message(socket, &text),
+        WsEvent::Close { .. } | WsEvent::Error => on_disconnect(socket),
+        _ => {}
+    }
+}
+
+// Runs on every connection, reconnects included: (re)starts the heartbeat. Rejoining
+// channels after a reconnect is left to `on_reopen`.
+fn on_open(socket: &Rc<RefCell<SocketInner>>) {
+    let mut inner = socket.borrow_mut();
+    inner.heartbeat_timer = start_heartbeat(socket, inner.heartbeat_interval);
+}
+
+// Runs after every reconnect (not the initial connection), before queued sends flush:
+// every channel still wanted gets rejoined here with a fresh `join_ref`. As documented on the
+// module itself, this rejoin is fire-and-forget: it doesn't resolve the future
+// returned by the original `join()` call, which already failed when the connection
+// dropped.
+fn on_reopen(socket: &Rc<RefCell<SocketInner>>) {
+    let rejoins: Vec<(String, u64, Value)>;
+    {
+        let mut inner = socket.borrow_mut();
+        inner.heartbeat_timer = start_heartbeat(socket, inner.heartbeat_interval);
+        let to_rejoin: Vec<(String, Value)> = inner
+            .channels
+            .iter()
+            .filter(|entry| entry.want_joined)
+            .map(|entry| (entry.topic.clone(), entry.join_payload.clone()))
+            .collect();
+        rejoins = to_rejoin.into_iter().map(|(topic, payload)| (topic, inner.allocate_ref(), payload)).collect();
+    }
+    let mut inner = socket.borrow_mut();
+    for (topic, join_ref, payload) in rejoins {
+        if let Some(entry) = inner.channels.iter_mut().find(|entry| entry.topic == topic) {
+            entry.join_ref = Some(join_ref);
+        }
+        inner.ws().send_text(&encode_message(Some(join_ref), Some(join_ref), &topic, "phx_join", &payload));
+    }
+}
+
+fn on_disconnect(socket: &Rc<RefCell<SocketInner>>) {
+    let mut inner = socket.borrow_mut();
+    inner.heartbeat_timer = None;
+    for entry in &mut inner.channels {
+        entry.join_ref = None;
+        // Dropping every pending sender completes its receiver with `Canceled`,
+        // which `join`/`push`/`leave` map to `PushCanceled`.
+        entry.pending.clear();
+    }
+}
+
+fn start_heartbeat(socket: &Rc<RefCell<SocketInner>>, interval: Duration) -> Option<Interval> {
+    let millis = millis_saturating(interval);
+    if millis == 0 {
+        return None;
+    }
+    let weak_socket = Rc::downgrade(socket);
+    Some(Interval::new(millis, move || {
+        if let Some(socket) = weak_socket.upgrade() {
+            let mut inner = socket.borrow_mut();
+            let msg_ref = inner.allocate_ref();
+            inner.ws().send_text(&encode_message(None, Some(msg_ref), "phoenix", "heartbeat", &Value::Object(Default::default())));
+        }
+    }))
+}
+
+fn on_message(socket: &Rc<RefCell<SocketInner>>, text: &str) {
+    let decoded = match decode_message(text) {
+        Some(decoded) => decoded,
+        None => return,
+    };
+    let mut inner = socket.borrow_mut();
+    let entry = match inner.channels.iter_mut().find(|entry| entry.topic == decoded.topic) {
+        Some(entry) => entry,
+        None => return,
+    };
+    if decoded.event == "phx_reply" {
+        if let Some(msg_ref) = decoded.msg_ref {
+            if let Some(sender) = entry.pending.remove(&msg_ref) {
+                let (status, response) = match decoded.payload {
+                    Value::Object(mut map) => {
+                        let status = map.remove("status").and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+                        let response = map.remove("response").unwrap_or(Value::Null);
+                        (status, response)
+                    }
+                    other => (String::new(), other),
+                };
+                let _ = sender.send(Reply { status, response });
+            }
+        }
+        return;
+    }
+    if decoded.event == "phx_close" || decoded.event == "phx_error" {
+        entry.join_ref = None;
+        entry.want_joined = false;
+    }
+    let _ = entry.events.unbounded_send(ChannelEvent { event: decoded.event, payload: decoded.payload });
+}
+
+struct DecodedMessage {
+    topic: String,
+    msg_ref: Option<u64>,
+    event: String,
+    payload: Value,
+}
+
+fn decode_message(text: &str) -> Option<DecodedMessage> {
+    let value: Value = serde_json::from_str(text).ok()?;
+    let elements = value.as_array()?;
+    if elements.len() != 5 {
+        return None;
+    }
+    let msg_ref = value_to_ref(&elements[1]);
+    let topic = elements[2].as_str()?.to_string();
+    let event = elements[3].as_str()?.to_string();
+    let payload = elements[4].clone();
+    Some(DecodedMessage { topic, msg_ref, event, payload })
+}
+
+fn encode_message(join_ref: Option<u64>, msg_ref: Option<u64>, topic: &str, event: &str, payload: &Value) -> String {
+    let message = Value::Array(vec![
+        ref_to_value(join_ref),
+        ref_to_value(msg_ref),
+        Value::String(topic.to_string()),
+        Value::String(event.to_string()),
+        payload.clone(),
+    ]);
+    // `Value` serialization never fails.
+    serde_json::to_string(&message).expect("serializing a serde_json::Value cannot fail")
+}
+
+fn ref_to_value(r: Option<u64>) -> Value {
+    match r {
+        Some(n) => Value::String(n.to_string()),
+        None => Value::Null,
+    }
+}
+
+fn value_to_ref(value: &Value) -> Option<u64> {
+    match value {
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+// Mirrors `cb::millis_saturating`: `Interval` takes a `u32` millisecond count, but
+// `Duration` doesn't fit in one, so this saturates instead of panicking on overflow.
+fn millis_saturating(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
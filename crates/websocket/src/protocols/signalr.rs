@@ -0,0 +1,377 @@
+//! A client for [ASP.NET SignalR](https://learn.microsoft.com/aspnet/core/signalr)'s
+//! JSON hub protocol, so a SignalR hub can be targeted without going through the
+//! `@microsoft/signalr` JavaScript client.
+//!
+//! Speaks the JSON hub protocol specifically (not MessagePack), where each message is
+//! a JSON document followed by the ASCII record separator `0x1e`. Connecting sends the
+//! [handshake request](https://github.com/dotnet/aspnetcore/blob/main/src/SignalR/docs/specs/HubProtocol.md#overview)
+//! immediately; call [`HubConnection::invoke`](struct.HubConnection.html#method.invoke)
+//! for a request/response call, [`send`](struct.HubConnection.html#method.send) to fire
+//! one without waiting for a completion, [`stream`](struct.HubConnection.html#method.stream)
+//! for a server-streaming call, and [`on`](struct.HubConnection.html#method.on) to
+//! handle invocations the hub sends without expecting a reply. Client-to-server
+//! streaming (where the client itself is the one streaming `StreamItem`s) isn't
+//! implemented -- this module only drives the directions a typical hub client needs.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, Future, Poll, Stream};
+use serde_json::Value;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+const RECORD_SEPARATOR: char = '\u{1e}';
+
+/// An error surfaced by [`invoke`](struct.HubConnection.html#method.invoke) or a
+/// [`stream`](struct.HubConnection.html#method.stream)'s [`Stream`](struct.HubStream.html).
+#[derive(Debug, Clone)]
+pub enum HubError {
+    /// The hub's completion message carried an `error` instead of a result.
+    Remote(String),
+    /// The connection dropped before a reply arrived.
+    Disconnected,
+}
+
+impl fmt::Display for HubError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HubError::Remote(message) => write!(f, "hub error: {}", message),
+            HubError::Disconnected => write!(f, "connection closed before a reply arrived"),
+        }
+    }
+}
+
+impl std::error::Error for HubError {}
+
+enum Pending {
+    Invoke(oneshot::Sender<Result<Value, HubError>>),
+    Stream(mpsc::UnboundedSender<Result<Value, HubError>>),
+}
+
+struct ClientInner {
+    // `None` only in the brief window in `build_client` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build` returns,
+    // so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    handshake_done: bool,
+    next_invocation_id: u64,
+    pending: HashMap<String, Pending>,
+    handlers: HashMap<String, Rc<RefCell<dyn FnMut(Vec<Value>)>>>,
+    handshake_error_callback: Option<Rc<RefCell<dyn FnMut(String)>>>,
+}
+
+impl ClientInner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("ClientInner.ws is set before any event can fire")
+    }
+
+    fn allocate_invocation_id(&mut self) -> String {
+        self.next_invocation_id += 1;
+        self.next_invocation_id.to_string()
+    }
+}
+
+/// A connection to a SignalR hub, built on a [`cb::WebSocket`](../../cb/struct.WebSocket.html).
+#[derive(Clone)]
+pub struct HubConnection {
+    inner: Rc<RefCell<ClientInner>>,
+}
+
+impl fmt::Debug for HubConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("HubConnection")
+            .field("ws", &inner.ws)
+            .field("handshake_done", &inner.handshake_done)
+            .finish()
+    }
+}
+
+impl HubConnection {
+    /// Connects to `url`, e.g. `wss://example.com/chathub`.
+    ///
+    /// SignalR's own negotiation step (which assigns the final URL and, for transports
+    /// other than WebSockets, picks one) isn't performed here; `url` is used as-is, as
+    /// it would be for a client that already knows it wants the WebSocket transport.
+    pub fn connect(url: &str) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url))
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../../cb/struct.WebSocketBuilder.html).
+    pub fn from_builder(builder: WebSocketBuilder) -> Result<Self, BuildError> {
+        let inner = Rc::new(RefCell::new(ClientInner {
+            ws: None,
+            handshake_done: false,
+            next_invocation_id: 0,
+            pending: HashMap::new(),
+            handlers: HashMap::new(),
+            handshake_error_callback: None,
+        }));
+        build_client(builder, inner)
+    }
+
+    /// Whether the hub protocol handshake has completed on the current connection.
+    pub fn is_connected(&self) -> bool {
+        self.inner.borrow().handshake_done
+    }
+
+    /// Registers a callback for when the server rejects the handshake, e.g. because it
+    /// doesn't support the `json` hub protocol.
+    pub fn on_handshake_error<F>(&self, callback: F)
+    where
+        F: FnMut(String) + 'static,
+    {
+        self.inner.borrow_mut().handshake_error_callback = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Registers `callback` to run whenever the hub invokes `method` without expecting
+    /// a reply. Replaces any callback already registered for `method`.
+    pub fn on<F>(&self, method: impl Into<String>, callback: F)
+    where
+        F: FnMut(Vec<Value>) + 'static,
+    {
+        self.inner.borrow_mut().handlers.insert(method.into(), Rc::new(RefCell::new(callback)));
+    }
+
+    /// Invokes `method` with `arguments`, resolving to the hub's completion result.
+    pub fn invoke(&self, method: &str, arguments: Vec<Value>) -> impl Future<Item = Value, Error = HubError> {
+        let mut inner = self.inner.borrow_mut();
+        let invocation_id = inner.allocate_invocation_id();
+        let (sender, receiver) = oneshot::channel();
+        inner.pending.insert(invocation_id.clone(), Pending::Invoke(sender));
+        inner.ws().send_text(&encode_message(&invocation_message(Some(&invocation_id), method, arguments)));
+        receiver.then(|result| match result {
+            Ok(result) => result,
+            Err(_canceled) => Err(HubError::Disconnected),
+        })
+    }
+
+    /// Invokes `method` with `arguments` without waiting for (or expecting) a reply.
+    pub fn send(&self, method: &str, arguments: Vec<Value>) {
+        let inner = self.inner.borrow();
+        inner.ws().send_text(&encode_message(&invocation_message(None, method, arguments)));
+    }
+
+    /// Starts a server-streaming invocation of `method`, returning a
+    /// [`HubStream`](struct.HubStream.html) of the items the hub streams back.
+    pub fn stream(&self, method: &str, arguments: Vec<Value>) -> HubStream {
+        let mut inner = self.inner.borrow_mut();
+        let invocation_id = inner.allocate_invocation_id();
+        let (sender, receiver) = mpsc::unbounded();
+        inner.pending.insert(invocation_id.clone(), Pending::Stream(sender));
+        let message = serde_json::json!({
+            "type": 4,
+            "invocationId": invocation_id,
+            "target": method,
+            "arguments": arguments,
+        });
+        inner.ws().send_text(&encode_message(&message));
+        HubStream { invocation_id, client: self.inner.clone(), receiver }
+    }
+}
+
+/// A server-streaming invocation's stream of items.
+///
+/// Ends normally once the hub sends its completion, or with a [`HubError`](enum.HubError.html)
+/// if the completion carries an error (or the connection drops first). Dropping this
+/// before the stream ends sends a `CancelInvocation`.
+pub struct HubStream {
+    invocation_id: String,
+    client: Rc<RefCell<ClientInner>>,
+    receiver: mpsc::UnboundedReceiver<Result<Value, HubError>>,
+}
+
+impl fmt::Debug for HubStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HubStream").field("invocation_id", &self.invocation_id).finish()
+    }
+}
+
+impl Stream for HubStream {
+    type Item = Value;
+    type Error = HubError;
+
+    fn poll(&mut self) -> Poll<Option<Value>, HubError> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(Some(Ok(item)))) => Ok(Async::Ready(Some(item))),
+            Ok(Async::Ready(Some(Err(err)))) => Err(err),
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Drop for HubStream {
+    fn drop(&mut self) {
+        let mut inner = self.client.borrow_mut();
+        if inner.pending.remove(&self.invocation_id).is_some() {
+            let message = serde_json::json!({ "type": 5, "invocationId": self.invocation_id });
+            inner.ws().send_text(&encode_message(&message));
+        }
+    }
+}
+
+fn invocation_message(invocation_id: Option<&str>, target: &str, arguments: Vec<Value>) -> Value {
+    let mut message = serde_json::Map::new();
+    message.insert("type".to_string(), Value::from(1));
+    if let Some(invocation_id) = invocation_id {
+        message.insert("invocationId".to_string(), Value::String(invocation_id.to_string()));
+    }
+    message.insert("target".to_string(), Value::String(target.to_string()));
+    message.insert("arguments".to_string(), Value::Array(arguments));
+    Value::Object(message)
+}
+
+fn build_client(builder: WebSocketBuilder, placeholder: Rc<RefCell<ClientInner>>) -> Result<HubConnection, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(HubConnection { inner: placeholder })
+}
+
+fn handle_event(client: &Rc<RefCell<ClientInner>>, event: WsEvent) {
+    match event {
+        WsEvent::Open => on_open(client),
+        WsEvent::Message(WsMessage::Text(text)) => on_data(client, &text),
+        WsEvent::Close { .. } | WsEvent::Error => on_disconnect(client),
+        _ => {}
+    }
+}
+
+fn on_open(client: &Rc<RefCell<ClientInner>>) {
+    let inner = client.borrow();
+    let handshake = serde_json::json!({ "protocol": "json", "version": 1 });
+    inner.ws().send_text(&encode_message(&handshake));
+}
+
+fn on_disconnect(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.handshake_done = false;
+    // Dropping an `invoke` sender completes its receiver with `Canceled`, which `invoke`
+    // maps to `HubError::Disconnected`. A dropped stream sender would end its
+    // `HubStream` normally, so each stream is sent that error explicitly first.
+    for (_, pending) in inner.pending.drain() {
+        if let Pending::Stream(sender) = pending {
+            let _ = sender.unbounded_send(Err(HubError::Disconnected));
+        }
+    }
+}
+
+fn on_data(client: &Rc<RefCell<ClientInner>>, text: &str) {
+    for chunk in text.split(RECORD_SEPARATOR) {
+        if chunk.is_empty() {
+            continue;
+        }
+        on_message(client, chunk);
+    }
+}
+
+fn on_message(client: &Rc<RefCell<ClientInner>>, text: &str) {
+    let value: Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let mut inner = client.borrow_mut();
+    if !inner.handshake_done {
+        inner.handshake_done = true;
+        if let Some(error) = value.get("error").and_then(Value::as_str) {
+            let callback = inner.handshake_error_callback.clone();
+            drop(inner);
+            if let Some(callback) = callback {
+                (callback.borrow_mut())(error.to_string());
+            }
+        }
+        return;
+    }
+    let object = match value.as_object() {
+        Some(object) => object,
+        None => return,
+    };
+    match object.get("type").and_then(Value::as_u64) {
+        Some(1) => dispatch_invocation(&mut inner, object),
+        Some(2) => dispatch_stream_item(&mut inner, object),
+        Some(3) => dispatch_completion(&mut inner, object),
+        Some(6) => {} // ping: no reply expected
+        Some(7) => {
+            // A `Close` message; the underlying socket is about to close too, but
+            // handle it here rather than waiting for `WsEvent::Close` so pending
+            // invocations don't wait out a full close handshake before failing.
+            inner.handshake_done = false;
+            for (_, pending) in inner.pending.drain() {
+                if let Pending::Stream(sender) = pending {
+                    let _ = sender.unbounded_send(Err(HubError::Disconnected));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn dispatch_invocation(inner: &mut ClientInner, object: &serde_json::Map<String, Value>) {
+    // An invocation with no `invocationId` is a notification; one that carries one
+    // expects a completion this client doesn't send, since every `on` handler is
+    // registered as fire-and-forget. Handling that properly would mean threading a
+    // return value back out of an arbitrary `FnMut`, which isn't worth it for the
+    // notification-style handlers hub methods overwhelmingly use in practice.
+    let target = match object.get("target").and_then(Value::as_str) {
+        Some(target) => target,
+        None => return,
+    };
+    let arguments = match object.get("arguments").and_then(Value::as_array) {
+        Some(arguments) => arguments.clone(),
+        None => Vec::new(),
+    };
+    let callback = inner.handlers.get(target).cloned();
+    if let Some(callback) = callback {
+        (callback.borrow_mut())(arguments);
+    }
+}
+
+fn dispatch_stream_item(inner: &mut ClientInner, object: &serde_json::Map<String, Value>) {
+    let invocation_id = match object.get("invocationId").and_then(Value::as_str) {
+        Some(invocation_id) => invocation_id,
+        None => return,
+    };
+    let item = object.get("item").cloned().unwrap_or(Value::Null);
+    if let Some(Pending::Stream(sender)) = inner.pending.get(invocation_id) {
+        let _ = sender.unbounded_send(Ok(item));
+    }
+}
+
+fn dispatch_completion(inner: &mut ClientInner, object: &serde_json::Map<String, Value>) {
+    let invocation_id = match object.get("invocationId").and_then(Value::as_str) {
+        Some(invocation_id) => invocation_id,
+        None => return,
+    };
+    let pending = match inner.pending.remove(invocation_id) {
+        Some(pending) => pending,
+        None => return,
+    };
+    let result = match object.get("error").and_then(Value::as_str) {
+        Some(error) => Err(HubError::Remote(error.to_string())),
+        None => Ok(object.get("result").cloned().unwrap_or(Value::Null)),
+    };
+    match pending {
+        Pending::Invoke(sender) => {
+            let _ = sender.send(result);
+        }
+        // A completion ends the stream; the sender is simply dropped, closing the
+        // `HubStream` (with an error if `result` is one).
+        Pending::Stream(sender) => {
+            if let Err(error) = result {
+                let _ = sender.unbounded_send(Err(error));
+            }
+        }
+    }
+}
+
+fn encode_message(value: &Value) -> String {
+    // `Value` serialization never fails.
+    let mut text = serde_json::to_string(value).expect("serializing a serde_json::Value cannot fail");
+    text.push(RECORD_SEPARATOR);
+    text
+}
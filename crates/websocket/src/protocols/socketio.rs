@@ -0,0 +1,500 @@
+//! A client for the [Socket.IO](https://socket.io/) wire protocol, so a Socket.IO server
+//! can be talked to without going through its own JavaScript client.
+//!
+//! This speaks the Engine.IO v4 packet framing and Socket.IO v4 packet format used by
+//! Socket.IO server versions 3 and up, which is what's deployed almost everywhere today.
+//! It connects straight over `wss://`, skipping the HTTP long-polling handshake the
+//! reference client performs before upgrading to a websocket -- most servers accept a
+//! direct websocket connection (and all of them do when configured with
+//! `transports: ["websocket"]`), so the polling round trip isn't something this crate
+//! needs to reproduce. Join a namespace with
+//! [`SocketIoClient::namespace`](struct.SocketIoClient.html#method.namespace), send with
+//! [`Namespace::emit`](struct.Namespace.html#method.emit) or
+//! [`emit_with_ack`](struct.Namespace.html#method.emit_with_ack), and read broadcasts
+//! from the namespace's [`Stream`](struct.Namespace.html) of [`Event`](struct.Event.html)s.
+//!
+//! This module has no use for
+//! [`WebSocket::on_reopen`](../../cb/struct.WebSocket.html#method.on_reopen): Engine.IO's
+//! handshake rides over the message stream itself rather than the socket's `Open` event,
+//! so a fresh `OPEN` packet naturally arrives every time the connection reconnects, and
+//! that's also where every namespace still held gets its `CONNECT` packet resent (fire
+//! and forget, same as this crate's other re-join logic).
+//!
+//! Binary packets, and the server asking the client to acknowledge an event (rather than
+//! the other way around), aren't implemented -- this module covers emitting events,
+//! optionally with an ack callback, and receiving events, which is what consuming an
+//! existing Socket.IO backend from Rust needs.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Poll, Stream};
+use gloo_timers::callback::Interval;
+use serde_json::Value;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+const EIO_OPEN: u8 = b'0';
+const EIO_CLOSE: u8 = b'1';
+const EIO_MESSAGE: u8 = b'4';
+
+const SIO_CONNECT: u8 = b'0';
+const SIO_EVENT: u8 = b'2';
+const SIO_ACK: u8 = b'3';
+const SIO_ERROR: u8 = b'4';
+const SIO_DISCONNECT: u8 = b'1';
+
+/// An event broadcast to a namespace.
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// The event's name, e.g. `"chat message"`.
+    pub name: String,
+    /// The event's arguments.
+    pub args: Vec<Value>,
+}
+
+/// An error from [`Namespace::connected`](struct.Namespace.html#method.connected).
+#[derive(Debug, Clone)]
+pub enum ConnectError {
+    /// The server replied to `CONNECT` with an `ERROR` packet, e.g. because server-side
+    /// namespace middleware refused the connection. Carries whatever data the server
+    /// attached to the error.
+    Rejected(Value),
+    /// The connection dropped before a reply arrived.
+    Disconnected,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::Rejected(data) => write!(f, "namespace connect rejected: {}", data),
+            ConnectError::Disconnected => write!(f, "connection closed before a reply arrived"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// An [`emit_with_ack`](struct.Namespace.html#method.emit_with_ack) never got a reply
+/// before the connection dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct AckCanceled;
+
+impl fmt::Display for AckCanceled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "connection closed before an acknowledgement arrived")
+    }
+}
+
+impl std::error::Error for AckCanceled {}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ConnectState {
+    Pending,
+    Connected,
+    Rejected,
+}
+
+struct NamespaceEntry {
+    id: u64,
+    path: String,
+    state: ConnectState,
+    rejection: Option<Value>,
+    connect_waiters: Vec<oneshot::Sender<Result<(), ConnectError>>>,
+    pending_acks: HashMap<u64, oneshot::Sender<Vec<Value>>>,
+    events: mpsc::UnboundedSender<Event>,
+}
+
+struct ClientInner {
+    // `None` only in the brief window in `build_client` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build` returns,
+    // so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    ping_timer: Option<Interval>,
+    next_namespace_id: u64,
+    next_ack_id: u64,
+    namespaces: Vec<NamespaceEntry>,
+}
+
+impl ClientInner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("ClientInner.ws is set before any event can fire")
+    }
+
+    fn allocate_ack_id(&mut self) -> u64 {
+        self.next_ack_id += 1;
+        self.next_ack_id
+    }
+}
+
+/// A Socket.IO connection, built on a [`cb::WebSocket`](../../cb/struct.WebSocket.html).
+#[derive(Clone)]
+pub struct SocketIoClient {
+    inner: Rc<RefCell<ClientInner>>,
+}
+
+impl fmt::Debug for SocketIoClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("SocketIoClient").field("ws", &inner.ws).field("namespaces", &inner.namespaces.len()).finish()
+    }
+}
+
+impl SocketIoClient {
+    /// Connects to `url`, e.g. `wss://example.com/socket.io/?EIO=4&transport=websocket`.
+    pub fn connect(url: &str) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url))
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../../cb/struct.WebSocketBuilder.html).
+    pub fn from_builder(builder: WebSocketBuilder) -> Result<Self, BuildError> {
+        let inner = Rc::new(RefCell::new(ClientInner {
+            ws: None,
+            ping_timer: None,
+            next_namespace_id: 0,
+            next_ack_id: 0,
+            namespaces: Vec::new(),
+        }));
+        build_client(builder, inner)
+    }
+
+    /// Joins the namespace at `path`, e.g. `"/"` or `"/chat"`. The `CONNECT` packet is
+    /// sent immediately; await [`Namespace::connected`](struct.Namespace.html#method.connected)
+    /// to know once the server has accepted it.
+    pub fn namespace(&self, path: impl Into<String>) -> Namespace {
+        let path = path.into();
+        let (sender, receiver) = mpsc::unbounded();
+        let id;
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.next_namespace_id += 1;
+            id = inner.next_namespace_id;
+            inner.namespaces.push(NamespaceEntry {
+                id,
+                path: path.clone(),
+                state: ConnectState::Pending,
+                rejection: None,
+                connect_waiters: Vec::new(),
+                pending_acks: HashMap::new(),
+                events: sender,
+            });
+            inner.ws().send_text(&encode_sio_connect(&path));
+        }
+        Namespace { id, path, client: self.inner.clone(), events: receiver }
+    }
+}
+
+/// A handle to a namespace connection, and the stream of events broadcast to it.
+///
+/// Dropping this leaves the namespace (sending `DISCONNECT`) if it was still connected.
+pub struct Namespace {
+    id: u64,
+    path: String,
+    client: Rc<RefCell<ClientInner>>,
+    events: mpsc::UnboundedReceiver<Event>,
+}
+
+impl fmt::Debug for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Namespace").field("path", &self.path).finish()
+    }
+}
+
+impl Namespace {
+    /// Resolves once the server has accepted (or rejected) this namespace connection.
+    pub fn connected(&self) -> impl Future<Item = (), Error = ConnectError> {
+        let mut inner = self.client.borrow_mut();
+        let entry = match inner.namespaces.iter_mut().find(|entry| entry.id == self.id) {
+            Some(entry) => entry,
+            None => return future::Either::A(future::err(ConnectError::Disconnected)),
+        };
+        match entry.state {
+            ConnectState::Connected => future::Either::A(future::ok(())),
+            ConnectState::Rejected => {
+                future::Either::A(future::err(ConnectError::Rejected(entry.rejection.clone().unwrap_or(Value::Null))))
+            }
+            ConnectState::Pending => {
+                let (sender, receiver) = oneshot::channel();
+                entry.connect_waiters.push(sender);
+                future::Either::B(receiver.then(|result| match result {
+                    Ok(result) => result,
+                    Err(_canceled) => Err(ConnectError::Disconnected),
+                }))
+            }
+        }
+    }
+
+    /// Emits `event` to this namespace, without requesting an acknowledgement.
+    pub fn emit(&self, event: &str, args: Vec<Value>) {
+        let inner = self.client.borrow();
+        inner.ws().send_text(&encode_sio_event(&self.path, None, event, &args));
+    }
+
+    /// Emits `event`, resolving to whatever arguments the server's acknowledgement
+    /// callback replies with.
+    pub fn emit_with_ack(&self, event: &str, args: Vec<Value>) -> impl Future<Item = Vec<Value>, Error = AckCanceled> {
+        let mut inner = self.client.borrow_mut();
+        let ack_id = inner.allocate_ack_id();
+        let (sender, receiver) = oneshot::channel();
+        if let Some(entry) = inner.namespaces.iter_mut().find(|entry| entry.id == self.id) {
+            entry.pending_acks.insert(ack_id, sender);
+        }
+        inner.ws().send_text(&encode_sio_event(&self.path, Some(ack_id), event, &args));
+        receiver.map_err(|_canceled| AckCanceled)
+    }
+}
+
+impl Stream for Namespace {
+    type Item = Event;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Event>, ()> {
+        self.events.poll()
+    }
+}
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        let mut inner = self.client.borrow_mut();
+        let was_connected =
+            inner.namespaces.iter().any(|entry| entry.id == self.id && entry.state == ConnectState::Connected);
+        inner.namespaces.retain(|entry| entry.id != self.id);
+        if was_connected {
+            inner.ws().send_text(&encode_sio_disconnect(&self.path));
+        }
+    }
+}
+
+fn build_client(builder: WebSocketBuilder, placeholder: Rc<RefCell<ClientInner>>) -> Result<SocketIoClient, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(SocketIoClient { inner: placeholder })
+}
+
+fn handle_event(client: &Rc<RefCell<ClientInner>>, event: WsEvent) {
+    match event {
+        WsEvent::Message(WsMessage::Text(text)) => on_data(client, &text),
+        WsEvent::Close { .. } | WsEvent::Error => on_disconnect(client),
+        _ => {}
+    }
+}
+
+// Runs on every close of the underlying socket, reconnects included (see the module
+// doc for why this protocol doesn't need `on_reopen`). Reset every namespace to `Pending` and drop whatever was waiting on
+// it, which completes `connected()`/`emit_with_ack()` futures rather than leaving them
+// hanging until a reconnect's fresh `OPEN` packet arrives.
+fn on_disconnect(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.ping_timer = None;
+    for entry in &mut inner.namespaces {
+        entry.state = ConnectState::Pending;
+        entry.connect_waiters.clear();
+        entry.pending_acks.clear();
+    }
+}
+
+fn on_data(client: &Rc<RefCell<ClientInner>>, text: &str) {
+    let mut chars = text.chars();
+    let packet_type = match chars.next() {
+        Some(c) => c as u8,
+        None => return,
+    };
+    let rest = chars.as_str();
+    match packet_type {
+        EIO_OPEN => on_eio_open(client, rest),
+        EIO_MESSAGE => on_eio_message(client, rest),
+        EIO_CLOSE => on_disconnect(client),
+        _ => {}
+    }
+}
+
+// The Engine.IO handshake. This arrives on every connection, including reconnects (a
+// reconnect gets a brand new Engine.IO session, with a new `sid`), which is why this is
+// also where every namespace still held gets (re)joined.
+fn on_eio_open(client: &Rc<RefCell<ClientInner>>, json: &str) {
+    let value: Value = match serde_json::from_str(json) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let ping_interval = value.get("pingInterval").and_then(Value::as_u64).map(Duration::from_millis);
+    let paths: Vec<String>;
+    {
+        let mut inner = client.borrow_mut();
+        inner.ping_timer = ping_interval.and_then(|interval| start_heartbeat(client, interval));
+        for entry in &mut inner.namespaces {
+            entry.state = ConnectState::Pending;
+            entry.rejection = None;
+        }
+        paths = inner.namespaces.iter().map(|entry| entry.path.clone()).collect();
+    }
+    let inner = client.borrow();
+    for path in &paths {
+        inner.ws().send_text(&encode_sio_connect(path));
+    }
+}
+
+// Engine.IO v4's heartbeat is client-initiated: the client pings on an interval taken
+// from the handshake, and the server pongs back. There's no watchdog on missing pongs
+// here, same as this crate's other protocol modules' heartbeats.
+fn start_heartbeat(client: &Rc<RefCell<ClientInner>>, interval: Duration) -> Option<Interval> {
+    let millis = millis_saturating(interval);
+    if millis == 0 {
+        return None;
+    }
+    let weak_client = Rc::downgrade(client);
+    Some(Interval::new(millis, move || {
+        if let Some(client) = weak_client.upgrade() {
+            client.borrow().ws().send_text("2");
+        }
+    }))
+}
+
+fn on_eio_message(client: &Rc<RefCell<ClientInner>>, payload: &str) {
+    let packet = match decode_sio_packet(payload) {
+        Some(packet) => packet,
+        None => return,
+    };
+    match packet.sio_type {
+        SIO_CONNECT => on_sio_connect(client, &packet),
+        SIO_DISCONNECT => on_sio_namespace_disconnect(client, &packet),
+        SIO_EVENT => on_sio_event(client, &packet),
+        SIO_ACK => on_sio_ack(client, &packet),
+        SIO_ERROR => on_sio_error(client, &packet),
+        _ => {}
+    }
+}
+
+fn on_sio_connect(client: &Rc<RefCell<ClientInner>>, packet: &SioPacket) {
+    let mut inner = client.borrow_mut();
+    let mut waiters = Vec::new();
+    for entry in inner.namespaces.iter_mut().filter(|entry| entry.path == packet.namespace) {
+        entry.state = ConnectState::Connected;
+        waiters.append(&mut entry.connect_waiters);
+    }
+    for waiter in waiters {
+        let _ = waiter.send(Ok(()));
+    }
+}
+
+fn on_sio_error(client: &Rc<RefCell<ClientInner>>, packet: &SioPacket) {
+    let mut inner = client.borrow_mut();
+    let mut waiters = Vec::new();
+    for entry in inner.namespaces.iter_mut().filter(|entry| entry.path == packet.namespace) {
+        entry.state = ConnectState::Rejected;
+        entry.rejection = Some(packet.data.clone());
+        waiters.append(&mut entry.connect_waiters);
+    }
+    for waiter in waiters {
+        let _ = waiter.send(Err(ConnectError::Rejected(packet.data.clone())));
+    }
+}
+
+// The server can send `DISCONNECT` for a namespace without the underlying socket
+// dropping, e.g. a server-side `socket.disconnect()` call. Unlike a dropped connection,
+// there's no fresh `OPEN` packet coming to trigger a rejoin, so a namespace kicked
+// this way just goes back to `Pending` and stays
+// there until the caller notices (e.g. via a `connected()` that never resolves) and
+// makes a new `Namespace`.
+fn on_sio_namespace_disconnect(client: &Rc<RefCell<ClientInner>>, packet: &SioPacket) {
+    let mut inner = client.borrow_mut();
+    for entry in inner.namespaces.iter_mut().filter(|entry| entry.path == packet.namespace) {
+        entry.state = ConnectState::Pending;
+    }
+}
+
+fn on_sio_event(client: &Rc<RefCell<ClientInner>>, packet: &SioPacket) {
+    let array = match packet.data.as_array() {
+        Some(array) if !array.is_empty() => array,
+        _ => return,
+    };
+    let name = match array[0].as_str() {
+        Some(name) => name.to_string(),
+        None => return,
+    };
+    let args = array[1..].to_vec();
+    let inner = client.borrow();
+    for entry in inner.namespaces.iter().filter(|entry| entry.path == packet.namespace) {
+        let _ = entry.events.unbounded_send(Event { name: name.clone(), args: args.clone() });
+    }
+}
+
+fn on_sio_ack(client: &Rc<RefCell<ClientInner>>, packet: &SioPacket) {
+    let ack_id = match packet.ack_id {
+        Some(ack_id) => ack_id,
+        None => return,
+    };
+    let args = packet.data.as_array().cloned().unwrap_or_default();
+    let mut inner = client.borrow_mut();
+    for entry in inner.namespaces.iter_mut() {
+        if let Some(sender) = entry.pending_acks.remove(&ack_id) {
+            let _ = sender.send(args);
+            return;
+        }
+    }
+}
+
+struct SioPacket {
+    sio_type: u8,
+    namespace: String,
+    ack_id: Option<u64>,
+    data: Value,
+}
+
+fn decode_sio_packet(text: &str) -> Option<SioPacket> {
+    let mut chars = text.chars();
+    let sio_type = chars.next()? as u8;
+    let rest = chars.as_str();
+    let (namespace, rest) = if rest.starts_with('/') {
+        match rest.find(',') {
+            Some(idx) => (rest[..idx].to_string(), &rest[idx + 1..]),
+            None => (rest.to_string(), ""),
+        }
+    } else {
+        ("/".to_string(), rest)
+    };
+    let digit_count = rest.chars().take_while(char::is_ascii_digit).count();
+    let (ack_id, rest) = if digit_count > 0 {
+        (rest[..digit_count].parse().ok(), &rest[digit_count..])
+    } else {
+        (None, rest)
+    };
+    let data = if rest.is_empty() { Value::Null } else { serde_json::from_str(rest).ok()? };
+    Some(SioPacket { sio_type, namespace, ack_id, data })
+}
+
+fn namespace_prefix(path: &str) -> String {
+    if path == "/" {
+        String::new()
+    } else {
+        format!("{},", path)
+    }
+}
+
+fn encode_sio_connect(path: &str) -> String {
+    format!("{}{}{}", EIO_MESSAGE as char, SIO_CONNECT as char, namespace_prefix(path))
+}
+
+fn encode_sio_disconnect(path: &str) -> String {
+    format!("{}{}{}", EIO_MESSAGE as char, SIO_DISCONNECT as char, namespace_prefix(path))
+}
+
+fn encode_sio_event(path: &str, ack_id: Option<u64>, event: &str, args: &[Value]) -> String {
+    let mut array = Vec::with_capacity(args.len() + 1);
+    array.push(Value::String(event.to_string()));
+    array.extend_from_slice(args);
+    let ack = ack_id.map(|id| id.to_string()).unwrap_or_default();
+    // `Value` serialization never fails.
+    let payload = serde_json::to_string(&Value::Array(array)).expect("serializing a serde_json::Value cannot fail");
+    format!("{}{}{}{}{}", EIO_MESSAGE as char, SIO_EVENT as char, namespace_prefix(path), ack, payload)
+}
+
+// Mirrors `cb::millis_saturating`: `Interval` takes a `u32` millisecond count, but
+// `Duration` doesn't fit in one, so this saturates instead of panicking on overflow.
+fn millis_saturating(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
@@ -0,0 +1,259 @@
+//! A client for the [SockJS](https://github.com/sockjs/sockjs-protocol) websocket
+//! transport's frame format, so a SockJS server can be talked to without its own
+//! JavaScript client. This only implements the websocket transport -- SockJS's other
+//! transports (XHR streaming, XHR polling, JSONP, ...) exist purely as fallbacks for
+//! browsers without websocket support, which isn't a concern here.
+//!
+//! Connect with [`SockJsClient::connect`](struct.SockJsClient.html#method.connect),
+//! await [`opened`](struct.SockJsClient.html#method.opened) if you need to know the
+//! session handshake (SockJS's `o` frame) completed, send with
+//! [`send`](struct.SockJsClient.html#method.send), and read frames from
+//! [`messages`](struct.SockJsClient.html#method.messages). SockJS's heartbeat (`h`
+//! frames, sent by the server on an interval) needs no reply and is swallowed
+//! internally.
+//!
+//! A SockJS session doesn't survive the underlying connection dropping, so -- like this
+//! crate's [`socketio`](../socketio/index.html) module -- this one has no use for
+//! [`WebSocket::on_reopen`](../../cb/struct.WebSocket.html#method.on_reopen): the
+//! server's handshake rides over the message stream itself, so a fresh `o` frame
+//! naturally arrives after every reconnect.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Poll, Stream};
+use serde_json::Value;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+/// The server closed the session with a SockJS close frame (`c[code, reason]`).
+#[derive(Debug, Clone)]
+pub struct CloseFrame {
+    /// The close code, e.g. `3000` for "Go away!".
+    pub code: u32,
+    /// The human-readable reason the server gave.
+    pub reason: String,
+}
+
+/// [`opened`](struct.SockJsClient.html#method.opened) never saw an `o` frame before the
+/// connection dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct NotOpened;
+
+impl fmt::Display for NotOpened {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "connection closed before the SockJS session opened")
+    }
+}
+
+impl std::error::Error for NotOpened {}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SessionState {
+    Pending,
+    Open,
+}
+
+struct ClientInner {
+    // `None` only in the brief window in `build_client` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build` returns,
+    // so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    state: SessionState,
+    open_waiters: Vec<oneshot::Sender<Result<(), NotOpened>>>,
+    messages: Option<mpsc::UnboundedSender<String>>,
+    close_callback: Option<Rc<RefCell<dyn FnMut(CloseFrame)>>>,
+}
+
+impl ClientInner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("ClientInner.ws is set before any event can fire")
+    }
+}
+
+/// A SockJS connection, built on a [`cb::WebSocket`](../../cb/struct.WebSocket.html).
+#[derive(Clone)]
+pub struct SockJsClient {
+    inner: Rc<RefCell<ClientInner>>,
+}
+
+impl fmt::Debug for SockJsClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("SockJsClient").field("ws", &inner.ws).finish()
+    }
+}
+
+impl SockJsClient {
+    /// Connects to `url`, e.g. `wss://example.com/echo/websocket`.
+    pub fn connect(url: &str) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url))
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../../cb/struct.WebSocketBuilder.html).
+    pub fn from_builder(builder: WebSocketBuilder) -> Result<Self, BuildError> {
+        let inner = Rc::new(RefCell::new(ClientInner {
+            ws: None,
+            state: SessionState::Pending,
+            open_waiters: Vec::new(),
+            messages: None,
+            close_callback: None,
+        }));
+        build_client(builder, inner)
+    }
+
+    /// Whether the session handshake (the `o` frame) has completed.
+    pub fn is_open(&self) -> bool {
+        self.inner.borrow().state == SessionState::Open
+    }
+
+    /// Resolves once the session handshake completes.
+    pub fn opened(&self) -> impl Future<Item = (), Error = NotOpened> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.state == SessionState::Open {
+            return future::Either::A(future::ok(()));
+        }
+        let (sender, receiver) = oneshot::channel();
+        inner.open_waiters.push(sender);
+        future::Either::B(receiver.then(|result| match result {
+            Ok(result) => result,
+            Err(_canceled) => Err(NotOpened),
+        }))
+    }
+
+    /// Registers a callback for the server's SockJS close frame, e.g. session
+    /// expiration or an application-level rejection.
+    pub fn on_close<F>(&self, callback: F)
+    where
+        F: FnMut(CloseFrame) + 'static,
+    {
+        self.inner.borrow_mut().close_callback = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Sends `message` to the server.
+    pub fn send(&self, message: &str) {
+        let inner = self.inner.borrow();
+        inner.ws().send_text(&encode_messages(&[message]));
+    }
+
+    /// Returns the stream of messages the server sends. Calling this again replaces
+    /// whichever [`Messages`](struct.Messages.html) was returned before -- a SockJS
+    /// session has exactly one message stream, not one per caller.
+    pub fn messages(&self) -> Messages {
+        let (sender, receiver) = mpsc::unbounded();
+        self.inner.borrow_mut().messages = Some(sender);
+        Messages { receiver }
+    }
+}
+
+/// The stream of messages a SockJS session delivers, from [`SockJsClient::messages`](struct.SockJsClient.html#method.messages).
+pub struct Messages {
+    receiver: mpsc::UnboundedReceiver<String>,
+}
+
+impl fmt::Debug for Messages {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Messages").finish()
+    }
+}
+
+impl Stream for Messages {
+    type Item = String;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<String>, ()> {
+        self.receiver.poll()
+    }
+}
+
+fn build_client(builder: WebSocketBuilder, placeholder: Rc<RefCell<ClientInner>>) -> Result<SockJsClient, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(SockJsClient { inner: placeholder })
+}
+
+fn handle_event(client: &Rc<RefCell<ClientInner>>, event: WsEvent) {
+    match event {
+        WsEvent::Message(WsMessage::Text(text)) => on_frame(client, &text),
+        WsEvent::Close { .. } | WsEvent::Error => on_disconnect(client),
+        _ => {}
+    }
+}
+
+// Runs on every close of the underlying socket, reconnects included (see the module
+// doc for why this protocol doesn't need `on_reopen`). Drop whatever was waiting on the handshake, which completes
+// `opened()` rather than leaving it hanging until a reconnect's fresh `o` frame arrives.
+fn on_disconnect(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.state = SessionState::Pending;
+    let waiters = std::mem::take(&mut inner.open_waiters);
+    for waiter in waiters {
+        let _ = waiter.send(Err(NotOpened));
+    }
+}
+
+fn on_frame(client: &Rc<RefCell<ClientInner>>, text: &str) {
+    let mut chars = text.chars();
+    let frame_type = match chars.next() {
+        Some(c) => c,
+        None => return,
+    };
+    let rest = chars.as_str();
+    match frame_type {
+        'o' => on_open(client),
+        'h' => {}
+        'a' => on_array(client, rest),
+        'c' => on_close(client, rest),
+        _ => {}
+    }
+}
+
+fn on_open(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.state = SessionState::Open;
+    let waiters = std::mem::take(&mut inner.open_waiters);
+    for waiter in waiters {
+        let _ = waiter.send(Ok(()));
+    }
+}
+
+fn on_array(client: &Rc<RefCell<ClientInner>>, json: &str) {
+    let messages: Vec<String> = match serde_json::from_str(json) {
+        Ok(messages) => messages,
+        Err(_) => return,
+    };
+    let inner = client.borrow();
+    if let Some(sender) = &inner.messages {
+        for message in messages {
+            let _ = sender.unbounded_send(message);
+        }
+    }
+}
+
+fn on_close(client: &Rc<RefCell<ClientInner>>, json: &str) {
+    let value: Value = match serde_json::from_str(json) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let array = match value.as_array() {
+        Some(array) if array.len() >= 2 => array,
+        _ => return,
+    };
+    let close_frame = CloseFrame {
+        code: array[0].as_u64().unwrap_or(0) as u32,
+        reason: array[1].as_str().unwrap_or_default().to_string(),
+    };
+    let callback = client.borrow().close_callback.clone();
+    if let Some(callback) = callback {
+        (callback.borrow_mut())(close_frame);
+    }
+}
+
+fn encode_messages(messages: &[&str]) -> String {
+    let array: Vec<Value> = messages.iter().map(|message| Value::String(message.to_string())).collect();
+    // `Value` serialization never fails.
+    serde_json::to_string(&Value::Array(array)).expect("serializing a serde_json::Value cannot fail")
+}
@@ -0,0 +1,506 @@
+//! A [STOMP 1.2](https://stomp.github.io/stomp-specification-1.2.html) client, for
+//! talking to brokers (RabbitMQ, ActiveMQ, Spring's `@MessageMapping` endpoints, ...)
+//! that expose STOMP over a WebSocket rather than raw AMQP or a broker-specific
+//! protocol.
+//!
+//! Covers the operations most STOMP consumers need: [`StompClient::connect`](struct.StompClient.html#method.connect),
+//! [`subscribe`](struct.StompClient.html#method.subscribe),
+//! [`send`](struct.StompClient.html#method.send), and
+//! [`ack`](struct.StompClient.html#method.ack)/[`nack`](struct.StompClient.html#method.nack),
+//! plus heart-beat header negotiation on CONNECT. Connection lifecycle, reconnects,
+//! and backoff are all inherited from [`cb::WebSocket`](../../cb/struct.WebSocket.html);
+//! every time the underlying socket (re)connects, CONNECT is resent and subscriptions
+//! still active are resent after the broker's CONNECTED frame, so a reconnect resumes
+//! them rather than leaving them hanging.
+//!
+//! This module does not watch for missed incoming heart-beats -- only the outgoing
+//! side of the negotiation (what this client sends the broker) is implemented.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::sync::mpsc;
+use futures::{Async, Poll, Stream};
+use gloo_timers::callback::Interval;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+/// A single STOMP frame, either sent or received.
+///
+/// Headers preserve the order and repetition of the wire frame (STOMP allows a header
+/// to appear more than once; the first occurrence wins), rather than collapsing them
+/// into a map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// The frame's command, e.g. `"CONNECTED"`, `"MESSAGE"`, `"ERROR"`.
+    pub command: String,
+    /// Header lines, in wire order.
+    pub headers: Vec<(String, String)>,
+    /// The frame body. Empty for frames that don't carry one.
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    /// Returns the value of the first header named `name`, if present.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// The body decoded as UTF-8, if it is valid UTF-8.
+    pub fn body_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.body).ok()
+    }
+}
+
+/// How a [`Subscription`](struct.Subscription.html)'s messages are acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckMode {
+    /// The broker considers every delivered message acknowledged; calling
+    /// [`StompClient::ack`](struct.StompClient.html#method.ack) is unnecessary (and
+    /// has no effect, since the broker didn't send an `ack` header to echo back).
+    Auto,
+    /// Acknowledging a message acknowledges every message the broker sent on this
+    /// subscription before it too.
+    Client,
+    /// Each message is acknowledged independently of the others on this subscription.
+    ClientIndividual,
+}
+
+impl AckMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            AckMode::Auto => "auto",
+            AckMode::Client => "client",
+            AckMode::ClientIndividual => "client-individual",
+        }
+    }
+}
+
+/// An error surfaced by a [`Subscription`](struct.Subscription.html).
+#[derive(Debug, Clone)]
+pub enum StompError {
+    /// The broker sent an `ERROR` frame closing this subscription's stream.
+    Server(Frame),
+}
+
+impl fmt::Display for StompError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StompError::Server(frame) => match frame.header("message") {
+                Some(message) => write!(f, "the broker reported an error: {}", message),
+                None => write!(f, "the broker sent an ERROR frame"),
+            },
+        }
+    }
+}
+
+impl std::error::Error for StompError {}
+
+struct Active {
+    destination: String,
+    ack_mode: AckMode,
+    sender: mpsc::UnboundedSender<Result<Frame, StompError>>,
+}
+
+struct ClientInner {
+    // `None` only in the brief window in `build_client` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build` returns,
+    // so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    host: String,
+    login: Option<(String, String)>,
+    outgoing_heartbeat: Duration,
+    requested_incoming_heartbeat: Duration,
+    connected: bool,
+    heartbeat_timer: Option<Interval>,
+    next_id: u64,
+    subscriptions: HashMap<String, Active>,
+}
+
+impl ClientInner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("ClientInner.ws is set before any event can fire")
+    }
+}
+
+/// A STOMP client, built on a [`cb::WebSocket`](../../cb/struct.WebSocket.html).
+#[derive(Clone)]
+pub struct StompClient {
+    inner: Rc<RefCell<ClientInner>>,
+}
+
+impl fmt::Debug for StompClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("StompClient")
+            .field("ws", &inner.ws)
+            .field("host", &inner.host)
+            .field("connected", &inner.connected)
+            .field("subscriptions", &inner.subscriptions.len())
+            .finish()
+    }
+}
+
+impl StompClient {
+    /// Connects to `url` and begins the STOMP handshake against virtual host `host`.
+    ///
+    /// `host` is the STOMP `host` header, not necessarily the same as `url`'s
+    /// hostname -- brokers like RabbitMQ route it to a virtual host independent of
+    /// where the socket itself connects.
+    pub fn connect(url: &str, host: &str) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url), host)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../../cb/struct.WebSocketBuilder.html).
+    pub fn from_builder(builder: WebSocketBuilder, host: &str) -> Result<Self, BuildError> {
+        let inner = Rc::new(RefCell::new(ClientInner {
+            ws: None,
+            host: host.to_string(),
+            login: None,
+            outgoing_heartbeat: Duration::from_millis(0),
+            requested_incoming_heartbeat: Duration::from_millis(0),
+            connected: false,
+            heartbeat_timer: None,
+            next_id: 0,
+            subscriptions: HashMap::new(),
+        }));
+        build_client(builder, inner)
+    }
+
+    /// Sets the `login`/`passcode` credentials sent with CONNECT. Applies to the next
+    /// (re)connection; does not itself trigger one.
+    pub fn set_login(&self, login: impl Into<String>, passcode: impl Into<String>) {
+        self.inner.borrow_mut().login = Some((login.into(), passcode.into()));
+    }
+
+    /// Proposes heart-beating on the next (re)connection: `outgoing` is how often this
+    /// client promises to send a heartbeat if otherwise idle, `incoming` is how often
+    /// it would like the broker to send one. A zero duration means "won't send" /
+    /// "doesn't need one", per the STOMP heart-beat header.
+    ///
+    /// The broker's CONNECTED frame has the final say per the negotiation rules in the
+    /// spec; this client honors the negotiated outgoing interval, but (as the module
+    /// docs say) the negotiated incoming interval isn't enforced.
+    pub fn set_heartbeat(&self, outgoing: Duration, incoming: Duration) {
+        let mut inner = self.inner.borrow_mut();
+        inner.outgoing_heartbeat = outgoing;
+        inner.requested_incoming_heartbeat = incoming;
+    }
+
+    /// Whether the CONNECT/CONNECTED handshake has completed on the current
+    /// connection.
+    pub fn is_connected(&self) -> bool {
+        self.inner.borrow().connected
+    }
+
+    /// Subscribes to `destination`, acknowledging delivered messages according to
+    /// `ack_mode`.
+    ///
+    /// Dropping the returned [`Subscription`](struct.Subscription.html) sends
+    /// UNSUBSCRIBE and stops forwarding further messages.
+    pub fn subscribe(&self, destination: impl Into<String>, ack_mode: AckMode) -> Subscription {
+        let (sender, receiver) = mpsc::unbounded();
+        let destination = destination.into();
+        let id = {
+            let mut inner = self.inner.borrow_mut();
+            inner.next_id += 1;
+            let id = inner.next_id.to_string();
+            if inner.connected {
+                send_subscribe(&inner, &id, &destination, ack_mode);
+            }
+            inner.subscriptions.insert(id.clone(), Active { destination, ack_mode, sender });
+            id
+        };
+        Subscription { id, client: self.inner.clone(), receiver }
+    }
+
+    /// Sends `body` to `destination` with no headers beyond `content-length` and
+    /// `destination` itself.
+    pub fn send(&self, destination: &str, body: impl AsRef<[u8]>) {
+        self.send_with_headers(destination, &[], body);
+    }
+
+    /// Sends `body` to `destination`, with additional headers beyond `content-length`
+    /// and `destination`.
+    pub fn send_with_headers(&self, destination: &str, headers: &[(String, String)], body: impl AsRef<[u8]>) {
+        let inner = self.inner.borrow();
+        let mut all_headers: Vec<(String, String)> = vec![("destination".to_string(), destination.to_string())];
+        all_headers.extend_from_slice(headers);
+        inner.ws().send_bytes(&encode_frame("SEND", &all_headers, body.as_ref()));
+    }
+
+    /// Acknowledges a message delivered on a subscription using
+    /// [`AckMode::Client`](enum.AckMode.html#variant.Client) or
+    /// [`ClientIndividual`](enum.AckMode.html#variant.ClientIndividual).
+    ///
+    /// Does nothing if `message` has no `ack` header, which is the case for messages
+    /// delivered under [`AckMode::Auto`](enum.AckMode.html#variant.Auto).
+    pub fn ack(&self, message: &Frame) {
+        self.ack_or_nack("ACK", message);
+    }
+
+    /// Signals that `message` was not processed successfully, per the rules of
+    /// whatever [`AckMode`](enum.AckMode.html) the owning subscription was opened with.
+    pub fn nack(&self, message: &Frame) {
+        self.ack_or_nack("NACK", message);
+    }
+
+    fn ack_or_nack(&self, command: &str, message: &Frame) {
+        let ack_id = match message.header("ack") {
+            Some(ack_id) => ack_id.to_string(),
+            None => return,
+        };
+        let inner = self.inner.borrow();
+        inner.ws().send_bytes(&encode_frame(command, &[("id".to_string(), ack_id)], &[]));
+    }
+}
+
+/// A subscription's stream of delivered `MESSAGE` frames.
+///
+/// A [`StompError`](enum.StompError.html) ends the stream if the broker sends an
+/// `ERROR` frame instead.
+pub struct Subscription {
+    id: String,
+    client: Rc<RefCell<ClientInner>>,
+    receiver: mpsc::UnboundedReceiver<Result<Frame, StompError>>,
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Subscription").field("id", &self.id).finish()
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Frame;
+    type Error = StompError;
+
+    fn poll(&mut self) -> Poll<Option<Frame>, StompError> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(Some(Ok(frame)))) => Ok(Async::Ready(Some(frame))),
+            Ok(Async::Ready(Some(Err(err)))) => Err(err),
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut inner = self.client.borrow_mut();
+        if inner.subscriptions.remove(&self.id).is_some() && inner.connected {
+            inner.ws().send_bytes(&encode_frame("UNSUBSCRIBE", &[("id".to_string(), self.id.clone())], &[]));
+        }
+    }
+}
+
+fn build_client(builder: WebSocketBuilder, placeholder: Rc<RefCell<ClientInner>>) -> Result<StompClient, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(StompClient { inner: placeholder })
+}
+
+fn handle_event(client: &Rc<RefCell<ClientInner>>, event: WsEvent) {
+    match event {
+        WsEvent::Open => on_open(client),
+        WsEvent::Message(WsMessage::Text(text)) => on_data(client, text.into_bytes()),
+        WsEvent::Message(WsMessage::Bytes(bytes)) => on_data(client, bytes.to_vec()),
+        WsEvent::Close { .. } | WsEvent::Error => on_disconnect(client),
+        _ => {}
+    }
+}
+
+fn on_open(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.connected = false;
+    let mut headers = vec![
+        ("accept-version".to_string(), "1.2".to_string()),
+        ("host".to_string(), inner.host.clone()),
+        (
+            "heart-beat".to_string(),
+            format!("{},{}", inner.outgoing_heartbeat.as_millis(), inner.requested_incoming_heartbeat.as_millis()),
+        ),
+    ];
+    if let Some((login, passcode)) = &inner.login {
+        headers.push(("login".to_string(), login.clone()));
+        headers.push(("passcode".to_string(), passcode.clone()));
+    }
+    inner.ws().send_bytes(&encode_frame("CONNECT", &headers, &[]));
+}
+
+fn on_disconnect(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.connected = false;
+    inner.heartbeat_timer = None;
+}
+
+fn on_data(client: &Rc<RefCell<ClientInner>>, bytes: Vec<u8>) {
+    if bytes.iter().all(|byte| *byte == b'\n' || *byte == b'\r') {
+        // A lone newline is the broker's own heart-beat; there's nothing to act on.
+        return;
+    }
+    let frame = match decode_frame(&bytes) {
+        Some(frame) => frame,
+        None => return,
+    };
+    match frame.command.as_str() {
+        "CONNECTED" => on_connected(client, &frame),
+        "MESSAGE" => on_message(client, frame),
+        "ERROR" => on_error(client, frame),
+        _ => {}
+    }
+}
+
+fn on_connected(client: &Rc<RefCell<ClientInner>>, frame: &Frame) {
+    let pending: Vec<(String, String, AckMode)>;
+    {
+        let mut inner = client.borrow_mut();
+        inner.connected = true;
+        inner.heartbeat_timer = negotiate_heartbeat(client, &inner, frame);
+        pending = inner
+            .subscriptions
+            .iter()
+            .map(|(id, active)| (id.clone(), active.destination.clone(), active.ack_mode))
+            .collect();
+    }
+    let inner = client.borrow();
+    for (id, destination, ack_mode) in &pending {
+        send_subscribe(&inner, id, destination, *ack_mode);
+    }
+}
+
+fn negotiate_heartbeat(client: &Rc<RefCell<ClientInner>>, inner: &ClientInner, frame: &Frame) -> Option<Interval> {
+    let (_broker_outgoing, broker_incoming) = parse_heartbeat_header(frame.header("heart-beat"))?;
+    let my_outgoing = millis_saturating(inner.outgoing_heartbeat);
+    if my_outgoing == 0 || broker_incoming == 0 {
+        return None;
+    }
+    let period = my_outgoing.max(broker_incoming);
+    let weak_client = Rc::downgrade(client);
+    Some(Interval::new(period, move || {
+        if let Some(client) = weak_client.upgrade() {
+            client.borrow().ws().send_bytes(b"\n");
+        }
+    }))
+}
+
+fn parse_heartbeat_header(header: Option<&str>) -> Option<(u32, u32)> {
+    let header = header?;
+    let mut parts = header.split(',');
+    let outgoing = parts.next()?.trim().parse().ok()?;
+    let incoming = parts.next()?.trim().parse().ok()?;
+    Some((outgoing, incoming))
+}
+
+fn on_message(client: &Rc<RefCell<ClientInner>>, frame: Frame) {
+    let subscription_id = match frame.header("subscription") {
+        Some(id) => id.to_string(),
+        None => return,
+    };
+    let inner = client.borrow();
+    if let Some(active) = inner.subscriptions.get(&subscription_id) {
+        let _ = active.sender.unbounded_send(Ok(frame));
+    }
+}
+
+fn on_error(client: &Rc<RefCell<ClientInner>>, frame: Frame) {
+    let subscription_id = frame.header("subscription").map(str::to_string);
+    let mut inner = client.borrow_mut();
+    match subscription_id.and_then(|id| inner.subscriptions.remove(&id)) {
+        Some(active) => {
+            let _ = active.sender.unbounded_send(Err(StompError::Server(frame)));
+        }
+        // The broker can send an ERROR frame unrelated to any one subscription (e.g.
+        // a malformed CONNECT); there's nowhere to deliver it but the connection is
+        // about to close anyway, so it's dropped.
+        None => {}
+    }
+}
+
+fn send_subscribe(inner: &ClientInner, id: &str, destination: &str, ack_mode: AckMode) {
+    let headers = vec![
+        ("id".to_string(), id.to_string()),
+        ("destination".to_string(), destination.to_string()),
+        ("ack".to_string(), ack_mode.as_str().to_string()),
+    ];
+    inner.ws().send_bytes(&encode_frame("SUBSCRIBE", &headers, &[]));
+}
+
+fn encode_frame(command: &str, headers: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    let mut out = String::new();
+    out.push_str(command);
+    out.push('\n');
+    for (key, value) in headers {
+        out.push_str(&escape(key));
+        out.push(':');
+        out.push_str(&escape(value));
+        out.push('\n');
+    }
+    if !body.is_empty() {
+        out.push_str(&format!("content-length:{}\n", body.len()));
+    }
+    out.push('\n');
+    let mut bytes = out.into_bytes();
+    bytes.extend_from_slice(body);
+    bytes.push(0);
+    bytes
+}
+
+fn decode_frame(bytes: &[u8]) -> Option<Frame> {
+    let header_end = find_subslice(bytes, b"\n\n")?;
+    let header_text = std::str::from_utf8(&bytes[..header_end]).ok()?;
+    let mut lines = header_text.split('\n');
+    let command = lines.next()?.to_string();
+    let mut headers = Vec::new();
+    for line in lines {
+        let colon = line.find(':')?;
+        headers.push((unescape(&line[..colon]), unescape(&line[colon + 1..])));
+    }
+    let mut body = &bytes[header_end + 2..];
+    if body.last() == Some(&0) {
+        body = &body[..body.len() - 1];
+    }
+    let body = match headers.iter().find(|(key, _)| key == "content-length").and_then(|(_, v)| v.parse::<usize>().ok()) {
+        Some(len) if len <= body.len() => &body[..len],
+        _ => body,
+    };
+    Some(Frame { command, headers, body: body.to_vec() })
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// Mirrors `cb::millis_saturating`: `Interval`/`Timeout` take a `u32` millisecond count,
+// but `Duration` doesn't fit in one, so this saturates instead of panicking on overflow.
+fn millis_saturating(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\r', "\\r").replace('\n', "\\n").replace(':', "\\c")
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            match chars.next() {
+                Some('r') => out.push('\r'),
+                Some('n') => out.push('\n'),
+                Some('c') => out.push(':'),
+                Some('\\') => out.push('\\'),
+                Some(other) => out.push(other),
+                None => {}
+            }
+        } else {
+            out.push(ch);
+        }
+    }
+    out
+}
@@ -0,0 +1,461 @@
+//! A client for the [WAMP v2](https://wamp-proto.org/) basic profile, so a
+//! Crossbar/Autobahn (or any other WAMP) router can be talked to without a JS shim.
+//!
+//! Supports the basic profile's publisher, subscriber, and caller roles over JSON
+//! serialization: [`WampClient::publish`](struct.WampClient.html#method.publish) and
+//! [`subscribe`](struct.WampClient.html#method.subscribe) for pub/sub, and
+//! [`call`](struct.WampClient.html#method.call) for RPC. The callee and dealer-side
+//! registration roles aren't implemented, since this crate is for consuming a backend,
+//! not acting as one. A WAMP session doesn't survive the underlying connection
+//! dropping, so every still-held [`Subscription`](struct.Subscription.html) is
+//! resubscribed under the new session once `WELCOME` arrives after a reconnect.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Poll, Stream};
+use serde_json::Value;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+const HELLO: u64 = 1;
+const WELCOME: u64 = 2;
+const ABORT: u64 = 3;
+const ERROR: u64 = 8;
+const PUBLISH: u64 = 16;
+const SUBSCRIBE: u64 = 32;
+const SUBSCRIBED: u64 = 33;
+const UNSUBSCRIBE: u64 = 34;
+const EVENT: u64 = 36;
+const CALL: u64 = 48;
+const RESULT: u64 = 50;
+
+/// The positional and keyword arguments carried by an `EVENT` or a `call`'s `RESULT`.
+#[derive(Debug, Clone, Default)]
+pub struct Arguments {
+    /// Positional arguments.
+    pub args: Vec<Value>,
+    /// Keyword arguments.
+    pub kwargs: serde_json::Map<String, Value>,
+}
+
+/// An error surfaced by [`call`](struct.WampClient.html#method.call).
+#[derive(Debug, Clone)]
+pub enum CallError {
+    /// The dealer or callee sent an `ERROR` instead of a `RESULT`.
+    Error {
+        /// The error's URI, e.g. `"wamp.error.no_such_procedure"`.
+        uri: String,
+        /// The error's arguments.
+        arguments: Arguments,
+    },
+    /// The connection dropped before a reply arrived.
+    Disconnected,
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallError::Error { uri, .. } => write!(f, "call error: {}", uri),
+            CallError::Disconnected => write!(f, "connection closed before a reply arrived"),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
+struct SubscriptionEntry {
+    id: u64,
+    topic: String,
+    request_id: u64,
+    subscription_id: Option<u64>,
+    canceled: bool,
+    sender: mpsc::UnboundedSender<Arguments>,
+}
+
+struct ClientInner {
+    // `None` only in the brief window in `build_client` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build` returns,
+    // so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    realm: String,
+    session_id: Option<u64>,
+    next_request_id: u64,
+    next_subscription_handle_id: u64,
+    subscriptions: Vec<SubscriptionEntry>,
+    pending_calls: HashMap<u64, oneshot::Sender<Result<Arguments, CallError>>>,
+    abort_callback: Option<Rc<RefCell<dyn FnMut(String)>>>,
+}
+
+impl ClientInner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("ClientInner.ws is set before any event can fire")
+    }
+
+    fn allocate_request_id(&mut self) -> u64 {
+        self.next_request_id += 1;
+        self.next_request_id
+    }
+}
+
+/// A connection to a WAMP router, built on a [`cb::WebSocket`](../../cb/struct.WebSocket.html).
+#[derive(Clone)]
+pub struct WampClient {
+    inner: Rc<RefCell<ClientInner>>,
+}
+
+impl fmt::Debug for WampClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("WampClient")
+            .field("ws", &inner.ws)
+            .field("realm", &inner.realm)
+            .field("session_id", &inner.session_id)
+            .finish()
+    }
+}
+
+impl WampClient {
+    /// Connects to `url` and joins `realm`.
+    pub fn connect(url: &str, realm: impl Into<String>) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url), realm)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../../cb/struct.WebSocketBuilder.html).
+    pub fn from_builder(builder: WebSocketBuilder, realm: impl Into<String>) -> Result<Self, BuildError> {
+        let inner = Rc::new(RefCell::new(ClientInner {
+            ws: None,
+            realm: realm.into(),
+            session_id: None,
+            next_request_id: 0,
+            next_subscription_handle_id: 0,
+            subscriptions: Vec::new(),
+            pending_calls: HashMap::new(),
+            abort_callback: None,
+        }));
+        build_client(builder, inner)
+    }
+
+    /// Whether `HELLO`/`WELCOME` has completed on the current connection.
+    pub fn is_connected(&self) -> bool {
+        self.inner.borrow().session_id.is_some()
+    }
+
+    /// The session ID the router assigned in `WELCOME`, if connected.
+    pub fn session_id(&self) -> Option<u64> {
+        self.inner.borrow().session_id
+    }
+
+    /// Registers a callback for when the router sends `ABORT` instead of `WELCOME`,
+    /// e.g. because the realm doesn't exist. The reason URI is passed through verbatim.
+    pub fn on_abort<F>(&self, callback: F)
+    where
+        F: FnMut(String) + 'static,
+    {
+        self.inner.borrow_mut().abort_callback = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Publishes `arguments` to `topic`. Sent without requesting acknowledgement, per
+    /// WAMP's default `PUBLISH` options, so there's no feedback if the router rejects
+    /// the publication.
+    pub fn publish(&self, topic: &str, arguments: Arguments) {
+        let mut inner = self.inner.borrow_mut();
+        let request_id = inner.allocate_request_id();
+        let message = encode_publish(request_id, topic, &arguments);
+        inner.ws().send_text(&message);
+    }
+
+    /// Calls `procedure`, resolving to the `RESULT`'s arguments.
+    pub fn call(&self, procedure: &str, arguments: Arguments) -> impl Future<Item = Arguments, Error = CallError> {
+        let mut inner = self.inner.borrow_mut();
+        let request_id = inner.allocate_request_id();
+        let (sender, receiver) = oneshot::channel();
+        inner.pending_calls.insert(request_id, sender);
+        inner.ws().send_text(&encode_call(request_id, procedure, &arguments));
+        receiver.then(|result| match result {
+            Ok(result) => result,
+            Err(_canceled) => Err(CallError::Disconnected),
+        })
+    }
+
+    /// Subscribes to `topic`.
+    ///
+    /// Dropping the returned [`Subscription`](struct.Subscription.html) sends
+    /// `UNSUBSCRIBE` and stops forwarding further events.
+    pub fn subscribe(&self, topic: impl Into<String>) -> Subscription {
+        let (sender, receiver) = mpsc::unbounded();
+        let topic = topic.into();
+        let id;
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.next_subscription_handle_id += 1;
+            id = inner.next_subscription_handle_id;
+            let request_id = inner.allocate_request_id();
+            let message = encode_subscribe(request_id, &topic);
+            inner.subscriptions.push(SubscriptionEntry {
+                id,
+                topic: topic.clone(),
+                request_id,
+                subscription_id: None,
+                canceled: false,
+                sender,
+            });
+            inner.ws().send_text(&message);
+        }
+        Subscription { id, client: self.inner.clone(), receiver }
+    }
+}
+
+/// A subscription's stream of `EVENT` arguments.
+pub struct Subscription {
+    id: u64,
+    client: Rc<RefCell<ClientInner>>,
+    receiver: mpsc::UnboundedReceiver<Arguments>,
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Subscription").finish()
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Arguments;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Arguments>, ()> {
+        self.receiver.poll()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut inner = self.client.borrow_mut();
+        let entry = match inner.subscriptions.iter_mut().find(|entry| entry.id == self.id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        match entry.subscription_id {
+            // Already confirmed: unsubscribe now and forget it.
+            Some(subscription_id) => {
+                inner.subscriptions.retain(|entry| entry.id != self.id);
+                let request_id = inner.allocate_request_id();
+                inner.ws().send_text(&encode_unsubscribe(request_id, subscription_id));
+            }
+            // Still waiting on `SUBSCRIBED`: there's no subscription ID to unsubscribe
+            // with yet, so just mark it for cleanup once the confirmation arrives.
+            None => entry.canceled = true,
+        }
+    }
+}
+
+fn build_client(builder: WebSocketBuilder, placeholder: Rc<RefCell<ClientInner>>) -> Result<WampClient, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(WampClient { inner: placeholder })
+}
+
+fn handle_event(client: &Rc<RefCell<ClientInner>>, event: WsEvent) {
+    match event {
+        WsEvent::Open => on_open(client),
+        WsEvent::Message(WsMessage::Text(text)) => on_data(client, &text),
+        WsEvent::Close { .. } | WsEvent::Error => on_disconnect(client),
+        _ => {}
+    }
+}
+
+fn on_open(client: &Rc<RefCell<ClientInner>>) {
+    let inner = client.borrow();
+    inner.ws().send_text(&encode_hello(&inner.realm));
+}
+
+fn on_disconnect(client: &Rc<RefCell<ClientInner>>) {
+    let mut inner = client.borrow_mut();
+    inner.session_id = None;
+    // Dropping every pending sender completes its receiver with `Canceled`, which
+    // `call` maps to `CallError::Disconnected`.
+    inner.pending_calls.clear();
+    for entry in &mut inner.subscriptions {
+        entry.subscription_id = None;
+    }
+}
+
+fn on_data(client: &Rc<RefCell<ClientInner>>, text: &str) {
+    let value: Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let elements = match value.as_array() {
+        Some(elements) => elements,
+        None => return,
+    };
+    let message_type = match elements.first().and_then(Value::as_u64) {
+        Some(message_type) => message_type,
+        None => return,
+    };
+    match message_type {
+        WELCOME => on_welcome(client, elements),
+        ABORT => on_abort(client, elements),
+        SUBSCRIBED => on_subscribed(client, elements),
+        EVENT => on_event(client, elements),
+        RESULT => on_result(client, elements),
+        ERROR => on_error(client, elements),
+        _ => {}
+    }
+}
+
+fn on_welcome(client: &Rc<RefCell<ClientInner>>, elements: &[Value]) {
+    let session_id = match elements.get(1).and_then(Value::as_u64) {
+        Some(session_id) => session_id,
+        None => return,
+    };
+    let resubscribe: Vec<(u64, String)>;
+    {
+        let mut inner = client.borrow_mut();
+        inner.session_id = Some(session_id);
+        resubscribe = inner
+            .subscriptions
+            .iter()
+            .filter(|entry| !entry.canceled)
+            .map(|entry| (entry.id, entry.topic.clone()))
+            .collect();
+    }
+    let mut inner = client.borrow_mut();
+    for (id, topic) in resubscribe {
+        let request_id = inner.allocate_request_id();
+        if let Some(entry) = inner.subscriptions.iter_mut().find(|entry| entry.id == id) {
+            entry.request_id = request_id;
+            entry.subscription_id = None;
+        }
+        inner.ws().send_text(&encode_subscribe(request_id, &topic));
+    }
+}
+
+fn on_abort(client: &Rc<RefCell<ClientInner>>, elements: &[Value]) {
+    let reason = elements.get(2).and_then(Value::as_str).unwrap_or_default().to_string();
+    let callback = client.borrow().abort_callback.clone();
+    if let Some(callback) = callback {
+        (callback.borrow_mut())(reason);
+    }
+}
+
+fn on_subscribed(client: &Rc<RefCell<ClientInner>>, elements: &[Value]) {
+    let request_id = match elements.get(1).and_then(Value::as_u64) {
+        Some(request_id) => request_id,
+        None => return,
+    };
+    let subscription_id = match elements.get(2).and_then(Value::as_u64) {
+        Some(subscription_id) => subscription_id,
+        None => return,
+    };
+    let mut inner = client.borrow_mut();
+    let entry = match inner.subscriptions.iter_mut().find(|entry| entry.request_id == request_id) {
+        Some(entry) => entry,
+        None => return,
+    };
+    if entry.canceled {
+        let id = entry.id;
+        inner.subscriptions.retain(|entry| entry.id != id);
+        let unsubscribe_request_id = inner.allocate_request_id();
+        inner.ws().send_text(&encode_unsubscribe(unsubscribe_request_id, subscription_id));
+    } else {
+        entry.subscription_id = Some(subscription_id);
+    }
+}
+
+fn on_event(client: &Rc<RefCell<ClientInner>>, elements: &[Value]) {
+    let subscription_id = match elements.get(1).and_then(Value::as_u64) {
+        Some(subscription_id) => subscription_id,
+        None => return,
+    };
+    let arguments = decode_arguments(elements, 3);
+    let inner = client.borrow();
+    if let Some(entry) = inner.subscriptions.iter().find(|entry| entry.subscription_id == Some(subscription_id)) {
+        let _ = entry.sender.unbounded_send(arguments);
+    }
+}
+
+fn on_result(client: &Rc<RefCell<ClientInner>>, elements: &[Value]) {
+    let request_id = match elements.get(1).and_then(Value::as_u64) {
+        Some(request_id) => request_id,
+        None => return,
+    };
+    let mut inner = client.borrow_mut();
+    if let Some(sender) = inner.pending_calls.remove(&request_id) {
+        let arguments = decode_arguments(elements, 3);
+        let _ = sender.send(Ok(arguments));
+    }
+}
+
+fn on_error(client: &Rc<RefCell<ClientInner>>, elements: &[Value]) {
+    let request_type = elements.get(1).and_then(Value::as_u64);
+    let request_id = match elements.get(2).and_then(Value::as_u64) {
+        Some(request_id) => request_id,
+        None => return,
+    };
+    if request_type != Some(CALL) {
+        return;
+    }
+    let mut inner = client.borrow_mut();
+    if let Some(sender) = inner.pending_calls.remove(&request_id) {
+        let uri = elements.get(4).and_then(Value::as_str).unwrap_or_default().to_string();
+        let arguments = decode_arguments(elements, 5);
+        let _ = sender.send(Err(CallError::Error { uri, arguments }));
+    }
+}
+
+fn decode_arguments(elements: &[Value], args_index: usize) -> Arguments {
+    let args = elements.get(args_index).and_then(Value::as_array).cloned().unwrap_or_default();
+    let kwargs = elements.get(args_index + 1).and_then(Value::as_object).cloned().unwrap_or_default();
+    Arguments { args, kwargs }
+}
+
+fn encode_hello(realm: &str) -> String {
+    let details = serde_json::json!({
+        "roles": {
+            "publisher": {},
+            "subscriber": {},
+            "caller": {},
+        },
+    });
+    encode(&[Value::from(HELLO), Value::String(realm.to_string()), details])
+}
+
+fn encode_subscribe(request_id: u64, topic: &str) -> String {
+    encode(&[Value::from(SUBSCRIBE), Value::from(request_id), Value::Object(Default::default()), Value::String(topic.to_string())])
+}
+
+fn encode_unsubscribe(request_id: u64, subscription_id: u64) -> String {
+    encode(&[Value::from(UNSUBSCRIBE), Value::from(request_id), Value::from(subscription_id)])
+}
+
+fn encode_publish(request_id: u64, topic: &str, arguments: &Arguments) -> String {
+    encode(&[
+        Value::from(PUBLISH),
+        Value::from(request_id),
+        Value::Object(Default::default()),
+        Value::String(topic.to_string()),
+        Value::Array(arguments.args.clone()),
+        Value::Object(arguments.kwargs.clone()),
+    ])
+}
+
+fn encode_call(request_id: u64, procedure: &str, arguments: &Arguments) -> String {
+    encode(&[
+        Value::from(CALL),
+        Value::from(request_id),
+        Value::Object(Default::default()),
+        Value::String(procedure.to_string()),
+        Value::Array(arguments.args.clone()),
+        Value::Object(arguments.kwargs.clone()),
+    ])
+}
+
+fn encode(elements: &[Value]) -> String {
+    // `Value` serialization never fails.
+    serde_json::to_string(&Value::Array(elements.to_vec())).expect("serializing a serde_json::Value cannot fail")
+}
@@ -0,0 +1,301 @@
+//! A generic ack-and-retransmit layer on top of [`cb::WebSocket`](../cb/struct.WebSocket.html),
+//! for apps that can't afford to silently drop an update across a reconnect.
+//!
+//! [`Reliable`] tags every outgoing message with a sequence number and keeps
+//! resending it (on the `retransmit_interval` passed to
+//! [`connect`](struct.Reliable.html#method.connect)) until the peer acks it --
+//! including across a reconnect, since a retransmit is nothing more than another
+//! `send_text` and [`cb::WebSocket`](../cb/struct.WebSocket.html) is already
+//! responsible for making that work once the connection comes back. Incoming
+//! messages are acked as soon as they arrive and delivered, via [`Stream`], in
+//! sequence order -- an out-of-order arrival is buffered until the gap in front of
+//! it is filled, and a duplicate (the peer retransmitting a message this end
+//! already acked, because the ack itself got lost) is acked again and otherwise
+//! dropped.
+//!
+//! This only works against a peer that speaks the same tiny envelope, so it's meant
+//! for a custom backend willing to implement it, not a drop-in for some existing
+//! wire protocol. A data frame is encoded as `D<seq>\u{1f}<key>\u{1f}<payload>` and an
+//! ack as `A<seq>`; both sides run the same sequence numbering independently, so
+//! `Reliable` is symmetric -- either end can originate messages.
+//!
+//! Every data frame also carries an idempotency key: a caller-supplied string (see
+//! [`send_with_key`](struct.Reliable.html#method.send_with_key)), or, for the plain
+//! [`send`](struct.Reliable.html#method.send), one generated here. The sequence
+//! number already makes retransmits within one `Reliable`'s lifetime safe to apply
+//! exactly once; the key exists for the cooperative server that wants the same
+//! guarantee across restarts, when the seq counter itself means nothing anymore.
+//! [`crate::dedup::reliable_key`] pulls a frame's key back out of the raw text, for a
+//! server that wants to run deduplication against this wire format without going
+//! through the rest of `Reliable`'s ack/retransmit machinery.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::sync::mpsc;
+use futures::{Poll, Stream};
+use gloo_timers::callback::Timeout;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+const SEPARATOR: char = '\u{1f}';
+
+enum Frame {
+    Data(u64, String, String),
+    Ack(u64),
+}
+
+/// One in-order delivery from the peer: the idempotency key the sender attached (see
+/// the [module docs](index.html)) alongside the payload itself.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    /// The key the sender attached to this message.
+    pub key: String,
+    /// The message payload.
+    pub payload: String,
+}
+
+struct Inner {
+    // `None` only in the brief window in `build_reliable` between constructing this
+    // and `WebSocketBuilder::build` returning; `on_event` can't fire until `build`
+    // returns, so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    retransmit_interval: Duration,
+    next_seq: u64,
+    unacked: HashMap<u64, Timeout>,
+    next_expected: u64,
+    reorder: BTreeMap<u64, Delivery>,
+    sender: mpsc::UnboundedSender<Delivery>,
+}
+
+impl Inner {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("Inner.ws is set before any event can fire")
+    }
+}
+
+/// A [`cb::WebSocket`](../cb/struct.WebSocket.html) with application-level acks,
+/// retransmission, and in-order delivery layered on top. See the
+/// [module docs](index.html) for the wire format.
+pub struct Reliable {
+    inner: Rc<RefCell<Inner>>,
+    receiver: mpsc::UnboundedReceiver<Delivery>,
+}
+
+impl fmt::Debug for Reliable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Reliable")
+            .field("ws", &inner.ws)
+            .field("unacked", &inner.unacked.len())
+            .field("next_seq", &inner.next_seq)
+            .field("next_expected", &inner.next_expected)
+            .finish()
+    }
+}
+
+impl Reliable {
+    /// Connects to `url`. Unacked messages are retransmitted every
+    /// `retransmit_interval` until the peer acks them.
+    pub fn connect(url: &str, retransmit_interval: Duration) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url), retransmit_interval)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../cb/struct.WebSocketBuilder.html).
+    /// See [`connect`](#method.connect) for what `retransmit_interval` does.
+    pub fn from_builder(builder: WebSocketBuilder, retransmit_interval: Duration) -> Result<Self, BuildError> {
+        let (sender, receiver) = mpsc::unbounded();
+        let inner = Rc::new(RefCell::new(Inner {
+            ws: None,
+            retransmit_interval,
+            next_seq: 0,
+            unacked: HashMap::new(),
+            next_expected: 0,
+            reorder: BTreeMap::new(),
+            sender,
+        }));
+        build_reliable(builder, inner, receiver)
+    }
+
+    /// Sends `payload` under a freshly generated idempotency key, tagged with the
+    /// next sequence number, and keeps retransmitting it until the peer acks it.
+    pub fn send(&self, payload: &str) {
+        self.send_with_key(&generate_key(), payload);
+    }
+
+    /// Like [`send`](#method.send), but with an idempotency key of the caller's
+    /// choosing rather than a generated one -- useful when the operation `payload`
+    /// describes already has a natural key (an order id, a form submission id) that
+    /// a cooperative server can use to recognize a retry as the same operation even
+    /// after this `Reliable`'s own sequence numbering has been forgotten, e.g. across
+    /// a page reload.
+    pub fn send_with_key(&self, key: &str, payload: &str) {
+        let seq = {
+            let mut inner = self.inner.borrow_mut();
+            let seq = inner.next_seq;
+            inner.next_seq += 1;
+            inner.ws().send_text(&encode_data(seq, key, payload));
+            seq
+        };
+        let timer = schedule_retransmit(&self.inner, seq, key.to_string(), payload.to_string());
+        self.inner.borrow_mut().unacked.insert(seq, timer);
+    }
+}
+
+impl Stream for Reliable {
+    type Item = Delivery;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Delivery>, ()> {
+        self.receiver.poll()
+    }
+}
+
+fn build_reliable(builder: WebSocketBuilder, placeholder: Rc<RefCell<Inner>>, receiver: mpsc::UnboundedReceiver<Delivery>) -> Result<Reliable, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(Reliable { inner: placeholder, receiver })
+}
+
+fn handle_event(inner: &Rc<RefCell<Inner>>, event: WsEvent) {
+    if let WsEvent::Message(WsMessage::Text(text)) = event {
+        match decode_frame(&text) {
+            Some(Frame::Data(seq, key, payload)) => on_data(inner, seq, key, payload),
+            Some(Frame::Ack(seq)) => on_ack(inner, seq),
+            None => {}
+        }
+    }
+}
+
+fn on_data(inner: &Rc<RefCell<Inner>>, seq: u64, key: String, payload: String) {
+    let mut inner = inner.borrow_mut();
+    inner.ws().send_text(&encode_ack(seq));
+    if seq < inner.next_expected {
+        // Already delivered; the peer's ack for our earlier ack must have gone
+        // missing. It's been re-acked above, nothing else to do.
+        return;
+    }
+    if seq > inner.next_expected {
+        inner.reorder.insert(seq, Delivery { key, payload });
+        return;
+    }
+    let _ = inner.sender.unbounded_send(Delivery { key, payload });
+    inner.next_expected += 1;
+    loop {
+        let next_expected = inner.next_expected;
+        match inner.reorder.remove(&next_expected) {
+            Some(next) => {
+                let _ = inner.sender.unbounded_send(next);
+                inner.next_expected += 1;
+            }
+            None => break,
+        }
+    }
+}
+
+fn on_ack(inner: &Rc<RefCell<Inner>>, seq: u64) {
+    // Dropping the `Timeout` is what stops the retransmissions.
+    inner.borrow_mut().unacked.remove(&seq);
+}
+
+fn schedule_retransmit(inner: &Rc<RefCell<Inner>>, seq: u64, key: String, payload: String) -> Timeout {
+    let weak_inner = Rc::downgrade(inner);
+    let interval = inner.borrow().retransmit_interval;
+    Timeout::new(millis_saturating(interval), move || {
+        let inner = match weak_inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        if !inner.borrow().unacked.contains_key(&seq) {
+            return;
+        }
+        inner.borrow().ws().send_text(&encode_data(seq, &key, &payload));
+        let timer = schedule_retransmit(&inner, seq, key.clone(), payload.clone());
+        let mut state = inner.borrow_mut();
+        if let Some(entry) = state.unacked.get_mut(&seq) {
+            *entry = timer;
+        }
+    })
+}
+
+fn decode_frame(text: &str) -> Option<Frame> {
+    let mut chars = text.chars();
+    let kind = chars.next()?;
+    let rest = chars.as_str();
+    match kind {
+        'D' => {
+            let mut parts = rest.splitn(3, SEPARATOR);
+            let seq = parts.next()?.parse().ok()?;
+            let key = parts.next()?.to_string();
+            let payload = parts.next()?.to_string();
+            Some(Frame::Data(seq, key, payload))
+        }
+        'A' => Some(Frame::Ack(rest.parse().ok()?)),
+        _ => None,
+    }
+}
+
+fn encode_data(seq: u64, key: &str, payload: &str) -> String {
+    format!("D{}{}{}{}{}", seq, SEPARATOR, key, SEPARATOR, payload)
+}
+
+// A v4-ish UUID, good enough to use as a default idempotency key without pulling in
+// a dedicated `uuid` dependency for it.
+fn generate_key() -> String {
+    let hi = (js_sys::Math::random() * (u64::MAX as f64)) as u64;
+    let lo = (js_sys::Math::random() * (u64::MAX as f64)) as u64;
+    format!("{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}", hi >> 32, (hi >> 16) & 0xffff, hi & 0xfff, (lo >> 48) & 0xffff, lo & 0xffff_ffff_ffff)
+}
+
+fn encode_ack(seq: u64) -> String {
+    format!("A{}", seq)
+}
+
+// Mirrors `cb::millis_saturating`: `Timeout` takes a `u32` millisecond count, but
+// `Duration` doesn't fit in one, so this saturates instead of panicking on overflow.
+fn millis_saturating(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_frame, encode_ack, encode_data, Frame};
+
+    #[test]
+    fn data_frame_roundtrips_through_encode_and_decode() {
+        let encoded = encode_data(7, "idem-key", "hello world");
+        match decode_frame(&encoded) {
+            Some(Frame::Data(seq, key, payload)) => {
+                assert_eq!(seq, 7);
+                assert_eq!(key, "idem-key");
+                assert_eq!(payload, "hello world");
+            }
+            other => panic!("expected a data frame, got {:?}", other.is_some()),
+        }
+    }
+
+    #[test]
+    fn ack_frame_roundtrips_through_encode_and_decode() {
+        let encoded = encode_ack(42);
+        assert!(matches!(decode_frame(&encoded), Some(Frame::Ack(42))));
+    }
+
+    #[test]
+    fn decode_frame_rejects_an_unknown_kind() {
+        assert!(decode_frame("X1").is_none());
+    }
+
+    #[test]
+    fn decode_frame_rejects_a_data_frame_missing_its_payload_part() {
+        assert!(decode_frame("D7\u{1f}idem-key").is_none());
+    }
+
+    #[test]
+    fn decode_frame_rejects_an_ack_with_a_non_numeric_sequence() {
+        assert!(decode_frame("Anot-a-number").is_none());
+    }
+}
@@ -0,0 +1,241 @@
+//! A generic request/response correlation layer on top of [`cb::WebSocket`](../cb/struct.WebSocket.html),
+//! for protocols that don't warrant a dedicated module under [`protocols`](../protocols/index.html)
+//! but still follow the common request-id-out/matching-reply-in shape -- stamp an id on
+//! the way out, match it against the id on the way back in. [`Requester`] doesn't know
+//! or care what that id or the surrounding message actually look like; the caller
+//! supplies a `stamp` closure to embed an id into an outgoing message and an `extract`
+//! closure to pull a reply's id back out of an incoming one, leaving the message
+//! format itself (JSON-RPC, a bespoke text protocol, whatever) entirely up to them.
+//!
+//! [`Requester::request`](struct.Requester.html#method.request) sends a message and
+//! returns a [`Request`](struct.Request.html) that resolves to the matching reply's raw
+//! text, to [`RequestError::Timeout`](enum.RequestError.html#variant.Timeout) if the
+//! given timeout elapses first, or to
+//! [`RequestError::Disconnected`](enum.RequestError.html#variant.Disconnected) if the
+//! connection drops first. Dropping a `Request` before it resolves cancels it: the
+//! correlation id is forgotten and the timeout timer is stopped, so a late reply (or
+//! none at all) has nothing left to resolve.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+use gloo_timers::callback::Timeout;
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+
+/// An error from [`Requester::request`](struct.Requester.html#method.request).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    /// The timeout passed to [`request`](struct.Requester.html#method.request) elapsed
+    /// before a matching reply arrived.
+    Timeout,
+    /// The connection dropped before a matching reply arrived.
+    Disconnected,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "request timed out"),
+            RequestError::Disconnected => write!(f, "connection closed before a reply arrived"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+type PendingReply = Result<String, RequestError>;
+
+struct Inner<Id> {
+    // `None` only in the brief window in `build_requester` between constructing this
+    // and `WebSocketBuilder::build` returning; `on_event` can't fire until `build`
+    // returns, so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    pending: HashMap<Id, (oneshot::Sender<PendingReply>, Timeout)>,
+    on_message: Option<Rc<RefCell<dyn FnMut(String)>>>,
+}
+
+impl<Id> Inner<Id> {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("Inner.ws is set before any event can fire")
+    }
+}
+
+/// Wraps a [`cb::WebSocket`](../cb/struct.WebSocket.html) with request/response
+/// correlation, using caller-supplied closures to stamp and extract correlation ids.
+pub struct Requester<Id: Eq + Hash + Clone + 'static> {
+    inner: Rc<RefCell<Inner<Id>>>,
+    stamp: Rc<dyn Fn(&Id, String) -> String>,
+}
+
+impl<Id: Eq + Hash + Clone + 'static> fmt::Debug for Requester<Id> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Requester").field("ws", &inner.ws).field("pending", &inner.pending.len()).finish()
+    }
+}
+
+impl<Id: Eq + Hash + Clone + 'static> Requester<Id> {
+    /// Connects to `url`. `stamp` embeds a request's id into the message being sent;
+    /// `extract` pulls a reply's id back out of an incoming message, returning `None`
+    /// for anything that isn't a reply (e.g. a server-initiated push), which gets
+    /// routed to [`on_message`](#method.on_message) instead.
+    pub fn connect<Stamp, Extract>(url: &str, stamp: Stamp, extract: Extract) -> Result<Self, BuildError>
+    where
+        Stamp: Fn(&Id, String) -> String + 'static,
+        Extract: Fn(&str) -> Option<Id> + 'static,
+    {
+        Self::from_builder(WebSocketBuilder::new(url), stamp, extract)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../cb/struct.WebSocketBuilder.html).
+    /// See [`connect`](#method.connect) for what `stamp` and `extract` do.
+    pub fn from_builder<Stamp, Extract>(builder: WebSocketBuilder, stamp: Stamp, extract: Extract) -> Result<Self, BuildError>
+    where
+        Stamp: Fn(&Id, String) -> String + 'static,
+        Extract: Fn(&str) -> Option<Id> + 'static,
+    {
+        let inner = Rc::new(RefCell::new(Inner { ws: None, pending: HashMap::new(), on_message: None }));
+        let extract: Rc<dyn Fn(&str) -> Option<Id>> = Rc::new(extract);
+        build_requester(builder, inner, extract, stamp)
+    }
+
+    /// Registers a callback for incoming messages `extract` couldn't match to a
+    /// pending [`request`](#method.request) -- e.g. server-initiated pushes, or a
+    /// reply that arrived after its `Request` was already canceled or timed out.
+    pub fn on_message<F>(&self, callback: F)
+    where
+        F: FnMut(String) + 'static,
+    {
+        self.inner.borrow_mut().on_message = Some(Rc::new(RefCell::new(callback)));
+    }
+
+    /// Sends `message` (after `stamp` embeds `id` into it) and returns a future that
+    /// resolves to the matching reply's raw text, or fails with
+    /// [`RequestError::Timeout`](enum.RequestError.html#variant.Timeout) if `timeout`
+    /// elapses first.
+    pub fn request(&self, id: Id, message: String, timeout: Duration) -> Request<Id> {
+        let stamped = (self.stamp)(&id, message);
+        let (sender, receiver) = oneshot::channel();
+        let weak_inner = Rc::downgrade(&self.inner);
+        let timeout_id = id.clone();
+        let timer = Timeout::new(millis_saturating(timeout), move || {
+            if let Some(inner) = weak_inner.upgrade() {
+                if let Some((sender, _timer)) = inner.borrow_mut().pending.remove(&timeout_id) {
+                    let _ = sender.send(Err(RequestError::Timeout));
+                }
+            }
+        });
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.pending.insert(id.clone(), (sender, timer));
+            inner.ws().send_text(&stamped);
+        }
+        Request { id, inner: self.inner.clone(), receiver }
+    }
+}
+
+/// A pending [`Requester::request`](struct.Requester.html#method.request) call.
+///
+/// Dropping this before it resolves cancels the request: the correlation id is
+/// forgotten and the timeout timer is stopped.
+pub struct Request<Id: Eq + Hash + Clone + 'static> {
+    id: Id,
+    inner: Rc<RefCell<Inner<Id>>>,
+    receiver: oneshot::Receiver<PendingReply>,
+}
+
+impl<Id: Eq + Hash + Clone + 'static> fmt::Debug for Request<Id> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Request").finish()
+    }
+}
+
+impl<Id: Eq + Hash + Clone + 'static> Future for Request<Id> {
+    type Item = String;
+    type Error = RequestError;
+
+    fn poll(&mut self) -> Poll<String, RequestError> {
+        match self.receiver.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(Ok(text))) => Ok(Async::Ready(text)),
+            Ok(Async::Ready(Err(err))) => Err(err),
+            Err(_canceled) => Err(RequestError::Disconnected),
+        }
+    }
+}
+
+impl<Id: Eq + Hash + Clone + 'static> Drop for Request<Id> {
+    fn drop(&mut self) {
+        // A no-op if the reply (or a disconnect) already removed this -- dropping the
+        // entry here is what cancels an in-flight request: it drops the `Sender` (so a
+        // late reply has nothing to deliver to) and the `Timeout` (so it never fires).
+        self.inner.borrow_mut().pending.remove(&self.id);
+    }
+}
+
+fn build_requester<Id, Stamp>(
+    builder: WebSocketBuilder,
+    placeholder: Rc<RefCell<Inner<Id>>>,
+    extract: Rc<dyn Fn(&str) -> Option<Id>>,
+    stamp: Stamp,
+) -> Result<Requester<Id>, BuildError>
+where
+    Id: Eq + Hash + Clone + 'static,
+    Stamp: Fn(&Id, String) -> String + 'static,
+{
+    let dispatch = placeholder.clone();
+    let dispatch_extract = extract.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, &dispatch_extract, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(Requester { inner: placeholder, stamp: Rc::new(stamp) })
+}
+
+fn handle_event<Id>(inner: &Rc<RefCell<Inner<Id>>>, extract: &Rc<dyn Fn(&str) -> Option<Id>>, event: WsEvent)
+where
+    Id: Eq + Hash + Clone + 'static,
+{
+    match event {
+        WsEvent::Message(WsMessage::Text(text)) => on_data(inner, extract, text),
+        WsEvent::Close { .. } | WsEvent::Error => on_disconnect(inner),
+        _ => {}
+    }
+}
+
+fn on_data<Id>(inner: &Rc<RefCell<Inner<Id>>>, extract: &Rc<dyn Fn(&str) -> Option<Id>>, text: String)
+where
+    Id: Eq + Hash + Clone + 'static,
+{
+    if let Some(id) = extract(&text) {
+        if let Some((sender, _timer)) = inner.borrow_mut().pending.remove(&id) {
+            let _ = sender.send(Ok(text));
+            return;
+        }
+    }
+    let callback = inner.borrow().on_message.clone();
+    if let Some(callback) = callback {
+        (callback.borrow_mut())(text);
+    }
+}
+
+fn on_disconnect<Id>(inner: &Rc<RefCell<Inner<Id>>>)
+where
+    Id: Eq + Hash + Clone + 'static,
+{
+    let pending = std::mem::take(&mut inner.borrow_mut().pending);
+    for (_id, (sender, _timer)) in pending {
+        let _ = sender.send(Err(RequestError::Disconnected));
+    }
+}
+
+// Mirrors `cb::millis_saturating`: `Timeout` takes a `u32` millisecond count, but
+// `Duration` doesn't fit in one, so this saturates instead of panicking on overflow.
+fn millis_saturating(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
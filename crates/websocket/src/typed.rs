@@ -0,0 +1,123 @@
+//! A typed wrapper around [`cb::WebSocket`](../cb/struct.WebSocket.html) built on a
+//! [`Codec`](../codec/trait.Codec.html), so the rest of an app can [`send`](TypedWebSocket::send)
+//! and receive its own message types instead of raw [`WsMessage`](../cb/enum.WsMessage.html)s.
+//!
+//! [`TypedWebSocket`] doesn't do anything a hand-rolled `encode`/`decode` call around
+//! [`cb::WebSocket`] couldn't -- it just centralizes that boilerplate once per `Codec`,
+//! the same way [`crate::multiplexer`] centralizes topic framing and
+//! [`crate::reliable`] centralizes ack bookkeeping.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use futures::sync::mpsc;
+use futures::{Poll, Stream};
+
+use crate::cb::{BuildError, WebSocket, WebSocketBuilder, WsEvent, WsMessage};
+use crate::codec::Codec;
+
+struct Inner<C: Codec> {
+    // `None` only in the brief window in `build_typed` between constructing this and
+    // `WebSocketBuilder::build` returning; `on_event` can't fire until `build`
+    // returns, so every other access sees `Some`.
+    ws: Option<WebSocket>,
+    codec: C,
+    sender: mpsc::UnboundedSender<C::In>,
+    on_decode_error: Option<Rc<RefCell<dyn FnMut(WsMessage, C::Error)>>>,
+}
+
+impl<C: Codec> Inner<C> {
+    fn ws(&self) -> &WebSocket {
+        self.ws.as_ref().expect("Inner.ws is set before any event can fire")
+    }
+}
+
+/// A [`cb::WebSocket`](../cb/struct.WebSocket.html) that sends and receives `C::Out`/`C::In`
+/// through a [`Codec`] instead of raw frames. See the [module docs](index.html).
+pub struct TypedWebSocket<C: Codec> {
+    inner: Rc<RefCell<Inner<C>>>,
+    receiver: mpsc::UnboundedReceiver<C::In>,
+}
+
+impl<C: Codec> fmt::Debug for TypedWebSocket<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TypedWebSocket").field("ws", &self.inner.borrow().ws).finish()
+    }
+}
+
+impl<C: Codec + 'static> TypedWebSocket<C> {
+    /// Connects to `url`, encoding and decoding every message with `codec`.
+    pub fn connect(url: &str, codec: C) -> Result<Self, BuildError> {
+        Self::from_builder(WebSocketBuilder::new(url), codec)
+    }
+
+    /// Connects using an already-configured [`WebSocketBuilder`](../cb/struct.WebSocketBuilder.html).
+    /// See [`connect`](#method.connect) for what `codec` does.
+    pub fn from_builder(builder: WebSocketBuilder, codec: C) -> Result<Self, BuildError> {
+        let (sender, receiver) = mpsc::unbounded();
+        let inner = Rc::new(RefCell::new(Inner { ws: None, codec, sender, on_decode_error: None }));
+        build_typed(builder, inner, receiver)
+    }
+
+    /// Encodes `value` with this socket's `Codec` and sends it.
+    pub fn send(&self, value: &C::Out) {
+        let inner = self.inner.borrow();
+        match inner.codec.encode(value) {
+            WsMessage::Text(text) => inner.ws().send_text(&text),
+            WsMessage::Bytes(bytes) => inner.ws().send_bytes(&bytes),
+            WsMessage::Blob(_) => unreachable!("no Codec encodes to a WsMessage::Blob"),
+        }
+    }
+
+    /// Registers a callback which fires whenever an incoming frame fails to decode,
+    /// with the raw frame and the codec's error, instead of it being silently dropped.
+    /// A frame this fires for is never delivered through [`Stream`](#impl-Stream).
+    pub fn on_decode_error<F>(&self, callback: F)
+    where
+        F: FnMut(WsMessage, C::Error) + 'static,
+    {
+        self.inner.borrow_mut().on_decode_error = Some(Rc::new(RefCell::new(callback)));
+    }
+}
+
+impl<C: Codec> Stream for TypedWebSocket<C> {
+    type Item = C::In;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, ()> {
+        self.receiver.poll()
+    }
+}
+
+fn build_typed<C: Codec + 'static>(
+    builder: WebSocketBuilder,
+    placeholder: Rc<RefCell<Inner<C>>>,
+    receiver: mpsc::UnboundedReceiver<C::In>,
+) -> Result<TypedWebSocket<C>, BuildError> {
+    let dispatch = placeholder.clone();
+    let ws = builder.build(move |event| handle_event(&dispatch, event))?;
+    placeholder.borrow_mut().ws = Some(ws);
+    Ok(TypedWebSocket { inner: placeholder, receiver })
+}
+
+fn handle_event<C: Codec>(inner: &Rc<RefCell<Inner<C>>>, event: WsEvent) {
+    if let WsEvent::Message(message) = event {
+        let raw = message.clone();
+        let (decoded, sender) = {
+            let state = inner.borrow();
+            (state.codec.decode(message), state.sender.clone())
+        };
+        match decoded {
+            Ok(value) => {
+                let _ = sender.unbounded_send(value);
+            }
+            Err(error) => {
+                let on_decode_error = inner.borrow().on_decode_error.clone();
+                if let Some(on_decode_error) = on_decode_error {
+                    (on_decode_error.borrow_mut())(raw, error);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Test suite for the Web and headless browsers.
+
+#![cfg(target_arch = "wasm32")]
+
+use gloo_websocket::cb::WsMessage;
+use gloo_websocket::codec::envelope::EnvelopeCodec;
+use gloo_websocket::codec::threshold::ThresholdCodec;
+use gloo_websocket::codec::Codec;
+use gloo_websocket::dedup::reliable_key;
+use wasm_bindgen_test::*;
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+/// A byte-oriented `Codec` that passes its payload through unchanged, standing in for
+/// a real compressed/serialized format in tests that only care about the adapter
+/// wrapped around it.
+#[derive(Debug)]
+struct IdentityCodec;
+
+impl Codec for IdentityCodec {
+    type In = Vec<u8>;
+    type Out = Vec<u8>;
+    type Error = ();
+
+    fn encode(&self, value: &Vec<u8>) -> WsMessage {
+        WsMessage::Bytes(value.clone().into())
+    }
+
+    fn decode(&self, message: WsMessage) -> Result<Vec<u8>, ()> {
+        match message {
+            WsMessage::Bytes(bytes) => Ok(bytes.to_vec()),
+            WsMessage::Text(text) => Ok(text.into_bytes()),
+            WsMessage::Blob(_) => Err(()),
+        }
+    }
+}
+
+#[wasm_bindgen_test]
+fn threshold_codec_passes_small_payloads_through_uncompressed() {
+    let codec = ThresholdCodec::new(IdentityCodec, 16);
+    let payload = vec![1, 2, 3];
+
+    let encoded = codec.encode(&payload);
+    match &encoded {
+        WsMessage::Bytes(bytes) => assert_eq!(bytes.to_vec(), vec![0, 1, 2, 3]),
+        _ => unreachable!("ThresholdCodec always encodes to bytes"),
+    }
+    let decoded = codec.decode(encoded).unwrap();
+
+    assert_eq!(decoded, payload);
+}
+
+#[wasm_bindgen_test]
+fn threshold_codec_runs_large_payloads_through_the_wrapped_codec() {
+    let codec = ThresholdCodec::new(IdentityCodec, 4);
+    let payload = vec![1, 2, 3, 4, 5, 6, 7, 8];
+
+    let encoded = codec.encode(&payload);
+    match &encoded {
+        WsMessage::Bytes(bytes) => assert_eq!(bytes.to_vec(), vec![1, 1, 2, 3, 4, 5, 6, 7, 8]),
+        _ => unreachable!("ThresholdCodec always encodes to bytes"),
+    }
+    let decoded = codec.decode(encoded).unwrap();
+
+    assert_eq!(decoded, payload);
+}
+
+#[wasm_bindgen_test]
+fn envelope_codec_attaches_and_recovers_an_id() {
+    let codec = EnvelopeCodec::new(IdentityCodec);
+    let first = codec.decode(codec.encode(&vec![9])).unwrap();
+    let second = codec.decode(codec.encode(&vec![9])).unwrap();
+
+    assert_eq!(first.id, 0);
+    assert_eq!(second.id, 1);
+    assert_eq!(first.payload, vec![9]);
+}
+
+#[wasm_bindgen_test]
+fn envelope_codec_rejects_a_frame_with_an_unsupported_version() {
+    let codec = EnvelopeCodec::new(IdentityCodec);
+    let mut framed = match codec.encode(&vec![1, 2, 3]) {
+        WsMessage::Bytes(bytes) => bytes.to_vec(),
+        _ => unreachable!("EnvelopeCodec always encodes to bytes"),
+    };
+    framed[0] = 99;
+
+    let error = codec.decode(WsMessage::Bytes(framed.into())).unwrap_err();
+
+    assert!(matches!(error, gloo_websocket::codec::envelope::EnvelopeError::UnsupportedVersion(99)));
+}
+
+#[wasm_bindgen_test]
+fn reliable_key_extracts_the_idempotency_key_from_a_data_frame() {
+    assert_eq!(reliable_key("D7\u{1f}idem-key\u{1f}payload"), Some("idem-key".to_string()));
+}
+
+#[wasm_bindgen_test]
+fn reliable_key_ignores_frames_that_are_not_data_frames() {
+    assert_eq!(reliable_key("A7"), None);
+    assert_eq!(reliable_key("not a frame"), None);
+}